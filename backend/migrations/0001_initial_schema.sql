@@ -0,0 +1,61 @@
+-- Users table for client management
+CREATE TABLE IF NOT EXISTS users (
+    id UUID PRIMARY KEY,
+    api_key TEXT UNIQUE NOT NULL,
+    quota_limit BIGINT NOT NULL,
+    quota_used BIGINT NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE DEFAULT CURRENT_TIMESTAMP
+);
+
+-- Rooms table for managing streaming rooms
+CREATE TABLE IF NOT EXISTS rooms (
+    id UUID PRIMARY KEY,
+    user_id UUID NOT NULL REFERENCES users(id),
+    room_id TEXT UNIQUE NOT NULL,
+    config JSONB,
+    created_at TIMESTAMP WITH TIME ZONE DEFAULT CURRENT_TIMESTAMP,
+    last_active TIMESTAMP WITH TIME ZONE
+);
+
+-- Recordings table for managing recorded sessions
+CREATE TABLE IF NOT EXISTS recordings (
+    id UUID PRIMARY KEY,
+    room_id UUID NOT NULL REFERENCES rooms(id),
+    start_time TIMESTAMP WITH TIME ZONE NOT NULL,
+    end_time TIMESTAMP WITH TIME ZONE,
+    storage_path TEXT NOT NULL,
+    size_bytes BIGINT DEFAULT 0,
+    frame_count BIGINT DEFAULT 0,
+    status TEXT NOT NULL
+);
+
+-- Room configurations for optimization settings
+CREATE TABLE IF NOT EXISTS room_configs (
+    room_id UUID PRIMARY KEY REFERENCES rooms(id),
+    video_codec TEXT,
+    audio_codec TEXT,
+    max_bitrate INTEGER,
+    frame_rate INTEGER,
+    resolution TEXT,
+    deduplication_enabled BOOLEAN DEFAULT true,
+    deduplication_threshold FLOAT
+);
+
+-- Stream metrics for analytics
+CREATE TABLE IF NOT EXISTS stream_metrics (
+    id UUID PRIMARY KEY,
+    room_id UUID NOT NULL REFERENCES rooms(id),
+    timestamp TIMESTAMP WITH TIME ZONE NOT NULL,
+    bytes_transferred BIGINT NOT NULL,
+    frames_processed BIGINT NOT NULL,
+    frames_deduplicated BIGINT NOT NULL,
+    current_bitrate INTEGER NOT NULL,
+    current_fps FLOAT NOT NULL,
+    peak_memory_mb INTEGER NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_stream_metrics_room_time
+ON stream_metrics (room_id, timestamp);
+
+CREATE INDEX IF NOT EXISTS idx_recordings_room_time
+ON recordings (room_id, start_time);
@@ -0,0 +1,20 @@
+-- Room display settings kept alongside the public room id
+ALTER TABLE rooms ADD COLUMN IF NOT EXISTS name TEXT NOT NULL DEFAULT '';
+ALTER TABLE rooms ADD COLUMN IF NOT EXISTS max_participants INTEGER NOT NULL DEFAULT 10;
+
+-- Remaining RoomConfig fields
+ALTER TABLE room_configs ADD COLUMN IF NOT EXISTS keyframe_interval INTEGER;
+ALTER TABLE room_configs ADD COLUMN IF NOT EXISTS adaptive_bitrate BOOLEAN NOT NULL DEFAULT false;
+ALTER TABLE room_configs ADD COLUMN IF NOT EXISTS min_bitrate INTEGER;
+ALTER TABLE room_configs ADD COLUMN IF NOT EXISTS quality_degradation_limit INTEGER;
+ALTER TABLE room_configs ADD COLUMN IF NOT EXISTS enable_frame_batching BOOLEAN NOT NULL DEFAULT false;
+ALTER TABLE room_configs ADD COLUMN IF NOT EXISTS batch_size INTEGER;
+ALTER TABLE room_configs ADD COLUMN IF NOT EXISTS batch_timeout_ms INTEGER;
+ALTER TABLE room_configs ADD COLUMN IF NOT EXISTS max_buffer_size INTEGER;
+ALTER TABLE room_configs ADD COLUMN IF NOT EXISTS buffer_duration_ms INTEGER;
+ALTER TABLE room_configs ADD COLUMN IF NOT EXISTS enable_error_resilience BOOLEAN NOT NULL DEFAULT false;
+ALTER TABLE room_configs ADD COLUMN IF NOT EXISTS error_correction_level INTEGER;
+ALTER TABLE room_configs ADD COLUMN IF NOT EXISTS retry_attempts INTEGER;
+ALTER TABLE room_configs ADD COLUMN IF NOT EXISTS enable_hardware_acceleration BOOLEAN NOT NULL DEFAULT false;
+ALTER TABLE room_configs ADD COLUMN IF NOT EXISTS preferred_hardware_vendor TEXT;
+ALTER TABLE room_configs ALTER COLUMN deduplication_threshold TYPE REAL;
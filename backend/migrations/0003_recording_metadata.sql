@@ -0,0 +1,5 @@
+-- Per-recording metadata tracked during ingest
+ALTER TABLE recordings ADD COLUMN IF NOT EXISTS duration_ms BIGINT;
+ALTER TABLE recordings ADD COLUMN IF NOT EXISTS video_frames BIGINT NOT NULL DEFAULT 0;
+ALTER TABLE recordings ADD COLUMN IF NOT EXISTS audio_frames BIGINT NOT NULL DEFAULT 0;
+ALTER TABLE recordings ADD COLUMN IF NOT EXISTS codec TEXT;
@@ -0,0 +1,4 @@
+-- Room lifecycle: active rooms accept connections, closed rooms reopen on the next
+-- connection, archived rooms keep their recordings but accept no connections
+ALTER TABLE rooms ADD COLUMN IF NOT EXISTS state TEXT NOT NULL DEFAULT 'active';
+ALTER TABLE rooms ADD COLUMN IF NOT EXISTS closed_at TIMESTAMP WITH TIME ZONE;
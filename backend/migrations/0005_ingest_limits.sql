@@ -0,0 +1,2 @@
+-- What happens when a publisher exceeds its room's configured limits
+ALTER TABLE room_configs ADD COLUMN IF NOT EXISTS limit_policy TEXT NOT NULL DEFAULT 'hint';
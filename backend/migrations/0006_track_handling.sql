@@ -0,0 +1,5 @@
+-- How a room's audio and video frames are laid out in its recordings
+ALTER TABLE room_configs ADD COLUMN IF NOT EXISTS recording_mode TEXT NOT NULL DEFAULT 'interleaved';
+
+-- Per-track codec, counters, timestamps and file of frame recordings, as JSON
+ALTER TABLE recordings ADD COLUMN IF NOT EXISTS tracks TEXT;
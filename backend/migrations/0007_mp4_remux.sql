@@ -0,0 +1,2 @@
+-- Fragmented MP4 copy of a recording, written after it is finalized
+ALTER TABLE recordings ADD COLUMN IF NOT EXISTS mp4_path TEXT;
@@ -0,0 +1,2 @@
+-- Channel count of frame streams' audio, declared by the room config
+ALTER TABLE room_configs ADD COLUMN IF NOT EXISTS audio_channels INTEGER;
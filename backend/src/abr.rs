@@ -0,0 +1,179 @@
+/*
+ * abr.rs
+ * Purpose: Adaptive bitrate feedback for publishers
+ *
+ * This file contains:
+ * - QualityBounds, the range a room's config lets the server adapt within
+ * - Signals sampled from viewer lag, server load and ingest throughput
+ * - AbrController, which turns those signals into target bitrate, target
+ *   frame rate and keyframe requests for the room's publisher
+ *
+ * The controller backs off multiplicatively while there is pressure, lowering
+ * bitrate first and frame rate once bitrate is at its floor, and recovers
+ * additively in the reverse order once the pressure has cleared. Neither ever
+ * drops below what the room's min_bitrate and quality_degradation_limit allow.
+ */
+
+use std::time::Duration;
+use crate::models::{FeedbackReason, RoomConfig, ServerMessage};
+
+// How often the controller samples its signals
+pub const ABR_INTERVAL: Duration = Duration::from_secs(2);
+
+// Used when the room doesn't set quality_degradation_limit
+const DEFAULT_DEGRADATION_LIMIT: i32 = 50;
+
+// Multiplier applied to the target under pressure
+const BACKOFF: f64 = 0.8;
+
+// Share of the maximum restored per calm interval
+const RECOVERY_STEP: f64 = 0.1;
+
+// Calm intervals required before recovering
+const RECOVERY_HOLD: u32 = 3;
+
+// Bitrate is only raised once the publisher is using this much of the current target
+const RECOVERY_UTILIZATION: f64 = 0.8;
+
+// Server load, in percent, treated as pressure
+const CPU_PRESSURE: f64 = 85.0;
+const MEMORY_PRESSURE: u64 = 90;
+
+#[derive(Debug, Clone, Copy)]
+pub struct QualityBounds {
+    max_bitrate: f64,
+    min_bitrate: f64,
+    max_fps: Option<f64>,
+    min_fps: Option<f64>,
+}
+
+impl QualityBounds {
+    // None unless the room has adaptive bitrate enabled with a max_bitrate
+    pub fn from_config(config: &RoomConfig) -> Option<Self> {
+        if !config.adaptive_bitrate {
+            return None;
+        }
+        let max_bitrate = f64::from(config.max_bitrate.filter(|b| *b > 0)?);
+        let keep = 1.0 - f64::from(config.quality_degradation_limit.unwrap_or(DEFAULT_DEGRADATION_LIMIT).clamp(0, 100)) / 100.0;
+
+        // Both min_bitrate and the degradation limit are floors; the higher one wins
+        let min_bitrate = config.min_bitrate
+            .map_or(0.0, f64::from)
+            .max(max_bitrate * keep)
+            .min(max_bitrate);
+        let max_fps = config.frame_rate.filter(|f| *f > 0).map(f64::from);
+
+        Some(Self {
+            max_bitrate,
+            min_bitrate,
+            max_fps,
+            min_fps: max_fps.map(|fps| (fps * keep).max(1.0)),
+        })
+    }
+}
+
+// One sample of what the controller reacts to
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Signals {
+    // Cumulative for the room; the controller looks at the change since the last sample
+    pub viewer_lag_events: u64,
+    pub viewer_packets_dropped: u64,
+    pub cpu_percent: f64,
+    pub memory_percent: u64,
+    // Publisher's incoming bitrate, bits per second
+    pub ingest_bitrate: f64,
+}
+
+pub struct AbrController {
+    bounds: QualityBounds,
+    bitrate: f64,
+    fps: Option<f64>,
+    last: Option<Signals>,
+    calm: u32,
+    // Last target sent to the publisher, which starts out encoding at the maximum
+    sent: (u32, Option<u32>),
+}
+
+impl AbrController {
+    pub fn new(bounds: QualityBounds) -> Self {
+        Self {
+            bounds,
+            bitrate: bounds.max_bitrate,
+            fps: bounds.max_fps,
+            last: None,
+            calm: 0,
+            sent: (bounds.max_bitrate as u32, bounds.max_fps.map(|fps| fps as u32)),
+        }
+    }
+
+    // Feed a new sample; returns the messages to send the publisher
+    pub fn update(&mut self, signals: Signals) -> Vec<ServerMessage> {
+        let mut messages = Vec::new();
+
+        let lagging = self.last.is_some_and(|last| {
+            signals.viewer_lag_events > last.viewer_lag_events
+                || signals.viewer_packets_dropped > last.viewer_packets_dropped
+        });
+        self.last = Some(signals);
+
+        let pressure = if lagging {
+            // Lagging viewers resume at the next keyframe; don't make them wait a whole GOP
+            messages.push(ServerMessage::RequestKeyframe { reason: FeedbackReason::ViewerLag });
+            Some(FeedbackReason::ViewerLag)
+        } else if signals.cpu_percent > CPU_PRESSURE || signals.memory_percent > MEMORY_PRESSURE {
+            Some(FeedbackReason::ServerLoad)
+        } else {
+            None
+        };
+
+        let reason = match pressure {
+            Some(reason) => {
+                self.calm = 0;
+                self.back_off();
+                reason
+            }
+            None => {
+                self.calm += 1;
+                if self.calm < RECOVERY_HOLD {
+                    return messages;
+                }
+                self.recover(signals.ingest_bitrate);
+                FeedbackReason::Recovered
+            }
+        };
+
+        let target = (self.bitrate.round() as u32, self.fps.map(|fps| fps.round() as u32));
+        if target != self.sent {
+            self.sent = target;
+            messages.push(ServerMessage::TargetQuality {
+                bitrate: target.0,
+                fps: target.1,
+                reason,
+            });
+        }
+        messages
+    }
+
+    fn back_off(&mut self) {
+        let bounds = self.bounds;
+        if self.bitrate > bounds.min_bitrate {
+            self.bitrate = (self.bitrate * BACKOFF).max(bounds.min_bitrate);
+        } else if let (Some(fps), Some(min_fps)) = (self.fps, bounds.min_fps) {
+            self.fps = Some((fps * BACKOFF).max(min_fps));
+        }
+    }
+
+    fn recover(&mut self, ingest_bitrate: f64) {
+        let bounds = self.bounds;
+        if let (Some(fps), Some(max_fps)) = (self.fps, bounds.max_fps) {
+            if fps < max_fps {
+                self.fps = Some((fps + max_fps * RECOVERY_STEP).min(max_fps));
+                return;
+            }
+        }
+        // No point raising a target the publisher isn't reaching, e.g. on a congested uplink
+        if self.bitrate < bounds.max_bitrate && ingest_bitrate >= self.bitrate * RECOVERY_UTILIZATION {
+            self.bitrate = (self.bitrate + bounds.max_bitrate * RECOVERY_STEP).min(bounds.max_bitrate);
+        }
+    }
+}
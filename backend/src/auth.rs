@@ -3,15 +3,29 @@ use serde::{Deserialize, Serialize};
 use chrono::{Utc, Duration};
 use uuid::Uuid;
 
-use crate::error::AppError;
+use crate::{error::AppError, models::ParticipantRole};
 
 const JWT_SECRET: &[u8] = b"your-secret-key";  // In production, this should be properly configured
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Claims {
     pub sub: String,      // Subject (API key)
     pub user_id: String,  // Unique user ID
     pub exp: i64,         // Expiration time
+    // Set on room tokens, which only grant access to this room's media
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub room_id: Option<String>,
+    // The WebSocket role a room token was issued for
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub role: Option<ParticipantRole>,
+}
+
+impl Claims {
+    // Regular tokens connect in any role to rooms their holder owns; room
+    // tokens only in the role they were issued for
+    pub fn grants(&self, role: ParticipantRole) -> bool {
+        self.room_id.is_none() || self.role == Some(role)
+    }
 }
 
 #[derive(Clone)]
@@ -28,19 +42,41 @@ impl Auth {
         }
     }
 
-    pub fn generate_token(&self, api_key: &str) -> Result<String, AppError> {
-        let user_id = Uuid::new_v4().to_string();
-        let expiration = Utc::now()
-            .checked_add_signed(Duration::hours(24))
-            .expect("valid timestamp")
-            .timestamp();
-
-        let claims = Claims {
+    pub fn generate_token(&self, user_id: &Uuid, api_key: &str) -> Result<String, AppError> {
+        self.sign(Claims {
             sub: api_key.to_string(),
-            user_id,
-            exp: expiration,
-        };
+            user_id: user_id.to_string(),
+            exp: expiration(),
+            room_id: None,
+            role: None,
+        })
+    }
+
+    // Room tokens are handed to devices and players and end up in URLs, so
+    // they don't carry the API key
+    pub fn generate_room_token(&self, user_id: &Uuid, room_id: &str, role: ParticipantRole) -> Result<String, AppError> {
+        self.sign(Claims {
+            sub: user_id.to_string(),
+            user_id: user_id.to_string(),
+            exp: expiration(),
+            room_id: Some(room_id.to_string()),
+            role: Some(role),
+        })
+    }
 
+    // Short-lived viewer tokens for the URIs a playlist or manifest lists,
+    // which get cached and logged where the caller's own token mustn't
+    pub fn generate_media_token(&self, user_id: &str, room_id: &str, valid_for: Duration) -> Result<String, AppError> {
+        self.sign(Claims {
+            sub: user_id.to_string(),
+            user_id: user_id.to_string(),
+            exp: (Utc::now() + valid_for).timestamp(),
+            room_id: Some(room_id.to_string()),
+            role: Some(ParticipantRole::Viewer),
+        })
+    }
+
+    fn sign(&self, claims: Claims) -> Result<String, AppError> {
         encode(&Header::default(), &claims, &self.encoding_key)
             .map_err(|e| AppError::Unauthorized(format!("Failed to create token: {}", e)))
     }
@@ -54,4 +90,86 @@ impl Auth {
         .map(|token_data| token_data.claims)
         .map_err(|e| AppError::Unauthorized(format!("Invalid token: {}", e)))
     }
-} 
\ No newline at end of file
+}
+
+// Tokens are valid for 24 hours
+fn expiration() -> i64 {
+    Utc::now()
+        .checked_add_signed(Duration::hours(24))
+        .expect("valid timestamp")
+        .timestamp()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn claims(auth: &Auth, token: &str) -> Claims {
+        auth.validate_token(token).unwrap()
+    }
+
+    #[test]
+    fn room_tokens_grant_only_their_role() {
+        let auth = Auth::new(b"test-secret");
+        let user = Uuid::new_v4();
+
+        let viewer = claims(&auth, &auth.generate_room_token(&user, "room", ParticipantRole::Viewer).unwrap());
+        assert_eq!(viewer.room_id.as_deref(), Some("room"));
+        assert!(viewer.grants(ParticipantRole::Viewer));
+        assert!(!viewer.grants(ParticipantRole::Publisher));
+
+        let publisher = claims(&auth, &auth.generate_room_token(&user, "room", ParticipantRole::Publisher).unwrap());
+        assert!(publisher.grants(ParticipantRole::Publisher));
+        assert!(!publisher.grants(ParticipantRole::Viewer));
+    }
+
+    #[test]
+    fn regular_tokens_grant_every_role() {
+        let auth = Auth::new(b"test-secret");
+        let token = claims(&auth, &auth.generate_token(&Uuid::new_v4(), "api-key").unwrap());
+        assert_eq!(token.room_id, None);
+        assert!(token.grants(ParticipantRole::Publisher));
+        assert!(token.grants(ParticipantRole::Viewer));
+    }
+
+    #[test]
+    fn room_tokens_without_a_role_grant_nothing() {
+        let auth = Auth::new(b"test-secret");
+        let user = Uuid::new_v4().to_string();
+        let token = auth
+            .sign(Claims {
+                sub: user.clone(),
+                user_id: user,
+                exp: expiration(),
+                room_id: Some("room".to_string()),
+                role: None,
+            })
+            .unwrap();
+        let claims = claims(&auth, &token);
+        assert!(!claims.grants(ParticipantRole::Publisher));
+        assert!(!claims.grants(ParticipantRole::Viewer));
+    }
+
+    #[test]
+    fn media_tokens_are_short_lived_viewer_tokens() {
+        let auth = Auth::new(b"test-secret");
+        let token = auth.generate_media_token("user", "room", Duration::minutes(10)).unwrap();
+        let claims = claims(&auth, &token);
+        assert_eq!(claims.user_id, "user");
+        assert_eq!(claims.room_id.as_deref(), Some("room"));
+        assert!(claims.grants(ParticipantRole::Viewer));
+        assert!(!claims.grants(ParticipantRole::Publisher));
+        assert!(claims.exp <= (Utc::now() + Duration::minutes(10)).timestamp());
+
+        let expired = auth.generate_media_token("user", "room", Duration::minutes(-10)).unwrap();
+        assert!(auth.validate_token(&expired).is_err());
+    }
+
+    #[test]
+    fn rejects_tokens_signed_with_another_secret() {
+        let token = Auth::new(b"other-secret")
+            .generate_room_token(&Uuid::new_v4(), "room", ParticipantRole::Publisher)
+            .unwrap();
+        assert!(matches!(Auth::new(b"test-secret").validate_token(&token), Err(AppError::Unauthorized(_))));
+    }
+}
@@ -9,6 +9,31 @@ pub struct Config {
     pub max_connections: u32,
     pub max_room_size: u32,
     pub storage_path: String,
+    // Directory template below storage_path, see storage::layout
+    pub storage_layout: String,
+    // Unix permission bits for recording files, e.g. 0o640
+    pub storage_file_mode: Option<u32>,
+    // Recordings larger than this roll over into a new segment file
+    pub storage_max_file_size: Option<u64>,
+    pub database_url: Option<String>,
+    pub redis_url: Option<String>,
+    pub storage_backend: StorageBackendConfig,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub enum StorageBackendConfig {
+    Local,
+    S3(S3Config),
+}
+
+// Any S3-compatible service; MinIO needs path-style addressing, which is what we use
+#[derive(Clone, Debug, Deserialize)]
+pub struct S3Config {
+    pub endpoint: String,
+    pub bucket: String,
+    pub region: String,
+    pub access_key: String,
+    pub secret_key: String,
 }
 
 impl Config {
@@ -25,6 +50,39 @@ impl Config {
                 .parse()
                 .unwrap_or(100),
             storage_path: env::var("STORAGE_PATH").unwrap_or_else(|_| "data/recordings".to_string()),
+            storage_layout: env::var("STORAGE_LAYOUT").unwrap_or_else(|_| "{room_id}".to_string()),
+            storage_file_mode: match env::var("STORAGE_FILE_MODE") {
+                Ok(mode) => Some(u32::from_str_radix(mode.trim_start_matches("0o"), 8)
+                    .map_err(|_| AppError::InternalError(format!("STORAGE_FILE_MODE must be octal, got {}", mode)))?),
+                Err(_) => None,
+            },
+            storage_max_file_size: env::var("STORAGE_MAX_FILE_SIZE")
+                .ok()
+                .and_then(|size| size.parse().ok())
+                .filter(|size| *size > 0),
+            database_url: env::var("DATABASE_URL").ok().filter(|url| !url.is_empty()),
+            redis_url: env::var("REDIS_URL").ok().filter(|url| !url.is_empty()),
+            storage_backend: Self::load_storage_backend()?,
         })
     }
+
+    fn load_storage_backend() -> Result<StorageBackendConfig, AppError> {
+        match env::var("STORAGE_BACKEND").unwrap_or_else(|_| "local".to_string()).as_str() {
+            "local" => Ok(StorageBackendConfig::Local),
+            "s3" => {
+                let endpoint = env::var("S3_ENDPOINT")
+                    .or_else(|_| env::var("MINIO_ENDPOINT"))
+                    .map_err(|_| AppError::InternalError("S3_ENDPOINT must be set for the s3 storage backend".to_string()))?;
+
+                Ok(StorageBackendConfig::S3(S3Config {
+                    endpoint: endpoint.trim_end_matches('/').to_string(),
+                    bucket: env::var("S3_BUCKET").unwrap_or_else(|_| "recordings".to_string()),
+                    region: env::var("S3_REGION").unwrap_or_else(|_| "us-east-1".to_string()),
+                    access_key: env::var("S3_ACCESS_KEY").unwrap_or_else(|_| "minioadmin".to_string()),
+                    secret_key: env::var("S3_SECRET_KEY").unwrap_or_else(|_| "minioadmin".to_string()),
+                }))
+            }
+            other => Err(AppError::InternalError(format!("Unknown STORAGE_BACKEND: {}", other))),
+        }
+    }
 } 
\ No newline at end of file
@@ -0,0 +1,410 @@
+/*
+ * cues.rs
+ * Purpose: Making finalized WebM/Matroska recordings seekable
+ *
+ * This file contains:
+ * - SegmentIndex, what ingest learned about one segment file while writing it
+ * - Rebuilding the file's head with a known Segment size, a Duration and a
+ *   SeekHead, and a Cues element listing every indexed keyframe cluster
+ * - write_cues, which rewrites a stored segment with both
+ *
+ * MediaRecorder output and the muxer's own both leave the Segment size unknown
+ * and carry no Cues, so players can't seek in them. The clusters themselves are
+ * copied unchanged; only the head before the first Cluster is rebuilt, and the
+ * cluster positions from the ingest index are shifted by however much it grew.
+ *
+ * Storage can't patch a file in place, so the rewrite reads and re-uploads the
+ * whole segment: with S3 that is a download and a multipart upload of every
+ * byte. Segments over MAX_REWRITE_LEN are left as they are; rollover keeps
+ * segments below it when STORAGE_MAX_FILE_SIZE is set.
+ */
+
+use futures::StreamExt;
+use tracing::info;
+use crate::{
+    error::AppError,
+    models::KeyframeEntry,
+    storage::Storage,
+    webm::{
+        element, float_element, id_bytes, read_element_header, uint_element, CUES_ID, CUE_CLUSTER_POSITION_ID,
+        CUE_POINT_ID, CUE_TIME_ID, CUE_TRACK_ID, CUE_TRACK_POSITIONS_ID, DURATION_ID, INFO_ID, SEEK_HEAD_ID,
+        SEEK_ID, SEEK_ID_ID, SEEK_POSITION_ID, SEGMENT_ID, TRACKS_ID, VOID_ID,
+    },
+};
+
+// The head is parsed from memory; MediaRecorder's is a few hundred bytes
+const MAX_HEAD_LEN: u64 = 1024 * 1024;
+
+// Bigger segments aren't copied again just to add Cues
+const MAX_REWRITE_LEN: u64 = 512 * 1024 * 1024;
+
+// One segment file as ingest saw it
+#[derive(Debug, Clone)]
+pub struct SegmentIndex {
+    // Bytes before the first Cluster
+    pub head_len: u64,
+    // Offset of the Segment's body in the file
+    pub segment_data_offset: u64,
+    // Nanoseconds per timecode unit
+    pub timecode_scale: u64,
+    // Timecode of the last block, in milliseconds
+    pub duration_ms: i64,
+    // The segment's keyframe clusters, offsets counted from the start of the file
+    pub keyframes: Vec<KeyframeEntry>,
+}
+
+// Rewrite a stored segment with a known Segment size, Duration, SeekHead and
+// Cues; returns how far the clusters moved, zero if the segment is too big to rewrite
+pub async fn write_cues(storage: &Storage, key: &str, index: &SegmentIndex) -> Result<i64, AppError> {
+    if index.head_len > MAX_HEAD_LEN {
+        return Err(AppError::StorageError(format!("{} has a {} byte head", key, index.head_len)));
+    }
+    let file_len = storage.stat_recording(key).await?.size;
+    if file_len < index.head_len {
+        return Err(AppError::StorageError(format!("{} is shorter than its head", key)));
+    }
+    if file_len > MAX_REWRITE_LEN {
+        info!("Not indexing {}: {} bytes is too big to rewrite", key, file_len);
+        return Ok(0);
+    }
+
+    let old_head = storage.read_recording_range(key, 0, Some(index.head_len)).await?;
+    let (head, cues) = rebuild(&old_head, index, file_len - index.head_len)?;
+
+    let mut clusters = storage.stream_recording(key, index.head_len, None).await?;
+    let mut upload = storage.replace_recording(key).await?;
+    let result = async {
+        upload.append(&head).await?;
+        while let Some(chunk) = clusters.next().await {
+            upload.append(&chunk?).await?;
+        }
+        upload.append(&cues).await
+    }
+    .await;
+
+    match result {
+        Ok(()) => {
+            upload.finalize().await?;
+            Ok(head.len() as i64 - index.head_len as i64)
+        }
+        Err(e) => {
+            let _ = upload.abort().await;
+            Err(e)
+        }
+    }
+}
+
+// New head and Cues for a segment whose clusters take `clusters_len` bytes
+fn rebuild(old_head: &[u8], index: &SegmentIndex, clusters_len: u64) -> Result<(Vec<u8>, Vec<u8>), AppError> {
+    let invalid = |what: &str| AppError::StreamingError(format!("Cannot index segment: {}", what));
+
+    // Everything up to the Segment element stays as it is, i.e. the EBML header
+    let mut pos = 0usize;
+    let segment_start = loop {
+        let (id, header_len, size) = old_head
+            .get(pos..)
+            .and_then(read_element_header)
+            .ok_or_else(|| invalid("no Segment"))?;
+        if id == SEGMENT_ID {
+            break pos;
+        }
+        pos += header_len + size.ok_or_else(|| invalid("unknown-size header element"))? as usize;
+    };
+    let mut children: Vec<(u32, Vec<u8>)> = Vec::new();
+    let mut pos = index.segment_data_offset as usize;
+    while pos < old_head.len() {
+        let (id, header_len, size) = read_element_header(&old_head[pos..]).ok_or_else(|| invalid("bad element"))?;
+        let end = pos + header_len + size.ok_or_else(|| invalid("unknown-size element before the clusters"))? as usize;
+        let bytes = old_head.get(pos..end).ok_or_else(|| invalid("element overruns the head"))?;
+        match id {
+            // Rewritten below
+            SEEK_HEAD_ID | CUES_ID | VOID_ID => {}
+            INFO_ID => children.push((id, rebuild_info(&bytes[header_len..], index).ok_or_else(|| invalid("bad Info"))?)),
+            _ => children.push((id, bytes.to_vec())),
+        }
+        pos = end;
+    }
+
+    // SeekPositions are written at full width so the SeekHead's size doesn't
+    // depend on them
+    let seek_targets: Vec<u32> = children
+        .iter()
+        .map(|(id, _)| *id)
+        .filter(|id| matches!(*id, INFO_ID | TRACKS_ID))
+        .chain([CUES_ID])
+        .collect();
+    let seek_head_len = seek_head(&seek_targets.iter().map(|id| (*id, 0)).collect::<Vec<_>>()).len() as u64;
+
+    let mut positions = Vec::new();
+    let mut offset = seek_head_len;
+    for (id, bytes) in &children {
+        if seek_targets.contains(id) {
+            positions.push((*id, offset));
+        }
+        offset += bytes.len() as u64;
+    }
+    let clusters_start = offset;
+    positions.push((CUES_ID, clusters_start + clusters_len));
+
+    // Cluster positions are relative to the Segment body
+    let mut cues_body = Vec::new();
+    for keyframe in index.keyframes.iter().filter(|k| k.offset >= index.head_len) {
+        let position = keyframe.offset - index.head_len + clusters_start;
+        let mut track_positions = uint_element(CUE_TRACK_ID, keyframe.track);
+        track_positions.extend_from_slice(&uint_element(CUE_CLUSTER_POSITION_ID, position));
+
+        let mut point = uint_element(CUE_TIME_ID, to_timecode(keyframe.time_ms, index.timecode_scale));
+        point.extend_from_slice(&element(CUE_TRACK_POSITIONS_ID, &track_positions));
+        cues_body.extend_from_slice(&element(CUE_POINT_ID, &point));
+    }
+    let cues = element(CUES_ID, &cues_body);
+
+    let mut head = old_head[..segment_start].to_vec();
+    head.extend_from_slice(&id_bytes(SEGMENT_ID));
+    // Always eight bytes, like the unknown size it replaces
+    let segment_len = clusters_start + clusters_len + cues.len() as u64;
+    head.extend_from_slice(&(segment_len | (1u64 << 56)).to_be_bytes());
+    head.extend_from_slice(&seek_head(&positions));
+    for (_, bytes) in children {
+        head.extend_from_slice(&bytes);
+    }
+    Ok((head, cues))
+}
+
+// Info with its Duration replaced by the one ingest measured
+fn rebuild_info(body: &[u8], index: &SegmentIndex) -> Option<Vec<u8>> {
+    let mut info = Vec::new();
+    let mut pos = 0;
+    while pos < body.len() {
+        let (id, header_len, size) = read_element_header(&body[pos..])?;
+        let end = pos + header_len + size? as usize;
+        if id != DURATION_ID {
+            info.extend_from_slice(body.get(pos..end)?);
+        }
+        pos = end;
+    }
+    let duration = to_timecode(index.duration_ms, index.timecode_scale) as f64;
+    info.extend_from_slice(&float_element(DURATION_ID, duration));
+    Some(element(INFO_ID, &info))
+}
+
+fn seek_head(positions: &[(u32, u64)]) -> Vec<u8> {
+    let mut body = Vec::new();
+    for (id, position) in positions {
+        let mut seek = element(SEEK_ID_ID, &id_bytes(*id));
+        seek.extend_from_slice(&element(SEEK_POSITION_ID, &position.to_be_bytes()));
+        body.extend_from_slice(&element(SEEK_ID, &seek));
+    }
+    element(SEEK_HEAD_ID, &body)
+}
+
+fn to_timecode(ms: i64, timecode_scale: u64) -> u64 {
+    (ms.max(0) as u64).saturating_mul(1_000_000) / timecode_scale.max(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        models::FrameType,
+        mux::{MatroskaMuxer, MuxTrack},
+        webm::{WebmEvent, WebmScanner, CLUSTER_ID, EBML_HEADER_ID, TIMECODE_ID},
+    };
+
+    // A muxed VP8/Opus recording: keyframes at 0 and 6s, audio every 20ms
+    fn recording() -> Vec<u8> {
+        let mut muxer = MatroskaMuxer::new(vec![
+            MuxTrack::new(FrameType::Video, Some("vp8"), Some((640, 360))),
+            MuxTrack::new(FrameType::Audio, Some("opus"), None),
+        ]);
+        let mut out = Vec::new();
+        for ms in (0..8_000).step_by(20) {
+            if ms % 40 == 0 {
+                // VP8 key frames have the low bit of the first byte clear
+                let frame = if ms % 6_000 == 0 { [0x10; 64] } else { [0x11; 64] };
+                muxer.write_frame(FrameType::Video, 1_000 + ms, &frame, 1_000, &mut out);
+            }
+            muxer.write_frame(FrameType::Audio, 1_000 + ms, &[0xFC; 16], 1_000, &mut out);
+        }
+        out
+    }
+
+    // What ingest would have recorded about a file, and the scanner's events for it
+    fn scan(file: &[u8]) -> (SegmentIndex, Vec<WebmEvent>) {
+        let mut scanner = WebmScanner::new();
+        let events = scanner.feed(file);
+        assert!(!scanner.failed(), "{:?}", scanner.corruption());
+
+        let mut keyframes = Vec::new();
+        let mut cluster = None;
+        let mut duration_ms = 0;
+        for event in &events {
+            match *event {
+                WebmEvent::ClusterStart { offset } => cluster = Some(offset),
+                WebmEvent::Block { track, keyframe, timecode, .. } => {
+                    duration_ms = duration_ms.max(timecode);
+                    if let (Some(offset), true) = (cluster.take(), keyframe && scanner.is_video_track(track)) {
+                        keyframes.push(KeyframeEntry { segment: 0, track, time_ms: timecode, offset });
+                    }
+                }
+            }
+        }
+        let index = SegmentIndex {
+            head_len: scanner.init_segment_len().unwrap(),
+            segment_data_offset: scanner.segment_data_offset().unwrap(),
+            timecode_scale: scanner.timecode_scale(),
+            duration_ms,
+            keyframes,
+        };
+        (index, events)
+    }
+
+    fn rewrite(file: &[u8], index: &SegmentIndex) -> Vec<u8> {
+        let head_len = index.head_len as usize;
+        let (mut out, cues) = rebuild(&file[..head_len], index, (file.len() - head_len) as u64).unwrap();
+        out.extend_from_slice(&file[head_len..]);
+        out.extend_from_slice(&cues);
+        out
+    }
+
+    // The sized elements at the start of `data`, as (ID, offset, body), up to
+    // the first Cluster
+    fn elements(data: &[u8]) -> Vec<(u32, usize, &[u8])> {
+        let mut out = Vec::new();
+        let mut pos = 0;
+        while let Some((id, header_len, Some(size))) = data.get(pos..).and_then(read_element_header) {
+            if id == CLUSTER_ID {
+                break;
+            }
+            let end = pos + header_len + size as usize;
+            out.push((id, pos, &data[pos + header_len..end]));
+            pos = end;
+        }
+        out
+    }
+
+    fn child(body: &[u8], id: u32) -> &[u8] {
+        elements(body).into_iter().find(|(child, _, _)| *child == id).unwrap().2
+    }
+
+    fn uint(body: &[u8]) -> u64 {
+        body.iter().fold(0, |acc, b| (acc << 8) | u64::from(*b))
+    }
+
+    #[test]
+    fn rewritten_segment_scans_the_same() {
+        let file = recording();
+        let (index, events) = scan(&file);
+        assert_eq!(index.keyframes.len(), 2);
+        let rewritten = rewrite(&file, &index);
+
+        let mut scanner = WebmScanner::new();
+        let mut rescanned = scanner.feed(&rewritten);
+        assert!(!scanner.failed(), "{:?}", scanner.corruption());
+        assert_eq!(scanner.segment_data_offset(), Some(index.segment_data_offset));
+
+        // The same clusters and blocks, moved by as much as the head grew
+        let shift = scanner.init_segment_len().unwrap() - index.head_len;
+        for event in &mut rescanned {
+            match event {
+                WebmEvent::ClusterStart { offset } | WebmEvent::Block { offset, .. } => *offset -= shift,
+            }
+        }
+        assert_eq!(rescanned, events);
+    }
+
+    #[test]
+    fn seek_head_and_cues_point_at_their_elements() {
+        let file = recording();
+        let (index, _) = scan(&file);
+        let rewritten = rewrite(&file, &index);
+
+        let (id, header_len, size) = read_element_header(&rewritten).unwrap();
+        assert_eq!(id, EBML_HEADER_ID);
+        let segment_start = header_len + size.unwrap() as usize;
+        let (id, header_len, size) = read_element_header(&rewritten[segment_start..]).unwrap();
+        assert_eq!(id, SEGMENT_ID);
+        assert_eq!(segment_start + header_len, index.segment_data_offset as usize);
+        // The Segment now has a size, and it runs to the end of the file
+        let body = &rewritten[segment_start + header_len..];
+        assert_eq!(size, Some(body.len() as u64));
+
+        let head = elements(body);
+        assert_eq!(head.iter().map(|(id, _, _)| *id).collect::<Vec<_>>(), [SEEK_HEAD_ID, INFO_ID, TRACKS_ID]);
+
+        // Every Seek names an element found at its position
+        let mut cues_position = None;
+        let seeks = elements(head[0].2);
+        assert_eq!(seeks.len(), 3);
+        for (_, _, seek) in seeks {
+            let target = uint(child(seek, SEEK_ID_ID)) as u32;
+            let position = uint(child(seek, SEEK_POSITION_ID)) as usize;
+            assert_eq!(read_element_header(&body[position..]).unwrap().0, target);
+            if target == CUES_ID {
+                cues_position = Some(position);
+            }
+        }
+
+        // The Cues close the file, and each CuePoint names a cluster starting at its time
+        let cues_position = cues_position.unwrap();
+        let (id, header_len, size) = read_element_header(&body[cues_position..]).unwrap();
+        assert_eq!(id, CUES_ID);
+        let cues = &body[cues_position + header_len..];
+        assert_eq!(size, Some(cues.len() as u64));
+        let mut times = Vec::new();
+        for (_, _, point) in elements(cues) {
+            let time = uint(child(point, CUE_TIME_ID));
+            let positions = child(point, CUE_TRACK_POSITIONS_ID);
+            assert_eq!(uint(child(positions, CUE_TRACK_ID)), 1);
+            let position = uint(child(positions, CUE_CLUSTER_POSITION_ID)) as usize;
+
+            let (id, header_len, _) = read_element_header(&body[position..]).unwrap();
+            assert_eq!(id, CLUSTER_ID);
+            let cluster_body = &body[position + header_len..];
+            let (id, header_len, size) = read_element_header(cluster_body).unwrap();
+            assert_eq!(id, TIMECODE_ID);
+            assert_eq!(uint(&cluster_body[header_len..header_len + size.unwrap() as usize]), time);
+            times.push(time);
+        }
+        assert_eq!(times, [0, 6_000]);
+
+        // Info carries the measured Duration
+        let duration = f64::from_be_bytes(child(head[1].2, DURATION_ID).try_into().unwrap());
+        assert_eq!(duration, 7_980.0);
+    }
+
+    #[test]
+    fn rebuilding_a_rewritten_head_changes_nothing() {
+        let file = recording();
+        let (index, _) = scan(&file);
+        let clusters_len = (file.len() - index.head_len as usize) as u64;
+        let (head, cues) = rebuild(&file[..index.head_len as usize], &index, clusters_len).unwrap();
+
+        // The SeekHead just written is replaced rather than kept alongside the new one
+        let mut rewritten = head.clone();
+        rewritten.extend_from_slice(&file[index.head_len as usize..]);
+        let (again, _) = scan(&rewritten);
+        assert_eq!(again.head_len, head.len() as u64);
+        assert_eq!(rebuild(&head, &again, clusters_len).unwrap(), (head, cues));
+    }
+
+    #[tokio::test]
+    async fn writes_cues_into_stored_segments() {
+        let dir = std::env::temp_dir().join(format!("cues-test-{}", uuid::Uuid::new_v4()));
+        let storage = Storage::local(dir.to_str().unwrap()).await.unwrap();
+        let key = "room/recording.webm";
+        let file = recording();
+        storage.backend().put(key, &file).await.unwrap();
+
+        let (index, _) = scan(&file);
+        let shift = write_cues(&storage, key, &index).await.unwrap();
+        let stored = storage.get_recording(key).await.unwrap();
+        assert_eq!(stored, rewrite(&file, &index));
+
+        let mut scanner = WebmScanner::new();
+        scanner.feed(&stored);
+        assert_eq!(shift, (scanner.init_segment_len().unwrap() - index.head_len) as i64);
+
+        let _ = tokio::fs::remove_dir_all(&dir).await;
+    }
+}
@@ -0,0 +1,205 @@
+/*
+ * dash.rs
+ * Purpose: MPEG-DASH manifests for live streams and finished recordings
+ *
+ * This file contains:
+ * - live_manifest, a dynamic MPD over a room's live segment window, with a
+ *   Period per publisher
+ * - vod_manifest, a static MPD over the fragments of a recording's MP4 copy
+ * - SegmentTemplate/SegmentTimeline writing shared by both
+ *
+ * Segments are the fMP4 fragments HLS serves, so they start at video keyframes
+ * and hold every track: each Period has a single muxed Representation. Times
+ * are in milliseconds, the unit segments are indexed in. Like HLS playlists,
+ * manifests append a query string to their segment URIs when given one.
+ */
+
+use std::fmt::Write;
+use chrono::{DateTime, SecondsFormat, Utc};
+use crate::{
+    hls::{LiveHls, LiveInit, LiveSegment},
+    models::FragmentIndex,
+};
+
+const PROFILE: &str = "urn:mpeg:dash:profile:isoff-live:2011";
+
+// How often players reload a live manifest; about one segment
+const MINIMUM_UPDATE_PERIOD: &str = "PT2S";
+
+const MIN_BUFFER_TIME: &str = "PT2S";
+
+// Dynamic MPD over the live window, None until the first segment is out. Once
+// the publisher has left it stops asking for updates and gets a duration.
+pub fn live_manifest(hls: &LiveHls, now: DateTime<Utc>, query: &str) -> Option<String> {
+    let available_since = hls.available_since()?;
+    let window: Vec<&LiveSegment> = hls.window().collect();
+    window.first()?;
+
+    // Each publisher's segments make a Period starting when it did
+    let mut periods: Vec<(&LiveInit, u32, Vec<&LiveSegment>)> = Vec::new();
+    for segment in window {
+        match periods.last_mut() {
+            Some((_, init_id, segments)) if *init_id == segment.init_id => segments.push(segment),
+            _ => periods.push((hls.init(segment.init_id)?, segment.init_id, vec![segment])),
+        }
+    }
+    let end_ms = periods
+        .iter()
+        .filter_map(|(init, _, segments)| segments.last().map(|s| init.period_start_ms + s.start_ms + s.duration_ms))
+        .max()
+        .unwrap_or(0);
+    let buffered_ms: i64 = periods.iter().flat_map(|(_, _, segments)| segments.iter()).map(|s| s.duration_ms).sum();
+
+    let mut out = String::new();
+    out.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    let _ = write!(
+        out,
+        "<MPD xmlns=\"urn:mpeg:dash:schema:mpd:2011\" profiles=\"{}\" type=\"dynamic\" availabilityStartTime=\"{}\" publishTime=\"{}\" timeShiftBufferDepth=\"{}\" minBufferTime=\"{}\"",
+        PROFILE,
+        date_time(available_since),
+        date_time(now),
+        duration(buffered_ms),
+        MIN_BUFFER_TIME,
+    );
+    if hls.is_ended() {
+        let _ = writeln!(out, " mediaPresentationDuration=\"{}\">", duration(end_ms));
+    } else {
+        let _ = writeln!(out, " minimumUpdatePeriod=\"{}\">", MINIMUM_UPDATE_PERIOD);
+    }
+
+    for (init, init_id, segments) in &periods {
+        let timeline: Vec<(i64, i64)> = segments.iter().map(|s| (s.start_ms, s.duration_ms)).collect();
+        let bytes: usize = segments.iter().map(|s| s.data.len()).sum();
+        let _ = writeln!(out, "  <Period id=\"{}\" start=\"{}\">", init_id, duration(init.period_start_ms));
+        let representation = Representation {
+            codecs: &init.codecs,
+            video_size: init.video_size,
+            bandwidth: bandwidth(bytes as u64, timeline.iter().map(|(_, d)| d).sum()),
+            initialization: format!("live/init_{}.mp4{}", init_id, query),
+            media: format!("live/$Number$.m4s{}", query),
+            start_number: segments[0].sequence,
+            presentation_time_offset: 0,
+            timeline,
+        };
+        representation.write(&mut out);
+        out.push_str("  </Period>\n");
+    }
+    out.push_str("</MPD>\n");
+    Some(out)
+}
+
+// Static MPD over the fragments of an MP4 copy, whose init segment and
+// fragments are served as `{base}init.mp4` and `{base}{n}.m4s`, numbered from 0
+pub fn vod_manifest(index: &FragmentIndex, base: &str, query: &str) -> String {
+    let timeline: Vec<(i64, i64)> = index.fragments.iter().map(|f| (f.start_ms, f.duration_ms)).collect();
+    let first = timeline.first().map_or(0, |(start, _)| *start);
+    let end = timeline.last().map_or(0, |(start, duration)| start + duration);
+    let bytes: u64 = index.fragments.iter().map(|f| f.size).sum();
+
+    let mut out = String::new();
+    out.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    let _ = writeln!(
+        out,
+        "<MPD xmlns=\"urn:mpeg:dash:schema:mpd:2011\" profiles=\"{}\" type=\"static\" mediaPresentationDuration=\"{}\" minBufferTime=\"{}\">",
+        PROFILE,
+        duration(end - first),
+        MIN_BUFFER_TIME,
+    );
+    out.push_str("  <Period id=\"0\" start=\"PT0S\">\n");
+    let representation = Representation {
+        codecs: index.codecs.as_deref().unwrap_or_default(),
+        video_size: index.video_size,
+        bandwidth: bandwidth(bytes, end - first),
+        initialization: format!("{}init.mp4{}", base, query),
+        media: format!("{}$Number$.m4s{}", base, query),
+        start_number: 0,
+        presentation_time_offset: first,
+        timeline,
+    };
+    representation.write(&mut out);
+    out.push_str("  </Period>\n</MPD>\n");
+    out
+}
+
+// The one Representation of a Period, holding every track
+struct Representation<'a> {
+    codecs: &'a str,
+    video_size: Option<(u32, u32)>,
+    bandwidth: u64,
+    initialization: String,
+    media: String,
+    start_number: u64,
+    presentation_time_offset: i64,
+    // Start and duration of each segment
+    timeline: Vec<(i64, i64)>,
+}
+
+impl Representation<'_> {
+    // As an AdaptationSet of its own
+    fn write(&self, out: &mut String) {
+        let has_video = self.codecs.split(',').any(|codec| codec.starts_with("avc1"));
+        let mime_type = if has_video { "video/mp4" } else { "audio/mp4" };
+        let _ = writeln!(
+            out,
+            "    <AdaptationSet mimeType=\"{}\" segmentAlignment=\"true\" startWithSAP=\"1\">",
+            mime_type
+        );
+        let _ = write!(out, "      <Representation id=\"0\" bandwidth=\"{}\"", self.bandwidth);
+        if !self.codecs.is_empty() {
+            let _ = write!(out, " codecs=\"{}\"", self.codecs);
+        }
+        if let Some((width, height)) = self.video_size {
+            let _ = write!(out, " width=\"{}\" height=\"{}\"", width, height);
+        }
+        out.push_str(">\n");
+        let _ = writeln!(
+            out,
+            "        <SegmentTemplate timescale=\"1000\" initialization=\"{}\" media=\"{}\" startNumber=\"{}\" presentationTimeOffset=\"{}\">",
+            self.initialization, self.media, self.start_number, self.presentation_time_offset
+        );
+        out.push_str("          <SegmentTimeline>\n");
+        write_timeline(out, &self.timeline);
+        out.push_str("          </SegmentTimeline>\n        </SegmentTemplate>\n      </Representation>\n    </AdaptationSet>\n");
+    }
+}
+
+// S elements, each segment lasting until the next one starts so the timeline
+// has no gaps; runs of equal durations are folded into one with a repeat count
+fn write_timeline(out: &mut String, timeline: &[(i64, i64)]) {
+    let durations: Vec<i64> = timeline
+        .iter()
+        .enumerate()
+        .map(|(i, (start, duration))| timeline.get(i + 1).map_or(*duration, |(next, _)| next - start).max(0))
+        .collect();
+
+    let mut i = 0;
+    while i < durations.len() {
+        let mut repeat = 0;
+        while i + repeat + 1 < durations.len() && durations[i + repeat + 1] == durations[i] {
+            repeat += 1;
+        }
+        out.push_str("            <S");
+        if i == 0 {
+            let _ = write!(out, " t=\"{}\"", timeline[0].0);
+        }
+        let _ = write!(out, " d=\"{}\"", durations[i]);
+        if repeat > 0 {
+            let _ = write!(out, " r=\"{}\"", repeat);
+        }
+        out.push_str("/>\n");
+        i += repeat + 1;
+    }
+}
+
+// Average bits per second over `duration_ms`
+fn bandwidth(bytes: u64, duration_ms: i64) -> u64 {
+    (bytes * 8 * 1000 / duration_ms.max(1) as u64).max(1)
+}
+
+fn duration(ms: i64) -> String {
+    format!("PT{:.3}S", ms.max(0) as f64 / 1000.0)
+}
+
+fn date_time(time: DateTime<Utc>) -> String {
+    time.to_rfc3339_opts(SecondsFormat::Millis, true)
+}
@@ -0,0 +1,174 @@
+/*
+ * dedup.rs
+ * Purpose: Frame deduplication for the ingest path
+ *
+ * This file contains:
+ * - Frame hashing
+ * - Redis-backed duplicate detection using the connection manager
+ * - In-memory fallback when Redis isn't configured
+ * - Perceptual (dHash) comparison for near-identical JPEG frames
+ */
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+};
+use jpeg_decoder::{Decoder, PixelFormat};
+use redis::aio::ConnectionManager;
+use sha2::{Digest, Sha256};
+use tracing::info;
+use uuid::Uuid;
+use crate::error::AppError;
+
+// How long a frame hash stays eligible as a reference in Redis
+const REDIS_HASH_TTL_SECS: u64 = 6 * 60 * 60;
+
+// dHash compares each pixel with its right neighbour on a 9x8 grid, giving 64 bits
+const DHASH_WIDTH: usize = 9;
+const DHASH_HEIGHT: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DedupResult {
+    Unique,
+    // Same bytes were already stored at this timestamp
+    Duplicate { reference_timestamp: i64 },
+}
+
+#[derive(Clone)]
+pub enum Deduplicator {
+    Redis(ConnectionManager),
+    Memory(Arc<RwLock<HashMap<Uuid, HashMap<String, i64>>>>),
+}
+
+impl Deduplicator {
+    pub async fn connect(redis_url: &str) -> Result<Self, AppError> {
+        let client = redis::Client::open(redis_url)
+            .map_err(|e| AppError::InternalError(format!("Invalid Redis URL: {}", e)))?;
+        let manager = client.get_connection_manager()
+            .await
+            .map_err(|e| AppError::InternalError(format!("Failed to connect to Redis: {}", e)))?;
+
+        info!("Frame deduplication using Redis");
+        Ok(Deduplicator::Redis(manager))
+    }
+
+    pub fn memory() -> Self {
+        Deduplicator::Memory(Arc::new(RwLock::new(HashMap::new())))
+    }
+
+    pub fn hash_frame(data: &[u8]) -> String {
+        format!("{:x}", Sha256::digest(data))
+    }
+
+    // Record the frame's hash for this recording, reporting an earlier copy if one exists
+    pub async fn check(&self, recording_id: &Uuid, data: &[u8], timestamp: i64) -> Result<DedupResult, AppError> {
+        let hash = Self::hash_frame(data);
+
+        match self {
+            Deduplicator::Redis(manager) => {
+                let mut conn = manager.clone();
+                let key = format!("dedup:{}:{}", recording_id, hash);
+
+                // SET NX GET stores the timestamp only if absent and returns any previous value
+                let previous: Option<i64> = redis::cmd("SET")
+                    .arg(&key)
+                    .arg(timestamp)
+                    .arg("NX")
+                    .arg("GET")
+                    .arg("EX")
+                    .arg(REDIS_HASH_TTL_SECS)
+                    .query_async(&mut conn)
+                    .await
+                    .map_err(|e| AppError::InternalError(format!("Redis dedup check failed: {}", e)))?;
+
+                Ok(match previous {
+                    Some(reference_timestamp) => DedupResult::Duplicate { reference_timestamp },
+                    None => DedupResult::Unique,
+                })
+            }
+            Deduplicator::Memory(hashes) => {
+                let mut hashes = hashes.write().unwrap();
+                let recording = hashes.entry(*recording_id).or_default();
+                match recording.get(&hash) {
+                    Some(&reference_timestamp) => Ok(DedupResult::Duplicate { reference_timestamp }),
+                    None => {
+                        recording.insert(hash, timestamp);
+                        Ok(DedupResult::Unique)
+                    }
+                }
+            }
+        }
+    }
+
+    // Drop hashes for a finished recording; Redis keys expire on their own
+    pub fn clear_recording(&self, recording_id: &Uuid) {
+        if let Deduplicator::Memory(hashes) = self {
+            hashes.write().unwrap().remove(recording_id);
+        }
+    }
+}
+
+// Difference hash of a JPEG frame, or None if the data isn't a decodable JPEG
+pub fn perceptual_hash(data: &[u8]) -> Option<u64> {
+    if !data.starts_with(&[0xFF, 0xD8]) {
+        return None;
+    }
+
+    let mut decoder = Decoder::new(data);
+    // Let the decoder downscale in the DCT domain; we only need a thumbnail
+    decoder.scale(DHASH_WIDTH as u16, DHASH_HEIGHT as u16).ok()?;
+    let pixels = decoder.decode().ok()?;
+    let info = decoder.info()?;
+
+    let (width, height) = (info.width as usize, info.height as usize);
+    if width == 0 || height == 0 {
+        return None;
+    }
+
+    let luma: Vec<u8> = match info.pixel_format {
+        PixelFormat::L8 => pixels,
+        PixelFormat::L16 => pixels.chunks_exact(2).map(|p| p[0]).collect(),
+        PixelFormat::RGB24 => pixels
+            .chunks_exact(3)
+            .map(|p| ((p[0] as u32 * 299 + p[1] as u32 * 587 + p[2] as u32 * 114) / 1000) as u8)
+            .collect(),
+        PixelFormat::CMYK32 => pixels
+            .chunks_exact(4)
+            .map(|p| 255 - p[3])
+            .collect(),
+    };
+    if luma.len() < width * height {
+        return None;
+    }
+
+    // Box-average the thumbnail onto the 9x8 grid
+    let mut grid = [[0u32; DHASH_WIDTH]; DHASH_HEIGHT];
+    for (gy, row) in grid.iter_mut().enumerate() {
+        let y0 = gy * height / DHASH_HEIGHT;
+        let y1 = ((gy + 1) * height / DHASH_HEIGHT).max(y0 + 1).min(height);
+        for (gx, cell) in row.iter_mut().enumerate() {
+            let x0 = gx * width / DHASH_WIDTH;
+            let x1 = ((gx + 1) * width / DHASH_WIDTH).max(x0 + 1).min(width);
+            let mut sum = 0u32;
+            for y in y0..y1 {
+                for x in x0..x1 {
+                    sum += luma[y * width + x] as u32;
+                }
+            }
+            *cell = sum / ((y1 - y0) * (x1 - x0)) as u32;
+        }
+    }
+
+    let mut hash = 0u64;
+    for row in grid.iter() {
+        for x in 0..DHASH_WIDTH - 1 {
+            hash = (hash << 1) | (row[x] > row[x + 1]) as u64;
+        }
+    }
+    Some(hash)
+}
+
+// Fraction of matching bits between two perceptual hashes, 1.0 meaning identical
+pub fn similarity(a: u64, b: u64) -> f32 {
+    1.0 - (a ^ b).count_ones() as f32 / 64.0
+}
@@ -28,11 +28,14 @@ static STORAGE_ERRORS: AtomicU64 = AtomicU64::new(0);
 
 #[derive(Debug)]
 pub enum AppError {
+    BadRequest(String),
     Unauthorized(String),
+    Forbidden(String),
     NotFound(String),
     ResourceExhausted(String),
     TooManyConnections(String),
     StorageError(String),
+    DatabaseError(String),
     StreamingError(String),
     InternalError(String),
     JwtError(String),
@@ -47,11 +50,14 @@ struct ErrorResponse {
 impl fmt::Display for AppError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
+            AppError::BadRequest(msg) => write!(f, "Bad request: {}", msg),
             AppError::Unauthorized(msg) => write!(f, "Unauthorized: {}", msg),
+            AppError::Forbidden(msg) => write!(f, "Forbidden: {}", msg),
             AppError::NotFound(msg) => write!(f, "Not found: {}", msg),
             AppError::ResourceExhausted(msg) => write!(f, "Resource exhausted: {}", msg),
             AppError::TooManyConnections(msg) => write!(f, "Too many connections: {}", msg),
             AppError::StorageError(msg) => write!(f, "Storage error: {}", msg),
+            AppError::DatabaseError(msg) => write!(f, "Database error: {}", msg),
             AppError::StreamingError(msg) => write!(f, "Streaming error: {}", msg),
             AppError::InternalError(msg) => write!(f, "Internal error: {}", msg),
             AppError::JwtError(msg) => write!(f, "JWT error: {}", msg),
@@ -78,11 +84,14 @@ impl IntoResponse for AppError {
         }
 
         let (status, message) = match self {
+            AppError::BadRequest(msg) => (StatusCode::BAD_REQUEST, msg),
             AppError::Unauthorized(msg) => (StatusCode::UNAUTHORIZED, msg),
+            AppError::Forbidden(msg) => (StatusCode::FORBIDDEN, msg),
             AppError::NotFound(msg) => (StatusCode::NOT_FOUND, msg),
             AppError::ResourceExhausted(msg) => (StatusCode::TOO_MANY_REQUESTS, msg),
             AppError::TooManyConnections(msg) => (StatusCode::SERVICE_UNAVAILABLE, msg),
             AppError::StorageError(msg) => (StatusCode::INTERNAL_SERVER_ERROR, msg),
+            AppError::DatabaseError(msg) => (StatusCode::INTERNAL_SERVER_ERROR, msg),
             AppError::StreamingError(msg) => (StatusCode::INTERNAL_SERVER_ERROR, msg),
             AppError::InternalError(msg) => (StatusCode::INTERNAL_SERVER_ERROR, msg),
             AppError::JwtError(msg) => (StatusCode::UNAUTHORIZED, msg),
@@ -121,6 +130,18 @@ impl From<jsonwebtoken::errors::Error> for AppError {
     }
 }
 
+impl From<sqlx::Error> for AppError {
+    fn from(err: sqlx::Error) -> Self {
+        AppError::DatabaseError(err.to_string())
+    }
+}
+
+impl From<sqlx::migrate::MigrateError> for AppError {
+    fn from(err: sqlx::migrate::MigrateError) -> Self {
+        AppError::DatabaseError(err.to_string())
+    }
+}
+
 impl From<std::fmt::Error> for AppError {
     fn from(err: std::fmt::Error) -> Self {
         AppError::InternalError(err.to_string())
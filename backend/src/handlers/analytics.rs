@@ -18,7 +18,7 @@ use serde::{Deserialize, Serialize};
 use std::sync::Arc;
 use tracing::info;
 
-use crate::{AppState, error::AppError};
+use crate::{AppState, error::AppError, models::StreamMetrics, monitoring::ViewerLag};
 
 #[derive(Debug, Deserialize)]
 pub struct MetricsRequest {
@@ -32,6 +32,16 @@ pub struct MetricsRequest {
 pub struct RoomAnalytics {
     pub total_bytes: u64,
     pub total_frames: u64,
+    pub frames_deduplicated: u64,
+    pub deduplication_ratio: f32,
+    pub viewer_lag_events: u64,
+    pub viewer_packets_dropped: u64,
+    pub slow_consumer_disconnects: u64,
+    // Connected viewers that have fallen behind
+    pub lagging_viewers: Vec<ViewerLag>,
+    // Publisher hints and disconnects for going over the room config's limits
+    pub limit_violations: u64,
+    pub frames_dropped_over_limit: u64,
     pub error_rate: f64,
     pub avg_latency_ms: f64,
 }
@@ -66,11 +76,29 @@ pub async fn get_room_analytics(
     Ok(Json(RoomAnalytics {
         total_bytes: metrics.bytes_transferred,
         total_frames: metrics.frames_processed,
+        frames_deduplicated: metrics.frames_deduplicated,
+        deduplication_ratio: metrics.deduplication_ratio(),
+        viewer_lag_events: metrics.viewer_lag_events,
+        viewer_packets_dropped: metrics.viewer_packets_dropped,
+        slow_consumer_disconnects: metrics.slow_consumer_disconnects,
+        lagging_viewers: state.metrics.get_lagging_viewers(&room_id),
+        limit_violations: metrics.limit_violations,
+        frames_dropped_over_limit: metrics.frames_dropped_over_limit,
         error_rate: metrics.error_rate,
         avg_latency_ms: metrics.avg_latency,
     }))
 }
 
+pub async fn get_stream_metrics(
+    State(state): State<Arc<AppState>>,
+    Path(room_id): Path<String>,
+) -> Result<Json<StreamMetrics>, AppError> {
+    let _room = state.rooms.get_room(&room_id).await?;
+    let metrics = state.metrics.get_stream_metrics(&room_id).await?;
+
+    Ok(Json(metrics))
+}
+
 pub async fn get_user_analytics(
     State(state): State<Arc<AppState>>,
 ) -> Result<Json<UserAnalytics>, AppError> {
@@ -11,7 +11,7 @@
  */
 
 use axum::{
-    extract::{State},
+    extract::{Query, State},
     http::{Request, StatusCode, header},
     middleware::Next,
     response::{Response, IntoResponse},
@@ -20,20 +20,59 @@ use axum::{
 use serde::{Deserialize, Serialize};
 use std::sync::Arc;
 use uuid::Uuid;
-use crate::{AppState, error::AppError};
+use crate::{AppState, auth::Claims, error::AppError, models::User, rooms::Room};
 use tower_cookies::{Cookie, Cookies};
 
+// Storage quota granted to newly generated credentials (10 GB)
+const DEFAULT_QUOTA_BYTES: i64 = 10 * 1024 * 1024 * 1024;
+
 #[derive(Debug, Serialize)]
 pub struct CredentialsResponse {
     pub message: String,
 }
 
+// Live playlists are reloaded every few seconds, each time with a fresh token
+pub(crate) const LIVE_MEDIA_TOKEN_MINUTES: i64 = 10;
+
+// A token passed in the query string, for players that can't set headers
+#[derive(Debug, Default, Deserialize)]
+pub struct TokenParams {
+    pub token: Option<String>,
+}
+
+impl TokenParams {
+    // The token for the URIs a playlist or manifest lists. Only callers that
+    // authenticated with ?token= need one, and they get a short-lived viewer
+    // token for the room rather than their own.
+    pub fn media_token(
+        &self,
+        state: &AppState,
+        claims: &Claims,
+        room_id: &str,
+        valid_for: chrono::Duration,
+    ) -> Result<Option<String>, AppError> {
+        if self.token.is_none() {
+            return Ok(None);
+        }
+        state.auth.generate_media_token(&claims.user_id, room_id, valid_for).map(Some)
+    }
+}
+
 pub async fn generate_credentials(
     State(state): State<Arc<AppState>>,
     cookies: Cookies,
 ) -> Result<impl IntoResponse, AppError> {
     let api_key = Uuid::new_v4().to_string();
-    let token = state.auth.generate_token(&api_key)?;
+    let user = User {
+        id: Uuid::new_v4(),
+        api_key: api_key.clone(),
+        quota_limit: DEFAULT_QUOTA_BYTES,
+        quota_used: 0,
+        created_at: None,
+    };
+    state.repo.insert_user(&user).await?;
+
+    let token = state.auth.generate_token(&user.id, &api_key)?;
     
     // Create a secure HTTP-only cookie with the JWT token
     let cookie = Cookie::build("jwt_token", token)
@@ -53,27 +92,52 @@ pub async fn generate_credentials(
 pub async fn require_auth<B>(
     State(state): State<Arc<AppState>>,
     cookies: Cookies,
-    req: Request<B>,
+    mut req: Request<B>,
     next: Next<B>,
 ) -> Result<Response, StatusCode> {
     // First try to get token from cookie
     if let Some(cookie) = cookies.get("jwt_token") {
-        if state.auth.validate_token(cookie.value()).is_ok() {
+        if let Ok(claims) = state.auth.validate_token(cookie.value()) {
+            req.extensions_mut().insert(claims);
             return Ok(next.run(req).await);
         }
     }
     
-    // Fallback to Authorization header
-    let auth_header = req
+    // Fallback to Authorization header, then to a token in the query string
+    let bearer = req
         .headers()
         .get("Authorization")
         .and_then(|value| value.to_str().ok())
-        .and_then(|value| value.strip_prefix("Bearer "));
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(str::to_string);
+    let query = Query::<TokenParams>::try_from_uri(req.uri())
+        .ok()
+        .and_then(|Query(params)| params.token);
+    let claims = bearer
+        .into_iter()
+        .chain(query)
+        .find_map(|token| state.auth.validate_token(&token).ok());
 
-    match auth_header {
-        Some(token) if state.auth.validate_token(token).is_ok() => {
+    match claims {
+        Some(claims) => {
+            // Handlers check what the caller may access from its claims
+            req.extensions_mut().insert(claims);
             Ok(next.run(req).await)
         }
-        _ => Err(StatusCode::UNAUTHORIZED),
+        None => Err(StatusCode::UNAUTHORIZED),
+    }
+}
+
+// The room, if the caller may watch it and read its recordings: its creator,
+// or anyone holding a room token for it
+pub(crate) async fn room_access(state: &AppState, claims: &Claims, room_id: &str) -> Result<Room, AppError> {
+    let room = state.rooms.get_room(room_id).await?;
+    let allowed = match claims.room_id.as_deref() {
+        Some(token_room) => token_room == room.id,
+        None => room.creator_id == claims.user_id,
+    };
+    if !allowed {
+        return Err(AppError::Forbidden(format!("Room {} belongs to another user", room_id)));
     }
-} 
\ No newline at end of file
+    Ok(room)
+}
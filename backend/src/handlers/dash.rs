@@ -0,0 +1,90 @@
+/*
+ * handlers/dash.rs
+ * Purpose: MPEG-DASH endpoints for rooms
+ *
+ * This file contains:
+ * - The dynamic MPD of a room's live stream; its segments are the HLS ones
+ * - Static MPDs over the MP4 copy of finished recordings, and the copy's init
+ *   segment and fragments as separate files for SegmentTemplate
+ *
+ * As with HLS, a manifest requested with ?token= lists its URIs with a
+ * short-lived viewer token for the room rather than the caller's.
+ */
+
+use axum::{
+    body::Body,
+    extract::{Path, Query, State},
+    response::Response,
+    Extension,
+};
+use chrono::{Duration, Utc};
+use std::sync::Arc;
+use uuid::Uuid;
+
+use crate::{
+    AppState,
+    auth::Claims,
+    dash,
+    error::AppError,
+    handlers::{
+        auth::{room_access, TokenParams, LIVE_MEDIA_TOKEN_MINUTES},
+        hls::{find_fragment_index, respond, vod_token_lifetime},
+    },
+};
+
+const MANIFEST_CONTENT_TYPE: &str = "application/dash+xml";
+const SEGMENT_CONTENT_TYPE: &str = "video/mp4";
+
+pub async fn live_manifest(
+    State(state): State<Arc<AppState>>,
+    Path(room_id): Path<String>,
+    Query(params): Query<TokenParams>,
+    Extension(claims): Extension<Claims>,
+) -> Result<Response, AppError> {
+    let room = room_access(&state, &claims, &room_id).await?;
+    let token = params.media_token(&state, &claims, &room.id, Duration::minutes(LIVE_MEDIA_TOKEN_MINUTES))?;
+    let query = token.as_deref().map(|token| format!("?token={}", token)).unwrap_or_default();
+    let manifest = state.rooms
+        .active_stream(&room_id)
+        .and_then(|hub| dash::live_manifest(&hub.hls().lock().unwrap(), Utc::now(), &query))
+        .ok_or_else(|| AppError::NotFound(format!("Room {} has no DASH stream", room_id)))?;
+
+    let cache_control = if token.is_some() { "private, no-cache" } else { "no-cache" };
+    respond(MANIFEST_CONTENT_TYPE, cache_control, Body::from(manifest))
+}
+
+pub async fn recording_manifest(
+    State(state): State<Arc<AppState>>,
+    Path((room_id, recording_id)): Path<(String, Uuid)>,
+    Query(params): Query<TokenParams>,
+    Extension(claims): Extension<Claims>,
+) -> Result<Response, AppError> {
+    let (_, index) = find_fragment_index(&state, &claims, &room_id, recording_id).await?;
+    let token = params.media_token(&state, &claims, &room_id, vod_token_lifetime(&index))?;
+    let query = token.as_deref().map(|token| format!("?token={}", token)).unwrap_or_default();
+    let manifest = dash::vod_manifest(&index, "segments/", &query);
+    let cache_control = if token.is_some() { "private, max-age=60" } else { "max-age=60" };
+    respond(MANIFEST_CONTENT_TYPE, cache_control, Body::from(manifest))
+}
+
+// init.mp4 and <n>.m4s, as named in the recording's SegmentTemplate
+pub async fn recording_segment(
+    State(state): State<Arc<AppState>>,
+    Path((room_id, recording_id, file)): Path<(String, Uuid, String)>,
+    Extension(claims): Extension<Claims>,
+) -> Result<Response, AppError> {
+    let (mp4_path, index) = find_fragment_index(&state, &claims, &room_id, recording_id).await?;
+    let range = if file == "init.mp4" {
+        Some((0, index.init_size))
+    } else {
+        file.strip_suffix(".m4s")
+            .and_then(|n| n.parse::<usize>().ok())
+            .and_then(|n| index.fragments.get(n))
+            .map(|fragment| (fragment.offset, fragment.offset + fragment.size))
+    };
+    let (start, end) = range
+        .ok_or_else(|| AppError::NotFound(format!("Segment {} not found in recording {}", file, recording_id)))?;
+
+    let data = state.storage.read_recording_range(&mp4_path, start, Some(end)).await?;
+    respond(SEGMENT_CONTENT_TYPE, "max-age=3600", Body::from(data))
+}
@@ -0,0 +1,137 @@
+/*
+ * handlers/hls.rs
+ * Purpose: HLS endpoints for rooms
+ *
+ * This file contains:
+ * - The live media playlist of a room's current stream, and its init and
+ *   media segments
+ * - VOD playlists over the MP4 copy of finished recordings
+ * - Loading a recording's fragment index, shared with the DASH endpoints
+ *
+ * Live segments are held in memory by the room's stream hub. Recording
+ * playlists point into the MP4 copy with byte ranges, so segments are served
+ * by the regular download endpoint.
+ *
+ * Every endpoint needs access to the room. Native players can't set headers,
+ * so when a playlist is requested with ?token= every URI it lists gets a
+ * short-lived viewer token for the room, and the playlist isn't shared.
+ */
+
+use axum::{
+    body::{boxed, Body},
+    extract::{Path, Query, State},
+    http::{header, StatusCode},
+    response::Response,
+    Extension,
+};
+use chrono::Duration;
+use std::sync::Arc;
+use uuid::Uuid;
+
+use crate::{
+    AppState,
+    auth::Claims,
+    error::AppError,
+    handlers::{
+        auth::{room_access, TokenParams, LIVE_MEDIA_TOKEN_MINUTES},
+        recording::find_recording,
+    },
+    hls,
+    models::FragmentIndex,
+};
+
+const PLAYLIST_CONTENT_TYPE: &str = "application/vnd.apple.mpegurl";
+const SEGMENT_CONTENT_TYPE: &str = "video/mp4";
+
+pub async fn live_playlist(
+    State(state): State<Arc<AppState>>,
+    Path(room_id): Path<String>,
+    Query(params): Query<TokenParams>,
+    Extension(claims): Extension<Claims>,
+) -> Result<Response, AppError> {
+    let room = room_access(&state, &claims, &room_id).await?;
+    let token = params.media_token(&state, &claims, &room.id, Duration::minutes(LIVE_MEDIA_TOKEN_MINUTES))?;
+    let query = token.as_deref().map(|token| format!("?token={}", token)).unwrap_or_default();
+    let playlist = state.rooms
+        .active_stream(&room_id)
+        .and_then(|hub| hub.hls().lock().unwrap().playlist(&query))
+        .ok_or_else(|| AppError::NotFound(format!("Room {} has no HLS stream", room_id)))?;
+
+    // Players reload live playlists and must never get a stale one
+    let cache_control = if token.is_some() { "private, no-cache" } else { "no-cache" };
+    respond(PLAYLIST_CONTENT_TYPE, cache_control, Body::from(playlist))
+}
+
+// init_<n>.mp4 and <sequence>.m4s, as listed in the live playlist
+pub async fn live_segment(
+    State(state): State<Arc<AppState>>,
+    Path((room_id, file)): Path<(String, String)>,
+    Extension(claims): Extension<Claims>,
+) -> Result<Response, AppError> {
+    room_access(&state, &claims, &room_id).await?;
+    let not_found = || AppError::NotFound(format!("Segment {} not found in room {}", file, room_id));
+    let hub = state.rooms.active_stream(&room_id).ok_or_else(not_found)?;
+    let data = {
+        let hls = hub.hls().lock().unwrap();
+        if let Some(id) = file.strip_prefix("init_").and_then(|f| f.strip_suffix(".mp4")) {
+            id.parse().ok().and_then(|id| hls.init_segment(id))
+        } else {
+            file.strip_suffix(".m4s").and_then(|seq| seq.parse().ok()).and_then(|seq| hls.segment(seq))
+        }
+    };
+    let data = data.ok_or_else(not_found)?;
+
+    // A segment's contents never change once it's listed
+    respond(SEGMENT_CONTENT_TYPE, "max-age=60", Body::from(data))
+}
+
+pub async fn recording_playlist(
+    State(state): State<Arc<AppState>>,
+    Path((room_id, recording_id)): Path<(String, Uuid)>,
+    Query(params): Query<TokenParams>,
+    Extension(claims): Extension<Claims>,
+) -> Result<Response, AppError> {
+    let (_, index) = find_fragment_index(&state, &claims, &room_id, recording_id).await?;
+    let token = params.media_token(&state, &claims, &room_id, vod_token_lifetime(&index))?;
+
+    // Relative to .../hls/recordings/<id>/index.m3u8, i.e. the download endpoint
+    let mut uri = format!("../../../recordings/{}?format=mp4", recording_id);
+    if let Some(token) = &token {
+        uri.push_str(&format!("&token={}", token));
+    }
+    let cache_control = if token.is_some() { "private, max-age=60" } else { "max-age=60" };
+    respond(PLAYLIST_CONTENT_TYPE, cache_control, Body::from(hls::vod_playlist(&index, &uri)))
+}
+
+// VOD playlists aren't reloaded, so their token lasts the recording's length
+// and then some
+pub(crate) fn vod_token_lifetime(index: &FragmentIndex) -> Duration {
+    let length_ms: i64 = index.fragments.iter().map(|fragment| fragment.duration_ms.max(0)).sum();
+    Duration::milliseconds(length_ms) + Duration::minutes(LIVE_MEDIA_TOKEN_MINUTES * 6)
+}
+
+// A recording's MP4 copy and the index of its fragments
+pub(crate) async fn find_fragment_index(
+    state: &AppState,
+    claims: &Claims,
+    room_id: &str,
+    recording_id: Uuid,
+) -> Result<(String, FragmentIndex), AppError> {
+    let recording = find_recording(state, claims, room_id, recording_id).await?;
+    let mp4_path = recording.mp4_path
+        .ok_or_else(|| AppError::NotFound(format!("Recording {} has no MP4 copy", recording_id)))?;
+
+    let json = state.storage.get_recording(&format!("{}.fragments.json", mp4_path)).await?;
+    let index = serde_json::from_slice(&json)
+        .map_err(|e| AppError::StorageError(format!("Invalid fragment index for {}: {}", mp4_path, e)))?;
+    Ok((mp4_path, index))
+}
+
+pub(crate) fn respond(content_type: &str, cache_control: &str, body: Body) -> Result<Response, AppError> {
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, content_type)
+        .header(header::CACHE_CONTROL, cache_control)
+        .body(boxed(body))
+        .map_err(|e| AppError::InternalError(e.to_string()))
+}
@@ -1,9 +1,15 @@
 pub mod auth;
 pub mod room;
+pub mod recording;
 pub mod stream;
 pub mod analytics;
+pub mod hls;
+pub mod dash;
 
 pub use auth::*;
 pub use room::*;
+pub use recording::*;
 pub use stream::*;
-pub use analytics::*; 
\ No newline at end of file
+pub use analytics::*;
+pub use hls::*;
+pub use dash::*; 
\ No newline at end of file
@@ -0,0 +1,273 @@
+/*
+ * handlers/recording.rs
+ * Purpose: Recording download endpoint
+ *
+ * This file contains:
+ * - Streaming download of a recording with single-range Range/If-Range support
+ * - Download of a single track for recordings stored one file per track
+ * - Download of the fragmented MP4 copy of H.264/Opus recordings
+ * - Content-Type detection for WebM and MP4
+ * - ETag and Last-Modified validators
+ */
+
+use axum::{
+    body::{boxed, Body, StreamBody},
+    extract::{Path, Query, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::Response,
+    Extension,
+};
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use std::sync::Arc;
+use uuid::Uuid;
+
+use crate::{
+    AppState,
+    auth::Claims,
+    error::AppError,
+    handlers::auth::room_access,
+    models::{FrameType, Recording},
+    storage::ObjectInfo,
+};
+
+const EBML_MAGIC: [u8; 4] = [0x1A, 0x45, 0xDF, 0xA3];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ByteRange {
+    // Inclusive start and end offsets
+    Partial(u64, u64),
+    Unsatisfiable,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DownloadFormat {
+    // The recording as stored
+    Original,
+    // The fragmented MP4 copy
+    Mp4,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DownloadParams {
+    // Download the file holding this track instead of the main file
+    pub track: Option<FrameType>,
+    pub format: Option<DownloadFormat>,
+}
+
+pub async fn download_recording(
+    State(state): State<Arc<AppState>>,
+    Path((room_id, recording_id)): Path<(String, Uuid)>,
+    Query(params): Query<DownloadParams>,
+    Extension(claims): Extension<Claims>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    let recording = find_recording(&state, &claims, &room_id, recording_id).await?;
+    let key = match (params.track, params.format) {
+        // The MP4 copy holds every track
+        (Some(_), Some(DownloadFormat::Mp4)) => {
+            return Err(AppError::BadRequest("track and format=mp4 cannot be combined".to_string()));
+        }
+        (None, Some(DownloadFormat::Mp4)) => recording.mp4_path
+            .as_deref()
+            .ok_or_else(|| AppError::NotFound(format!("Recording {} has no MP4 copy", recording_id)))?,
+        (Some(track), _) => recording.tracks
+            .iter()
+            .find(|info| info.track == track)
+            .map(|info| info.storage_path.as_str())
+            .ok_or_else(|| AppError::NotFound(format!("Recording {} has no {} track", recording_id, track.as_str())))?,
+        (None, _) => recording.storage_path.as_str(),
+    };
+
+    let info = state.storage.stat_recording(key).await?;
+    let etag = etag_for(&info);
+    let last_modified = http_date(info.last_modified);
+
+    // Older recordings were all named .mp4, so their first bytes tell WebM and MP4 apart
+    let head = state.storage.read_recording_range(key, 0, Some(12)).await?;
+    let content_type = content_type_for(key, &head);
+
+    // A stale If-Range validator means the client gets the whole, current file
+    let range_applies = match headers.get(header::IF_RANGE).and_then(|v| v.to_str().ok()) {
+        Some(validator) => if_range_matches(validator, &etag, info.last_modified),
+        None => true,
+    };
+    let range = headers
+        .get(header::RANGE)
+        .and_then(|v| v.to_str().ok())
+        .filter(|_| range_applies)
+        .and_then(|value| parse_range(value, info.size));
+
+    let builder = Response::builder()
+        .header(header::ACCEPT_RANGES, "bytes")
+        .header(header::ETAG, &etag)
+        .header(header::LAST_MODIFIED, last_modified)
+        .header(header::CONTENT_TYPE, content_type);
+
+    let response = match range {
+        Some(ByteRange::Unsatisfiable) => builder
+            .status(StatusCode::RANGE_NOT_SATISFIABLE)
+            .header(header::CONTENT_RANGE, format!("bytes */{}", info.size))
+            .body(boxed(Body::empty())),
+        Some(ByteRange::Partial(start, end)) => {
+            let stream = state.storage.stream_recording(key, start, Some(end + 1)).await?;
+            builder
+                .status(StatusCode::PARTIAL_CONTENT)
+                .header(header::CONTENT_RANGE, format!("bytes {}-{}/{}", start, end, info.size))
+                .header(header::CONTENT_LENGTH, end - start + 1)
+                .body(boxed(StreamBody::new(stream)))
+        }
+        None => {
+            let stream = state.storage.stream_recording(key, 0, Some(info.size)).await?;
+            builder
+                .status(StatusCode::OK)
+                .header(header::CONTENT_LENGTH, info.size)
+                .body(boxed(StreamBody::new(stream)))
+        }
+    };
+
+    response.map_err(|e| AppError::InternalError(e.to_string()))
+}
+
+// A recording of a room the caller has access to
+pub(crate) async fn find_recording(
+    state: &AppState,
+    claims: &Claims,
+    room_id: &str,
+    recording_id: Uuid,
+) -> Result<Recording, AppError> {
+    let room = room_access(state, claims, room_id).await?;
+    state.repo
+        .get_recording(recording_id)
+        .await?
+        .filter(|recording| recording.room_id == room.record_id)
+        .ok_or_else(|| AppError::NotFound(format!("Recording {} not found in room {}", recording_id, room_id)))
+}
+
+fn content_type_for(key: &str, head: &[u8]) -> &'static str {
+    match key.rsplit_once('.').map(|(_, ext)| ext.to_ascii_lowercase()).as_deref() {
+        Some("webm") => return "video/webm",
+        Some("mkv") => return "video/x-matroska",
+        Some("mka") => return "audio/x-matroska",
+        Some("mjpeg") => return "video/x-motion-jpeg",
+        Some("m4s") => return "video/mp4",
+        _ => {}
+    }
+    if head.starts_with(&EBML_MAGIC) {
+        return "video/webm";
+    }
+    if head.len() >= 8 && &head[4..8] == b"ftyp" {
+        return "video/mp4";
+    }
+    "application/octet-stream"
+}
+
+// Backends report ETags with or without quotes; the header needs them quoted
+fn etag_for(info: &ObjectInfo) -> String {
+    match &info.etag {
+        Some(etag) if etag.starts_with('"') || etag.starts_with("W/") => etag.clone(),
+        Some(etag) => format!("\"{}\"", etag),
+        None => format!("\"{:x}-{:x}\"", info.size, info.last_modified.timestamp()),
+    }
+}
+
+fn http_date(time: DateTime<Utc>) -> HeaderValue {
+    HeaderValue::from_str(&time.format("%a, %d %b %Y %H:%M:%S GMT").to_string())
+        .unwrap_or_else(|_| HeaderValue::from_static(""))
+}
+
+// If-Range carries either a strong ETag or an HTTP date
+fn if_range_matches(validator: &str, etag: &str, last_modified: DateTime<Utc>) -> bool {
+    let validator = validator.trim();
+    if validator.starts_with('"') {
+        return !etag.starts_with("W/") && validator == etag;
+    }
+    if validator.starts_with("W/") {
+        return false;
+    }
+    DateTime::parse_from_rfc2822(&validator.replace("GMT", "+0000"))
+        .is_ok_and(|date| date.timestamp() == last_modified.timestamp())
+}
+
+// Only single byte ranges are honoured; anything else is served as the full file
+fn parse_range(value: &str, size: u64) -> Option<ByteRange> {
+    let spec = value.trim().strip_prefix("bytes=")?;
+    if spec.contains(',') {
+        return None;
+    }
+    let (start, end) = spec.trim().split_once('-')?;
+
+    if start.is_empty() {
+        let suffix: u64 = end.parse().ok()?;
+        if suffix == 0 || size == 0 {
+            return Some(ByteRange::Unsatisfiable);
+        }
+        return Some(ByteRange::Partial(size.saturating_sub(suffix), size - 1));
+    }
+
+    let start: u64 = start.parse().ok()?;
+    let end: Option<u64> = if end.is_empty() { None } else { Some(end.parse().ok()?) };
+    if matches!(end, Some(end) if end < start) {
+        return None;
+    }
+    if start >= size {
+        return Some(ByteRange::Unsatisfiable);
+    }
+
+    Some(ByteRange::Partial(start, end.map_or(size - 1, |end| end.min(size - 1))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn parses_single_byte_ranges() {
+        assert_eq!(parse_range("bytes=0-", 100), Some(ByteRange::Partial(0, 99)));
+        assert_eq!(parse_range("bytes=10-19", 100), Some(ByteRange::Partial(10, 19)));
+        assert_eq!(parse_range("bytes=90-500", 100), Some(ByteRange::Partial(90, 99)));
+        assert_eq!(parse_range(" bytes=5-5 ", 100), Some(ByteRange::Partial(5, 5)));
+    }
+
+    #[test]
+    fn parses_suffix_ranges() {
+        assert_eq!(parse_range("bytes=-10", 100), Some(ByteRange::Partial(90, 99)));
+        assert_eq!(parse_range("bytes=-500", 100), Some(ByteRange::Partial(0, 99)));
+        assert_eq!(parse_range("bytes=-0", 100), Some(ByteRange::Unsatisfiable));
+        assert_eq!(parse_range("bytes=-10", 0), Some(ByteRange::Unsatisfiable));
+    }
+
+    #[test]
+    fn ranges_past_the_end_are_unsatisfiable() {
+        assert_eq!(parse_range("bytes=100-", 100), Some(ByteRange::Unsatisfiable));
+        assert_eq!(parse_range("bytes=150-200", 100), Some(ByteRange::Unsatisfiable));
+        assert_eq!(parse_range("bytes=0-", 0), Some(ByteRange::Unsatisfiable));
+    }
+
+    #[test]
+    fn ignores_invalid_and_multi_ranges() {
+        assert_eq!(parse_range("bytes=20-10", 100), None);
+        assert_eq!(parse_range("bytes=0-10,20-30", 100), None);
+        for value in ["bytes=", "bytes=-", "bytes=a-b", "bytes=1", "items=0-10", "0-10"] {
+            assert_eq!(parse_range(value, 100), None, "{}", value);
+        }
+    }
+
+    #[test]
+    fn if_range_needs_a_strong_etag_or_exact_date() {
+        let last_modified = Utc.with_ymd_and_hms(2024, 3, 7, 12, 30, 45).unwrap();
+        let etag = "\"64-65e9b4a5\"";
+
+        assert!(if_range_matches(etag, etag, last_modified));
+        assert!(!if_range_matches("\"other\"", etag, last_modified));
+        assert!(!if_range_matches("W/\"64-65e9b4a5\"", etag, last_modified));
+        assert!(!if_range_matches("W/\"64-65e9b4a5\"", "W/\"64-65e9b4a5\"", last_modified));
+        assert!(!if_range_matches(etag, "W/\"64-65e9b4a5\"", last_modified));
+
+        assert!(if_range_matches("Thu, 07 Mar 2024 12:30:45 GMT", etag, last_modified));
+        assert!(!if_range_matches("Thu, 07 Mar 2024 12:30:44 GMT", etag, last_modified));
+        assert!(!if_range_matches("not a date", etag, last_modified));
+    }
+}
@@ -1,9 +1,11 @@
 /*
  * handlers/room.rs
  * Purpose: Room management and recording endpoints
- * 
+ *
  * This file contains:
  * - Room creation and configuration
+ * - Room config validation and device join
+ * - Room lifecycle (get/update/close/archive/delete)
  * - Recording management (start/stop/list)
  * - Room state tracking
  * - Room capacity management
@@ -11,29 +13,35 @@
  */
 
 use crate::{
+    auth::Claims,
     error::AppError,
-    models::{CreateRoomRequest, RoomResponse},
+    handlers::auth::room_access,
+    models::{
+        CreateRoomRequest, CreateRoomTokenRequest, JoinRoomRequest, JoinRoomResponse, Recording, RoomConfig,
+        RoomResponse, RoomState, RoomTokenResponse, SlowConsumerPolicy, UpdateRoomRequest,
+    },
+    rooms::{Room, MAX_PUBLISHERS},
     AppState,
 };
 
 use axum::{
     extract::{Path, State},
-    Json,
+    http::StatusCode,
+    Extension, Json,
 };
 use tower_cookies::Cookies;
 use serde::{Deserialize, Serialize};
 use std::sync::Arc;
-use tracing::info;
+use tokio::task::JoinHandle;
+use tracing::{error, info};
 use uuid::Uuid;
-use chrono::{DateTime, Utc};
+
+const VIDEO_CODECS: &[&str] = &["h264", "vp8", "vp9", "av1", "jpeg"];
+const AUDIO_CODECS: &[&str] = &["opus", "vorbis", "aac", "pcm"];
 
 #[derive(Debug, Serialize)]
-pub struct Recording {
-    pub id: String,
-    pub room_id: String,
-    pub start_time: DateTime<Utc>,
-    pub end_time: Option<DateTime<Utc>>,
-    pub size_bytes: u64,
+pub struct ListRecordingsResponse {
+    pub recordings: Vec<Recording>,
 }
 
 pub async fn create_room(
@@ -41,30 +49,22 @@ pub async fn create_room(
     cookies: Cookies,
     Json(req): Json<CreateRoomRequest>,
 ) -> Result<Json<RoomResponse>, AppError> {
-    let token = cookies.get("jwt_token")
-        .ok_or_else(|| AppError::Unauthorized("No authentication token found".to_string()))?
-        .value()
-        .to_string();
-    
-    let claims = state.auth.validate_token(&token)?;
-    let user_id = claims.user_id.clone();
+    let user_id = caller_id(&state, &cookies)?;
+
+    let max_participants = req.max_participants.unwrap_or(10);  // Default to 10 if not specified
+    validate_settings(&state, Some(max_participants), req.slow_consumer)?;
+    let config = req.config.map(normalize_config).transpose()?;
 
     let room = state.rooms.create_room(
         Uuid::new_v4().to_string(),
         req.name,
-        req.max_participants.unwrap_or(10),  // Default to 10 if not specified
+        max_participants,
         user_id,
+        req.slow_consumer.unwrap_or_default(),
+        config,
     ).await?;
-    
-    Ok(Json(RoomResponse {
-        id: room.id,
-        name: room.name,
-        max_participants: room.max_participants,
-        recording_enabled: room.recording_enabled,
-        current_participants: room.current_participants,
-        start_time: Utc::now(),
-        end_time: None,
-    }))
+
+    Ok(Json(room_response(room)))
 }
 
 pub async fn list_rooms(
@@ -72,46 +72,324 @@ pub async fn list_rooms(
     cookies: Cookies,
 ) -> Result<Json<Vec<RoomResponse>>, AppError> {
     // Get user_id from JWT token in cookie
-    let token = cookies.get("jwt_token")
-        .ok_or_else(|| AppError::Unauthorized("No authentication token found".to_string()))?
-        .value()
-        .to_string();
-    
-    let claims = state.auth.validate_token(&token)?;
-    let user_id = claims.user_id.clone();
-    
+    let user_id = caller_id(&state, &cookies)?;
+
     let rooms = state.rooms.list_rooms(&user_id).await?;
-    
+
     let room_responses = rooms.into_iter()
-        .map(|room| RoomResponse {
-            id: room.id,
-            name: room.name,
-            max_participants: room.max_participants,
-            recording_enabled: room.recording_enabled,
-            current_participants: room.current_participants,
-            start_time: Utc::now(), // This should ideally come from room creation time
-            end_time: None,
-        })
+        .map(room_response)
         .collect();
 
     Ok(Json(room_responses))
 }
 
+pub async fn get_room(
+    State(state): State<Arc<AppState>>,
+    cookies: Cookies,
+    Path(room_id): Path<String>,
+) -> Result<Json<RoomResponse>, AppError> {
+    let room = owned_room(&state, &cookies, &room_id).await?;
+    Ok(Json(room_response(room)))
+}
+
+pub async fn update_room(
+    State(state): State<Arc<AppState>>,
+    cookies: Cookies,
+    Path(room_id): Path<String>,
+    Json(req): Json<UpdateRoomRequest>,
+) -> Result<Json<RoomResponse>, AppError> {
+    owned_room(&state, &cookies, &room_id).await?;
+
+    if matches!(&req.name, Some(name) if name.trim().is_empty()) {
+        return Err(AppError::BadRequest("Room name cannot be empty".to_string()));
+    }
+    validate_settings(&state, req.max_participants, req.slow_consumer)?;
+
+    let room = state.rooms.update_room(&room_id, req).await?;
+    Ok(Json(room_response(room)))
+}
+
+pub async fn get_room_config(
+    State(state): State<Arc<AppState>>,
+    cookies: Cookies,
+    Path(room_id): Path<String>,
+) -> Result<Json<RoomConfig>, AppError> {
+    owned_room(&state, &cookies, &room_id).await?;
+    Ok(Json(state.rooms.get_config(&room_id).await?))
+}
+
+// Replaces the whole config; omitted fields fall back to their defaults
+pub async fn update_room_config(
+    State(state): State<Arc<AppState>>,
+    cookies: Cookies,
+    Path(room_id): Path<String>,
+    Json(config): Json<RoomConfig>,
+) -> Result<Json<RoomConfig>, AppError> {
+    owned_room(&state, &cookies, &room_id).await?;
+
+    let config = normalize_config(config)?;
+    Ok(Json(state.rooms.set_config(&room_id, config).await?))
+}
+
+// Exchange an API key for an access token along with the room's config, so
+// devices can configure their encoder before connecting. The room's creator
+// gets a regular token; anyone else needs an invite and gets a room token.
+pub async fn join_room(
+    State(state): State<Arc<AppState>>,
+    Json(req): Json<JoinRoomRequest>,
+) -> Result<Json<JoinRoomResponse>, AppError> {
+    let user = state.repo
+        .get_user_by_api_key(&req.api_key)
+        .await?
+        .ok_or_else(|| AppError::Unauthorized("Invalid API key".to_string()))?;
+
+    let room = state.rooms.get_room(&req.room_id).await?;
+    if room.state == RoomState::Archived {
+        return Err(AppError::Forbidden(format!("Room {} is archived", room.id)));
+    }
+
+    let owner = room.creator_id == user.id.to_string();
+    // Invited callers get the role their invite was issued for
+    let invite = match req.invite.as_deref() {
+        Some(invite) => Some(state.auth.validate_token(invite)?),
+        None => None,
+    };
+    let invited_role = invite
+        .filter(|claims| claims.room_id.as_deref() == Some(room.id.as_str()))
+        .map(|claims| claims.role.unwrap_or_default());
+
+    let access_token = match (owner, invited_role) {
+        (true, _) => state.auth.generate_token(&user.id, &user.api_key)?,
+        (false, Some(role)) => state.auth.generate_room_token(&user.id, &room.id, role)?,
+        (false, None) => return Err(AppError::Forbidden(format!("Room {} belongs to another user", room.id))),
+    };
+    let config = state.rooms.get_config(&room.id).await?;
+
+    Ok(Json(JoinRoomResponse {
+        access_token,
+        config: Some(config),
+    }))
+}
+
+// A room token for devices to join with as publishers, or for players to
+// watch the room and fetch its media as viewers
+pub async fn create_room_token(
+    State(state): State<Arc<AppState>>,
+    cookies: Cookies,
+    Path(room_id): Path<String>,
+    Json(req): Json<CreateRoomTokenRequest>,
+) -> Result<Json<RoomTokenResponse>, AppError> {
+    let room = owned_room(&state, &cookies, &room_id).await?;
+    let user_id = Uuid::parse_str(&room.creator_id)
+        .map_err(|_| AppError::InternalError(format!("Invalid creator id for room {}", room_id)))?;
+
+    let token = state.auth.generate_room_token(&user_id, &room.id, req.role)?;
+    Ok(Json(RoomTokenResponse { token }))
+}
+
+// Disconnect everyone and finalize the active recording; the room reopens on the next connection
+pub async fn close_room(
+    State(state): State<Arc<AppState>>,
+    cookies: Cookies,
+    Path(room_id): Path<String>,
+) -> Result<Json<RoomResponse>, AppError> {
+    let room = owned_room(&state, &cookies, &room_id).await?;
+    if room.state == RoomState::Archived {
+        return Ok(Json(room_response(room)));
+    }
+
+    let (room, _) = end_session(&state, &room_id, RoomState::Closed).await?;
+    Ok(Json(room_response(room)))
+}
+
+// Close the room for good: recordings stay available, new connections are refused
+pub async fn archive_room(
+    State(state): State<Arc<AppState>>,
+    cookies: Cookies,
+    Path(room_id): Path<String>,
+) -> Result<Json<RoomResponse>, AppError> {
+    owned_room(&state, &cookies, &room_id).await?;
+
+    let (room, _) = end_session(&state, &room_id, RoomState::Archived).await?;
+    Ok(Json(room_response(room)))
+}
+
+// Remove the room along with its recordings
+pub async fn delete_room(
+    State(state): State<Arc<AppState>>,
+    cookies: Cookies,
+    Path(room_id): Path<String>,
+) -> Result<StatusCode, AppError> {
+    owned_room(&state, &cookies, &room_id).await?;
+
+    let (_, remux) = end_session(&state, &room_id, RoomState::Closed).await?;
+    // The MP4 copy writes into the room's directory, so let it finish before removing it
+    if let Some(remux) = remux {
+        if let Err(e) = remux.await {
+            error!("MP4 remux for room {} failed before deletion: {}", room_id, e);
+        }
+    }
+    let deleted = state.storage.delete_room(&room_id).await?;
+    state.rooms.delete_room(&room_id).await?;
+
+    info!("Deleted room {} and {} stored objects", room_id, deleted);
+    Ok(StatusCode::NO_CONTENT)
+}
+
 pub async fn list_recordings(
     State(state): State<Arc<AppState>>,
+    Extension(claims): Extension<Claims>,
     Path(room_id): Path<String>,
-) -> Result<Json<Vec<Recording>>, AppError> {
-    let filenames = state.storage.list_recordings(&room_id).await?;
-    
-    let recordings = filenames.into_iter()
-        .map(|filename| Recording {
-            id: filename.clone(),
-            room_id: room_id.clone(),
-            start_time: Utc::now(), // This should be parsed from filename
-            end_time: None,
-            size_bytes: 0, // This should be fetched from file metadata
-        })
-        .collect();
+) -> Result<Json<ListRecordingsResponse>, AppError> {
+    let room = room_access(&state, &claims, &room_id).await?;
+    let recordings = state.repo.list_recordings(room.record_id).await?;
 
-    Ok(Json(recordings))
-} 
\ No newline at end of file
+    Ok(Json(ListRecordingsResponse { recordings }))
+}
+
+// The new state is set first so an archived room refuses reconnects, then
+// participants are disconnected and the active recording is finalized
+// Also returns the recording's MP4 remux, which closing and archiving leave running
+async fn end_session(state: &AppState, room_id: &str, target: RoomState) -> Result<(Room, Option<JoinHandle<()>>), AppError> {
+    let room = state.rooms.set_state(room_id, target).await?;
+    state.rooms.close_stream(room_id);
+
+    let mut remux = None;
+    if let Some(stopped) = state.recordings.finish(room_id).await? {
+        info!("Finalized recording {} while closing room {}", stopped.id, room_id);
+        remux = stopped.remux;
+    }
+    Ok((room, remux))
+}
+
+// User id from the JWT token in the cookie
+fn caller_id(state: &AppState, cookies: &Cookies) -> Result<String, AppError> {
+    let token = cookies.get("jwt_token")
+        .ok_or_else(|| AppError::Unauthorized("No authentication token found".to_string()))?
+        .value()
+        .to_string();
+
+    let claims = state.auth.validate_token(&token)?;
+    // Room tokens give access to a room's media, not to managing rooms
+    if claims.room_id.is_some() {
+        return Err(AppError::Forbidden("Room tokens cannot manage rooms".to_string()));
+    }
+    Ok(claims.user_id)
+}
+
+async fn owned_room(state: &AppState, cookies: &Cookies, room_id: &str) -> Result<Room, AppError> {
+    let user_id = caller_id(state, cookies)?;
+    let room = state.rooms.get_room(room_id).await?;
+    if room.creator_id != user_id {
+        return Err(AppError::Forbidden(format!("Room {} belongs to another user", room_id)));
+    }
+    Ok(room)
+}
+
+fn validate_settings(
+    state: &AppState,
+    max_participants: Option<u32>,
+    slow_consumer: Option<SlowConsumerPolicy>,
+) -> Result<(), AppError> {
+    // Viewers plus the publisher must fit within the per-room connection limit
+    let max_room_size = state.connection_tracker.max_room_connections();
+    if matches!(max_participants, Some(max) if max.saturating_add(MAX_PUBLISHERS) > max_room_size) {
+        return Err(AppError::BadRequest(format!(
+            "max_participants cannot exceed {}",
+            max_room_size.saturating_sub(MAX_PUBLISHERS)
+        )));
+    }
+
+    if let Some(SlowConsumerPolicy::Buffer { max_bytes: 0 }) = slow_consumer {
+        return Err(AppError::BadRequest("slow_consumer buffer max_bytes must be greater than 0".to_string()));
+    }
+    Ok(())
+}
+
+// Check a config's values against each other and lowercase its codec names
+fn normalize_config(mut config: RoomConfig) -> Result<RoomConfig, AppError> {
+    fn invalid(message: &str) -> Result<RoomConfig, AppError> {
+        Err(AppError::BadRequest(format!("Invalid room config: {}", message)))
+    }
+    fn not_positive(value: Option<i32>) -> bool {
+        value.is_some_and(|v| v <= 0)
+    }
+    fn negative(value: Option<i32>) -> bool {
+        value.is_some_and(|v| v < 0)
+    }
+    fn not_percentage(value: Option<i32>) -> bool {
+        value.is_some_and(|v| !(0..=100).contains(&v))
+    }
+
+    config.video_codec = config.video_codec.map(|codec| codec.trim().to_lowercase());
+    config.audio_codec = config.audio_codec.map(|codec| codec.trim().to_lowercase());
+
+    if matches!(&config.video_codec, Some(codec) if !VIDEO_CODECS.contains(&codec.as_str())) {
+        return invalid(&format!("video_codec must be one of {}", VIDEO_CODECS.join(", ")));
+    }
+    if matches!(&config.audio_codec, Some(codec) if !AUDIO_CODECS.contains(&codec.as_str())) {
+        return invalid(&format!("audio_codec must be one of {}", AUDIO_CODECS.join(", ")));
+    }
+    if config.audio_channels.is_some_and(|channels| !(1..=2).contains(&channels)) {
+        return invalid("audio_channels must be 1 or 2");
+    }
+    if not_positive(config.max_bitrate) || not_positive(config.min_bitrate) {
+        return invalid("bitrates must be greater than 0");
+    }
+    if let (Some(min), Some(max)) = (config.min_bitrate, config.max_bitrate) {
+        if min > max {
+            return invalid("min_bitrate cannot exceed max_bitrate");
+        }
+    }
+    if config.frame_rate.is_some_and(|fps| !(1..=240).contains(&fps)) {
+        return invalid("frame_rate must be between 1 and 240");
+    }
+    if config.resolution.is_some() {
+        match config.resolution_dimensions() {
+            Some((width, height)) if width > 0 && height > 0 => {}
+            _ => return invalid("resolution must look like 1280x720"),
+        }
+    }
+    if config.deduplication_threshold.is_some_and(|t| !(0.0..=1.0).contains(&t)) {
+        return invalid("deduplication_threshold must be between 0 and 1");
+    }
+    if not_positive(config.keyframe_interval) {
+        return invalid("keyframe_interval must be greater than 0");
+    }
+    if not_percentage(config.quality_degradation_limit) || not_percentage(config.error_correction_level) {
+        return invalid("quality_degradation_limit and error_correction_level must be between 0 and 100");
+    }
+    if config.adaptive_bitrate && config.max_bitrate.is_none() {
+        return invalid("adaptive_bitrate requires max_bitrate");
+    }
+    if not_positive(config.batch_size) || negative(config.batch_timeout_ms) {
+        return invalid("batch_size must be greater than 0 and batch_timeout_ms cannot be negative");
+    }
+    if config.enable_frame_batching && config.batch_size.is_none() {
+        return invalid("enable_frame_batching requires batch_size");
+    }
+    if not_positive(config.max_buffer_size) || negative(config.buffer_duration_ms) {
+        return invalid("max_buffer_size must be greater than 0 and buffer_duration_ms cannot be negative");
+    }
+    if negative(config.retry_attempts) {
+        return invalid("retry_attempts cannot be negative");
+    }
+    Ok(config)
+}
+
+fn room_response(room: Room) -> RoomResponse {
+    RoomResponse {
+        id: room.id,
+        name: room.name,
+        max_participants: room.max_participants,
+        recording_enabled: room.recording_enabled,
+        current_participants: room.publishers + room.viewers,
+        publishers: room.publishers,
+        viewers: room.viewers,
+        slow_consumer: room.slow_consumer,
+        state: room.state,
+        start_time: room.created_at,
+        end_time: room.closed_at,
+        last_active: room.last_active,
+    }
+}
@@ -4,61 +4,414 @@
  * 
  * This file contains:
  * - WebSocket connection handling and upgrade
- * - Stream message processing and broadcasting
+ * - Stream message processing and broadcasting, unpacking frame batches
  * - Room connection management
  * - Recording functionality for streams
+ * - Slow-consumer handling for viewers, per the room's policy
+ * - Enforcement of the room config's ingest limits for publishers
+ * - Adaptive bitrate feedback to publishers
  */
 
-use std::sync::Arc;
+use std::{
+    borrow::Cow,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 use axum::{
-    extract::{Path, State, WebSocketUpgrade, ws::{Message, WebSocket}},
+    extract::{Path, Query, State, WebSocketUpgrade, ws::{CloseFrame, Message, WebSocket}},
     response::IntoResponse,
+    Extension,
 };
-use futures::{stream::StreamExt, SinkExt};
-use tokio::sync::broadcast;
-use tracing::{error, info};
+use bytes::Bytes;
+use futures::{stream::{SplitSink, StreamExt}, SinkExt};
+use serde::Deserialize;
+use tokio::sync::{broadcast, mpsc, watch};
+use tracing::{error, info, warn};
+use uuid::Uuid;
 use crate::{
     AppState,
+    abr::{AbrController, QualityBounds, Signals, ABR_INTERVAL},
+    auth::Claims,
+    dedup::DedupResult,
     error::AppError,
+    handlers::auth::room_access,
+    ingest::{IngestLimits, IngestMonitor, Verdict},
+    models::{
+        ControlAction, ErrorCode, Frame, FrameType, ParticipantRole, RecordingMode, RoomConfig, RoomState,
+        ServerMessage, SlowConsumerPolicy, WebSocketMessage,
+    },
+    monitoring::{ConnectionSlot, MetricsStore},
+    protocol::{
+        BinaryEnvelope, BinaryMessageType, Protocol, CLOSE_INVALID_MEDIA, CLOSE_LIMIT_EXCEEDED, CLOSE_ROOM_CLOSED,
+        CLOSE_SLOW_CONSUMER, SUPPORTED_PROTOCOLS,
+    },
+    rooms::{ParticipantSlot, Room},
+    stream_hub::{StreamHub, StreamPacket},
 };
 
+// Unsent bytes a viewer may accumulate before it counts as lagging, unless the
+// room's policy sets its own buffer size
+const DEFAULT_VIEWER_BACKLOG_BYTES: usize = 2 * 1024 * 1024;
+
+// How long a disconnected viewer's socket may take to close before it's dropped
+const CLOSE_GRACE_PERIOD: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Deserialize)]
+pub struct ConnectParams {
+    #[serde(default)]
+    pub role: ParticipantRole,
+}
+
 pub async fn ws_handler(
     ws: WebSocketUpgrade,
     Path(room_id): Path<String>,
+    Query(params): Query<ConnectParams>,
     State(state): State<Arc<AppState>>,
+    Extension(claims): Extension<Claims>,
 ) -> Result<impl IntoResponse, AppError> {
-    // Validate room exists
-    let _room = state.rooms.get_room(&room_id).await?;
+    // Validate the caller may use the room and it accepts connections; a closed room reopens
+    let room = room_access(&state, &claims, &room_id).await?;
+    let role = params.role;
+    if !claims.grants(role) {
+        return Err(AppError::Forbidden(format!("Token doesn't allow joining room {} as {:?}", room_id, role)));
+    }
+    match room.state {
+        RoomState::Archived => return Err(AppError::Forbidden(format!("Room {} is archived", room_id))),
+        RoomState::Closed => {
+            state.rooms.set_state(&room_id, RoomState::Active).await?;
+        }
+        RoomState::Active => {}
+    }
+
+    // Reserve a connection and a slot for the declared role before upgrading;
+    // either refusal is a 503. The slots move into the upgrade callback, so a
+    // failed upgrade drops them along with it.
+    let slots = Slots {
+        _connection: state.connection_tracker.add_connection(&room_id).await?,
+        _participant: state.rooms.add_participant(&room_id, role).await?,
+    };
 
-    // Check connection limits
-    state.connection_tracker.check_limits(&room_id).await;
+    // Publishers are held to the room config's limits and get adaptive bitrate feedback
+    let config = match role {
+        ParticipantRole::Publisher => state.rooms.get_config(&room_id).await?,
+        ParticipantRole::Viewer => RoomConfig::default(),
+    };
 
     // Get stream for room
     let tx = state.rooms.get_stream(&room_id).await?;
 
-    info!("New WebSocket connection for room {}", room_id);
+    info!("New {:?} WebSocket connection for room {}", role, room_id);
+    state.rooms.touch(&room_id).await;
+
+    // Upgrade connection, negotiating the message framing
+    Ok(ws
+        .protocols(SUPPORTED_PROTOCOLS)
+        .on_upgrade(move |socket| handle_socket(socket, room, role, state, tx, slots, config)))
+}
+
+// What a connection holds against the server and room limits, released when dropped
+struct Slots {
+    _connection: ConnectionSlot,
+    _participant: ParticipantSlot,
+}
+
+// Per-connection protocol state
+struct ConnectionState {
+    protocol: Protocol,
+    role: ParticipantRole,
+    last_sequence: Option<u32>,
+    // Room stream that publishers fan out to
+    stream: Arc<StreamHub>,
+    // Measures what a publisher sends against the room's limits
+    ingest: IngestMonitor,
+    // The room records audio only, so video frames are refused
+    audio_only: bool,
+}
+
+impl ConnectionState {
+    // Log gaps in binary envelope sequence numbers
+    fn track_sequence(&mut self, room_id: &str, sequence: u32) {
+        if let Some(last) = self.last_sequence {
+            let expected = last.wrapping_add(1);
+            if sequence != expected {
+                warn!(
+                    "Sequence gap in room {}: expected {}, got {}",
+                    room_id, expected, sequence
+                );
+            }
+        }
+        self.last_sequence = Some(sequence);
+    }
+
+}
+
+// What the incoming side hands to the outgoing loop
+enum Reply {
+    Message(ServerMessage),
+    // Close the connection with an application close code
+    Close(u16, &'static str),
+}
+
+// Messages queued for a connection's writer task
+struct Outbox {
+    tx: mpsc::UnboundedSender<(Message, usize)>,
+    // Bytes of live packets queued but not yet written to the socket
+    backlog: Arc<AtomicUsize>,
+    // Set once a close frame is queued; anything still ahead of it is discarded
+    closing: Arc<AtomicBool>,
+}
+
+impl Outbox {
+    // Spawn the task that owns the socket's sending half
+    fn spawn(sender: SplitSink<WebSocket, Message>) -> (Self, tokio::task::JoinHandle<()>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let backlog = Arc::new(AtomicUsize::new(0));
+        let closing = Arc::new(AtomicBool::new(false));
+        let writer = tokio::spawn(write_messages(sender, rx, backlog.clone(), closing.clone()));
+        (Self { tx, backlog, closing }, writer)
+    }
+
+    fn backlog(&self) -> usize {
+        self.backlog.load(Ordering::Relaxed)
+    }
+
+    // Queue a message; false once the writer has stopped
+    fn push(&self, msg: Message) -> bool {
+        self.push_counted(msg, 0)
+    }
+
+    // Queue a live packet, counting it against the viewer's backlog
+    fn push_packet(&self, msg: Message) -> bool {
+        let len = message_len(&msg);
+        self.push_counted(msg, len)
+    }
 
-    // Upgrade connection
-    Ok(ws.on_upgrade(move |socket| handle_socket(socket, room_id, state, tx)))
+    fn push_counted(&self, msg: Message, counted: usize) -> bool {
+        self.backlog.fetch_add(counted, Ordering::Relaxed);
+        if self.tx.send((msg, counted)).is_err() {
+            self.backlog.fetch_sub(counted, Ordering::Relaxed);
+            return false;
+        }
+        true
+    }
+
+    fn close(&self, code: u16, reason: &'static str) {
+        self.closing.store(true, Ordering::Release);
+        self.push(Message::Close(Some(CloseFrame { code, reason: Cow::Borrowed(reason) })));
+    }
+}
+
+async fn write_messages(
+    mut sender: SplitSink<WebSocket, Message>,
+    mut rx: mpsc::UnboundedReceiver<(Message, usize)>,
+    backlog: Arc<AtomicUsize>,
+    closing: Arc<AtomicBool>,
+) {
+    while let Some((msg, counted)) = rx.recv().await {
+        let is_close = matches!(msg, Message::Close(_));
+        let result = if closing.load(Ordering::Acquire) && !is_close {
+            Ok(())
+        } else {
+            sender.send(msg).await
+        };
+        backlog.fetch_sub(counted, Ordering::Relaxed);
+
+        if let Err(e) = result {
+            error!("Error sending message: {}", e);
+            break;
+        }
+        if is_close {
+            break;
+        }
+    }
+}
+
+fn message_len(msg: &Message) -> usize {
+    match msg {
+        Message::Binary(data) => data.len(),
+        Message::Text(text) => text.len(),
+        _ => 0,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Admission {
+    Send,
+    Drop,
+    Disconnect,
+}
+
+// A viewer's subscription to the room stream and its slow-consumer state
+struct ViewerFeed {
+    id: Uuid,
+    room_id: String,
+    rx: broadcast::Receiver<StreamPacket>,
+    policy: SlowConsumerPolicy,
+    metrics: MetricsStore,
+    // Dropping packets until the next resume point
+    skipping: bool,
+    dropped: u64,
+}
+
+impl ViewerFeed {
+    fn max_backlog(&self) -> usize {
+        match self.policy {
+            SlowConsumerPolicy::Buffer { max_bytes } => usize::try_from(max_bytes).unwrap_or(usize::MAX),
+            SlowConsumerPolicy::DropToKeyframe | SlowConsumerPolicy::Disconnect => DEFAULT_VIEWER_BACKLOG_BYTES,
+        }
+    }
+
+    // Decide what happens to the next live packet given the viewer's unsent backlog
+    fn admit(&mut self, packet: &StreamPacket, backlog: usize) -> Admission {
+        let behind = backlog + packet.len() > self.max_backlog();
+
+        let admission = if self.skipping {
+            if packet.is_resume_point() && !behind {
+                info!(
+                    "Viewer {} in room {} resumed after dropping {} packets",
+                    self.id, self.room_id, self.dropped
+                );
+                self.skipping = false;
+                self.dropped = 0;
+                Admission::Send
+            } else {
+                Admission::Drop
+            }
+        } else if behind {
+            warn!("Viewer {} in room {} has {} bytes unsent", self.id, self.room_id, backlog);
+            self.fall_behind()
+        } else {
+            Admission::Send
+        };
+
+        if admission == Admission::Drop {
+            self.dropped += 1;
+            self.metrics.record_viewer_dropped(&self.room_id, self.id, 1, packet.len() as u64);
+        }
+        admission
+    }
+
+    // The broadcast channel overflowed before the viewer read these packets
+    fn lagged(&mut self, skipped: u64) -> Admission {
+        warn!("Viewer {} in room {} lagged, skipped {} packets", self.id, self.room_id, skipped);
+        self.dropped += skipped;
+        self.metrics.record_viewer_dropped(&self.room_id, self.id, skipped, 0);
+        if self.skipping {
+            return Admission::Drop;
+        }
+        self.fall_behind()
+    }
+
+    fn fall_behind(&mut self) -> Admission {
+        self.metrics.record_viewer_lag(&self.room_id, self.id);
+        match self.policy {
+            SlowConsumerPolicy::DropToKeyframe => {
+                self.skipping = true;
+                Admission::Drop
+            }
+            SlowConsumerPolicy::Disconnect | SlowConsumerPolicy::Buffer { .. } => {
+                self.metrics.record_slow_consumer_disconnect(&self.room_id);
+                Admission::Disconnect
+            }
+        }
+    }
 }
 
 async fn handle_socket(
     socket: WebSocket,
-    room_id: String,
+    room: Room,
+    role: ParticipantRole,
     state: Arc<AppState>,
-    tx: broadcast::Sender<Vec<u8>>,
+    hub: Arc<StreamHub>,
+    slots: Slots,
+    config: RoomConfig,
 ) {
-    let (mut sender, mut receiver) = socket.split();
+    let room_id = room.id;
+    // Only viewers receive the room stream. They start with what a decoder needs
+    // (init segment and latest keyframe onwards), then follow live.
+    let (catch_up, mut feed) = match role {
+        ParticipantRole::Viewer => {
+            let (catch_up, rx) = hub.subscribe();
+            let feed = ViewerFeed {
+                id: Uuid::new_v4(),
+                room_id: room_id.clone(),
+                rx,
+                policy: room.slow_consumer,
+                metrics: state.metrics.clone(),
+                skipping: false,
+                dropped: 0,
+            };
+            (catch_up, Some(feed))
+        }
+        ParticipantRole::Publisher => {
+            hub.reset(&config);
+            (Vec::new(), None)
+        }
+    };
+
+    let protocol = Protocol::from_socket(&socket);
+    let mut conn = ConnectionState {
+        protocol,
+        role,
+        last_sequence: None,
+        stream: hub,
+        ingest: IngestMonitor::new(IngestLimits::from_config(&config)),
+        audio_only: config.recording_mode == RecordingMode::AudioOnly,
+    };
+    let mut abr = match role {
+        ParticipantRole::Publisher => QualityBounds::from_config(&config)
+            .map(|bounds| (AbrController::new(bounds), tokio::time::interval(ABR_INTERVAL))),
+        ParticipantRole::Viewer => None,
+    };
+    info!("Room {} connection using {:?} framing", room_id, protocol);
+
+    let (sender, mut receiver) = socket.split();
+    let (reply_tx, mut reply_rx) = mpsc::unbounded_channel::<Reply>();
+    let (outbox, writer) = Outbox::spawn(sender);
 
     // Handle incoming messages
-    tokio::spawn(async move {
-        while let Some(msg) = receiver.next().await {
+    let incoming_room_id = room_id.clone();
+    let incoming_state = state.clone();
+    let mut closed = conn.stream.watch_closed();
+    let mut incoming_closed = conn.stream.watch_closed();
+    let mut incoming = tokio::spawn(async move {
+        let room_id = incoming_room_id;
+        let state = incoming_state;
+        loop {
+            // Stop reading as soon as the room is closed
+            let msg = tokio::select! {
+                msg = receiver.next() => match msg {
+                    Some(msg) => msg,
+                    None => break,
+                },
+                _ = room_closed(&mut incoming_closed) => break,
+            };
             match msg {
                 Ok(msg) => {
                     // Process message
-                    if let Err(e) = process_message(msg, &room_id, &state).await {
-                        error!("Error processing message: {}", e);
-                        break;
+                    match process_message(msg, &room_id, &state, &mut conn).await {
+                        Ok(Some(reply @ Reply::Close(..))) => {
+                            let _ = reply_tx.send(reply);
+                            break;
+                        }
+                        Ok(Some(reply)) => {
+                            let _ = reply_tx.send(reply);
+                        }
+                        Ok(None) => {}
+                        Err(e) => {
+                            error!("Error processing message: {}", e);
+                            let recoverable = matches!(
+                                e,
+                                AppError::BadRequest(_) | AppError::StreamingError(_) | AppError::Forbidden(_)
+                            );
+                            let _ = reply_tx.send(Reply::Message(error_reply(e)));
+                            if !recoverable {
+                                break;
+                            }
+                        }
                     }
                 }
                 Err(e) => {
@@ -67,29 +420,373 @@ async fn handle_socket(
                 }
             }
         }
+
+        // Finalize any recording left open by the publisher
+        if conn.role == ParticipantRole::Publisher {
+            conn.stream.end_publish();
+            state.metrics.clear_ingest_rate(&room_id);
+            if let Err(e) = state.recordings.stop(&room_id).await {
+                error!("Error finalizing recording for room {}: {}", room_id, e);
+            }
+        }
     });
 
-    // Handle outgoing messages
-    let mut rx = tx.subscribe();
-    while let Ok(msg) = rx.recv().await {
-        if let Err(e) = sender.send(Message::Binary(msg)).await {
-            error!("Error sending message: {}", e);
-            break;
+    // Handle outgoing messages. Catch-up isn't counted against the viewer's backlog.
+    let mut sequence: u32 = 0;
+    for packet in catch_up {
+        if let Some(msg) = packet_message(packet, protocol, sequence) {
+            sequence = sequence.wrapping_add(1);
+            outbox.push(msg);
+        }
+    }
+
+    loop {
+        tokio::select! {
+            packet = next_packet(&mut feed) => {
+                let Some(viewer) = feed.as_mut() else {
+                    break;
+                };
+                let admission = match &packet {
+                    Ok(packet) => viewer.admit(packet, outbox.backlog()),
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => viewer.lagged(*skipped),
+                    Err(broadcast::error::RecvError::Closed) => break,
+                };
+
+                match (admission, packet) {
+                    (Admission::Send, Ok(packet)) => {
+                        if let Some(msg) = packet_message(packet, protocol, sequence) {
+                            if !outbox.push_packet(msg) {
+                                break;
+                            }
+                        }
+                        sequence = sequence.wrapping_add(1);
+                    }
+                    // Dropped frames still use up a sequence number so envelope viewers see the gap
+                    (Admission::Send | Admission::Drop, _) => sequence = sequence.wrapping_add(1),
+                    (Admission::Disconnect, _) => {
+                        warn!("Disconnecting slow viewer {} from room {}", viewer.id, room_id);
+                        outbox.close(CLOSE_SLOW_CONSUMER, "slow consumer");
+                        break;
+                    }
+                }
+            }
+            feedback = next_feedback(&mut abr, &state, &room_id) => {
+                for message in feedback {
+                    info!("Sending {:?} to publisher in room {}", message, room_id);
+                    match serde_json::to_string(&message) {
+                        Ok(text) => {
+                            outbox.push(Message::Text(text));
+                        }
+                        Err(e) => error!("Error serializing feedback: {}", e),
+                    }
+                }
+            }
+            _ = room_closed(&mut closed) => {
+                info!("Room {} closed, disconnecting {:?}", room_id, role);
+                outbox.close(CLOSE_ROOM_CLOSED, "room closed");
+                break;
+            }
+            reply = reply_rx.recv() => match reply {
+                Some(Reply::Message(reply)) => match serde_json::to_string(&reply) {
+                    Ok(text) => {
+                        if !outbox.push(Message::Text(text)) {
+                            break;
+                        }
+                    }
+                    Err(e) => error!("Error serializing reply: {}", e),
+                },
+                Some(Reply::Close(code, reason)) => {
+                    outbox.close(code, reason);
+                    break;
+                }
+                // Incoming side has closed
+                None => break,
+            },
+        }
+    }
+
+    // Let the writer flush what's queued, or the close frame
+    drop(outbox);
+    let _ = writer.await;
+
+    // Publishers must finish finalizing their recording. A viewer's incoming side
+    // ends once the client answers the close; don't wait forever for that.
+    match role {
+        ParticipantRole::Publisher => {
+            let _ = (&mut incoming).await;
+        }
+        ParticipantRole::Viewer => {
+            if tokio::time::timeout(CLOSE_GRACE_PERIOD, &mut incoming).await.is_err() {
+                incoming.abort();
+            }
         }
     }
+
+    if let Some(viewer) = feed {
+        state.metrics.remove_viewer(&room_id, viewer.id);
+    }
+    state.rooms.touch(&room_id).await;
+    drop(slots);
+    info!("{:?} disconnected from room {}", role, room_id);
+}
+
+// Next packet for viewers; publishers have no subscription and never resolve
+async fn next_packet(
+    feed: &mut Option<ViewerFeed>,
+) -> Result<StreamPacket, broadcast::error::RecvError> {
+    match feed {
+        Some(feed) => feed.rx.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+// Next round of adaptive bitrate feedback; never resolves without a controller
+async fn next_feedback(
+    abr: &mut Option<(AbrController, tokio::time::Interval)>,
+    state: &AppState,
+    room_id: &str,
+) -> Vec<ServerMessage> {
+    let Some((controller, interval)) = abr else {
+        return std::future::pending().await;
+    };
+    interval.tick().await;
+
+    let mut signals = Signals {
+        cpu_percent: state.resource_monitor.get_cpu_usage().await,
+        memory_percent: state.resource_monitor.get_memory_usage().await,
+        ingest_bitrate: state.metrics.get_ingest_rate(room_id).map_or(0.0, |(bitrate, _)| bitrate),
+        ..Signals::default()
+    };
+    if let Ok(metrics) = state.metrics.get_room_metrics(room_id).await {
+        signals.viewer_lag_events = metrics.viewer_lag_events;
+        signals.viewer_packets_dropped = metrics.viewer_packets_dropped;
+    }
+    controller.update(signals)
+}
+
+// Resolves once the room's stream is closed, immediately if it already is
+async fn room_closed(closed: &mut watch::Receiver<bool>) {
+    let _ = closed.wait_for(|closed| *closed).await;
 }
 
-async fn process_message(msg: Message, room_id: &str, state: &AppState) -> Result<(), AppError> {
+// Encode a packet for a viewer in its negotiated framing
+fn packet_message(packet: StreamPacket, protocol: Protocol, sequence: u32) -> Option<Message> {
+    match packet {
+        StreamPacket::Chunk { data, .. } => Some(Message::Binary(data.to_vec())),
+        StreamPacket::Frame { frame_type, timestamp, data } => match protocol {
+            Protocol::Binary => Some(Message::Binary(
+                BinaryEnvelope {
+                    message_type: BinaryMessageType::Frame,
+                    track: frame_type,
+                    sequence,
+                    timestamp,
+                    payload: data.to_vec(),
+                }
+                .encode(),
+            )),
+            Protocol::Json => {
+                let message = WebSocketMessage::Frame(Frame {
+                    timestamp,
+                    frame_type,
+                    data: data.to_vec(),
+                });
+                match serde_json::to_string(&message) {
+                    Ok(text) => Some(Message::Text(text)),
+                    Err(e) => {
+                        error!("Error serializing frame: {}", e);
+                        None
+                    }
+                }
+            }
+            // Raw viewers only get the media bytes
+            Protocol::Raw => Some(Message::Binary(data.to_vec())),
+        },
+    }
+}
+
+async fn process_message(
+    msg: Message,
+    room_id: &str,
+    state: &AppState,
+    conn: &mut ConnectionState,
+) -> Result<Option<Reply>, AppError> {
+    if conn.role == ParticipantRole::Viewer && matches!(msg, Message::Binary(_) | Message::Text(_)) {
+        return Err(AppError::Forbidden("Viewers cannot send frames or controls".to_string()));
+    }
+
     match msg {
+        Message::Binary(data) if conn.protocol == Protocol::Binary => {
+            let envelope = BinaryEnvelope::decode(&data)?;
+            conn.track_sequence(room_id, envelope.sequence);
+            dispatch_message(envelope.into_message()?, room_id, state, conn).await
+        }
         Message::Binary(data) => {
-            // Store frame
-            state.storage.save_recording(room_id, &data).await?;
-            Ok(())
+            let verdict = match conn.ingest.check_chunk(&data) {
+                Ok(verdict) => verdict,
+                Err(e) => {
+                    warn!("Disconnecting publisher from room {}: {}", room_id, e);
+                    return Ok(Some(Reply::Close(CLOSE_INVALID_MEDIA, "corrupt media stream")));
+                }
+            };
+            let (keep, reply) = apply_verdict(verdict, room_id, state, &conn.ingest);
+            if keep {
+                // Fan the chunk out live, then append it to the room's recording session
+                let data = Bytes::from(data);
+                conn.stream.publish_chunk(data.clone());
+                state.recordings.append(room_id, &data).await?;
+            }
+            Ok(reply)
+        }
+        Message::Text(text) => {
+            let message: WebSocketMessage = serde_json::from_str(&text)
+                .map_err(|e| AppError::BadRequest(format!("Malformed message: {}", e)))?;
+            dispatch_message(message, room_id, state, conn).await
         }
         Message::Close(_) => {
             info!("Client disconnected from room {}", room_id);
-            Ok(())
+            Ok(None)
+        }
+        _ => Ok(None),
+    }
+}
+
+async fn dispatch_message(
+    message: WebSocketMessage,
+    room_id: &str,
+    state: &AppState,
+    conn: &mut ConnectionState,
+) -> Result<Option<Reply>, AppError> {
+    if conn.audio_only {
+        let video = match &message {
+            WebSocketMessage::Frame(frame) => frame.frame_type == FrameType::Video,
+            WebSocketMessage::Batch { frames } => frames.iter().any(|frame| frame.frame_type == FrameType::Video),
+            WebSocketMessage::Control { .. } => false,
+        };
+        if video {
+            return Err(AppError::BadRequest(format!("Room {} records audio only", room_id)));
+        }
+    }
+
+    match message {
+        WebSocketMessage::Frame(frame) => {
+            let verdict = conn.ingest.check_frame(frame.frame_type, &frame.data);
+            let (keep, reply) = apply_verdict(verdict, room_id, state, &conn.ingest);
+            if keep {
+                conn.stream.publish_frame(frame.frame_type, frame.timestamp, Bytes::from(frame.data.clone()));
+                process_frames(&[frame], room_id, state).await?;
+            }
+            Ok(reply)
+        }
+        WebSocketMessage::Batch { frames } => {
+            // Each frame is checked and fanned out on its own, then the kept
+            // ones are recorded together
+            let mut kept = Vec::with_capacity(frames.len());
+            let mut reply = None;
+            for frame in frames {
+                let verdict = conn.ingest.check_frame(frame.frame_type, &frame.data);
+                let (keep, frame_reply) = apply_verdict(verdict, room_id, state, &conn.ingest);
+                if keep {
+                    conn.stream.publish_frame(frame.frame_type, frame.timestamp, Bytes::from(frame.data.clone()));
+                    kept.push(frame);
+                }
+                match frame_reply {
+                    Some(close @ Reply::Close(..)) => {
+                        reply = Some(close);
+                        break;
+                    }
+                    // One hint per batch is enough
+                    Some(hint) => {
+                        reply.get_or_insert(hint);
+                    }
+                    None => {}
+                }
+            }
+            process_frames(&kept, room_id, state).await?;
+            Ok(reply)
+        }
+        WebSocketMessage::Control { action } => {
+            let recording_id = process_control(action, room_id, state).await?;
+            Ok(Some(Reply::Message(ServerMessage::Ack { action, recording_id })))
         }
-        _ => Ok(()),
     }
-} 
\ No newline at end of file
+}
+
+// Act on a publisher message's limit check; returns whether to keep the message
+// and what to send the publisher
+fn apply_verdict(verdict: Verdict, room_id: &str, state: &AppState, ingest: &IngestMonitor) -> (bool, Option<Reply>) {
+    state.metrics.record_ingest_rate(room_id, ingest.bitrate(), ingest.fps());
+
+    match verdict {
+        Verdict::Accept => (true, None),
+        Verdict::Hint(violation) => {
+            state.metrics.record_limit_exceeded(room_id);
+            let hint = ServerMessage::LimitExceeded {
+                limit: violation.limit,
+                measured: violation.measured,
+                allowed: violation.allowed,
+                policy: ingest.policy(),
+            };
+            (true, Some(Reply::Message(hint)))
+        }
+        Verdict::Drop(_) => {
+            state.metrics.record_limit_dropped(room_id);
+            (false, None)
+        }
+        Verdict::Disconnect(violation) => {
+            warn!(
+                "Disconnecting publisher from room {}: {:?} at {:.0}, limit {:.0}",
+                room_id, violation.limit, violation.measured, violation.allowed
+            );
+            state.metrics.record_limit_exceeded(room_id);
+            (false, Some(Reply::Close(CLOSE_LIMIT_EXCEEDED, violation.close_reason())))
+        }
+    }
+}
+
+async fn process_frames(frames: &[Frame], room_id: &str, state: &AppState) -> Result<(), AppError> {
+    if frames.is_empty() {
+        return Ok(());
+    }
+    let results = state.recordings.append_frames(room_id, frames).await?;
+
+    let bytes = frames.iter().map(|frame| frame.data.len() as u64).sum();
+    let duplicates = results.iter().filter(|result| matches!(result, DedupResult::Duplicate { .. })).count();
+    state.metrics.record_frames(room_id.to_string(), frames.len() as u64);
+    state.metrics.record_bytes(room_id.to_string(), bytes);
+    if duplicates > 0 {
+        state.metrics.record_deduplicated(room_id.to_string(), duplicates as u64);
+    }
+    Ok(())
+}
+
+async fn process_control(action: ControlAction, room_id: &str, state: &AppState) -> Result<Option<Uuid>, AppError> {
+    match action {
+        ControlAction::StartRecording => {
+            // Starting a room that is already recording is a no-op
+            if let Some(session) = state.recordings.get_active(room_id) {
+                return Ok(Some(session.lock().await.id));
+            }
+            let session = state.recordings.start(room_id).await?;
+            let id = session.lock().await.id;
+            Ok(Some(id))
+        }
+        ControlAction::StopRecording => state.recordings.stop(room_id).await,
+        ControlAction::PauseRecording => state.recordings.pause(room_id).await.map(Some),
+        ControlAction::ResumeRecording => state.recordings.resume(room_id).await.map(Some),
+    }
+}
+
+fn error_reply(err: AppError) -> ServerMessage {
+    let code = match err {
+        AppError::BadRequest(_) => ErrorCode::InvalidMessage,
+        AppError::StreamingError(_) => ErrorCode::InvalidState,
+        AppError::Forbidden(_) => ErrorCode::Forbidden,
+        AppError::StorageError(_) => ErrorCode::StorageFailure,
+        _ => ErrorCode::Internal,
+    };
+
+    ServerMessage::Error {
+        code,
+        message: err.to_string(),
+    }
+}
@@ -0,0 +1,380 @@
+/*
+ * hls.rs
+ * Purpose: HLS packaging of live streams and finished recordings
+ *
+ * This file contains:
+ * - LiveHls, a room's rolling window of fMP4 segments cut from what its
+ *   publisher sends, frames or Matroska chunks alike
+ * - The live media playlist over that window, ended with EXT-X-ENDLIST once
+ *   the publisher leaves
+ * - vod_playlist, which lists the fragments of a recording's MP4 copy as byte
+ *   ranges of the file itself
+ *
+ * The live segments are shared with DASH, whose manifests are built in dash.rs.
+ *
+ * Only H.264 video and Opus audio are packaged; they're copied as they are, so
+ * a room publishing anything else has no HLS stream. Segments are cut at video
+ * keyframes. A new publisher continues the same playlist after an
+ * EXT-X-DISCONTINUITY, with its own init segment.
+ */
+
+use std::collections::{hash_map::Entry, HashMap, VecDeque};
+use std::fmt::Write;
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use tracing::debug;
+use crate::{
+    models::{FragmentIndex, FrameType, RecordingMode, RoomConfig},
+    mux::{opus_head, MuxTrack},
+    remux::{is_copyable, Fmp4Writer, Fragment, MatroskaDemuxer, SampleConverter},
+};
+
+// Segments run from one video keyframe to the first one past this age
+const LIVE_SEGMENT_MS: i64 = 2_000;
+
+// Segments listed in the live playlist
+const PLAYLIST_WINDOW: usize = 6;
+
+// Segments kept in memory, so players still working through an older playlist
+// can fetch what it lists
+const RETAINED_SEGMENTS: usize = 10;
+
+// Lower bound on EXT-X-TARGETDURATION, in seconds
+const MIN_TARGET_DURATION: u64 = 2;
+
+// Where the packaged stream comes from
+enum Source {
+    // Timestamped frames, configured from the room
+    Frames {
+        converter: SampleConverter,
+        opus_head: Vec<u8>,
+        size: Option<(u32, u32)>,
+        // Timestamp of the first frame; segment times count from it
+        origin: Option<i64>,
+    },
+    // A Matroska stream, whose tracks are known once its header is parsed
+    Chunks { demuxer: Box<MatroskaDemuxer> },
+}
+
+// One publisher's stream being cut into segments
+struct Packager {
+    source: Source,
+    // Created once the tracks are known
+    writer: Option<Fmp4Writer>,
+    init_id: u32,
+    // When the first frame or chunk arrived
+    started: Option<DateTime<Utc>>,
+}
+
+// A segment in the rolling window
+pub struct LiveSegment {
+    pub sequence: u64,
+    pub init_id: u32,
+    // Media time of its first sample and how long it plays, in milliseconds
+    pub start_ms: i64,
+    pub duration_ms: i64,
+    // First segment of a publisher that followed another one
+    pub discontinuity: bool,
+    // Discontinuities up to and including this segment
+    discontinuity_count: u64,
+    pub data: Bytes,
+}
+
+// One publisher's init segment and what manifests need to know about it
+pub struct LiveInit {
+    pub data: Bytes,
+    pub codecs: String,
+    pub video_size: Option<(u32, u32)>,
+    // Where the publisher's media time zero falls, in milliseconds since the
+    // hub's first stream started: when its first frame arrived, or the end of
+    // the stream before it if that's later
+    pub period_start_ms: i64,
+}
+
+#[derive(Default)]
+pub struct LiveHls {
+    // None when nothing is publishing or the stream can't be packaged
+    packager: Option<Packager>,
+    segments: VecDeque<LiveSegment>,
+    init_segments: HashMap<u32, LiveInit>,
+    // When the first stream packaged here started; DASH times count from it
+    available_since: Option<DateTime<Utc>>,
+    // End of the latest segment on that timeline, in milliseconds
+    presentation_end_ms: i64,
+    next_sequence: u64,
+    next_init_id: u32,
+    discontinuity_count: u64,
+    // The next segment starts a new publisher's stream
+    discontinuity_pending: bool,
+    target_duration: u64,
+    // The publisher has left and the last segment is out
+    ended: bool,
+}
+
+impl LiveHls {
+    // Start packaging a new publisher's stream. Frames are expected in the
+    // room's configured codecs; a chunk stream declares its own.
+    pub fn restart(&mut self, config: &RoomConfig) {
+        self.end();
+        let video = MuxTrack::for_room(FrameType::Video, config);
+        let audio = MuxTrack::for_room(FrameType::Audio, config);
+        let opus_head = opus_head(audio.channels);
+        let tracks: Vec<FrameType> = match config.recording_mode {
+            RecordingMode::AudioOnly => vec![audio],
+            _ => vec![video.clone(), audio],
+        }
+        .into_iter()
+        .filter(|track| is_copyable(track.track, &track.codec))
+        .map(|track| track.track)
+        .collect();
+
+        let init_id = self.next_init_id;
+        self.next_init_id += 1;
+        self.packager = Some(Packager {
+            source: Source::Frames {
+                converter: SampleConverter::default(),
+                opus_head,
+                size: video.size,
+                origin: None,
+            },
+            // Frames in other codecs mean no HLS unless they turn out to be chunks
+            writer: (!tracks.is_empty()).then(|| Fmp4Writer::new(&tracks, LIVE_SEGMENT_MS)),
+            init_id,
+            started: None,
+        });
+        self.discontinuity_pending = !self.segments.is_empty();
+        self.ended = false;
+    }
+
+    pub fn push_chunk(&mut self, data: &[u8]) {
+        let Some(packager) = self.packager.as_mut() else {
+            return;
+        };
+        packager.started.get_or_insert_with(Utc::now);
+        // A publisher sends either frames or chunks, so the first chunk settles it
+        if let Source::Frames { origin: None, .. } = packager.source {
+            packager.source = Source::Chunks {
+                demuxer: Box::new(MatroskaDemuxer::new(&[FrameType::Video, FrameType::Audio])),
+            };
+            packager.writer = None;
+        }
+        let Source::Chunks { demuxer } = &mut packager.source else {
+            return;
+        };
+
+        if let Err(e) = demuxer.push(data) {
+            debug!("Live stream can't be packaged for HLS: {}", e);
+            self.stop();
+            return;
+        }
+        if packager.writer.is_none() {
+            let Some(tracks) = demuxer.header_tracks() else {
+                return;
+            };
+            let media: Vec<(FrameType, Option<&str>)> = tracks
+                .iter()
+                .filter(|t| t.is_video() || t.is_audio())
+                .map(|t| (if t.is_video() { FrameType::Video } else { FrameType::Audio }, t.codec_name()))
+                .collect();
+            let copyable = media.iter().all(|(kind, codec)| codec.is_some_and(|codec| is_copyable(*kind, codec)));
+            let kinds: Vec<FrameType> = [FrameType::Video, FrameType::Audio]
+                .into_iter()
+                .filter(|kind| media.iter().any(|(track, _)| track == kind))
+                .collect();
+            if !copyable || kinds.is_empty() {
+                debug!("Live stream isn't H.264/Opus, not packaging it for HLS");
+                self.stop();
+                return;
+            }
+            packager.writer = Some(Fmp4Writer::new(&kinds, LIVE_SEGMENT_MS));
+        }
+
+        let mut fragments = Vec::new();
+        if let Some(writer) = packager.writer.as_mut() {
+            while let Some(sample) = demuxer.next_sample() {
+                fragments.extend(writer.push(sample));
+            }
+        }
+        for fragment in fragments {
+            self.add_segment(fragment);
+        }
+    }
+
+    pub fn push_frame(&mut self, frame_type: FrameType, timestamp: i64, data: &[u8]) {
+        let Some(packager) = self.packager.as_mut() else {
+            return;
+        };
+        packager.started.get_or_insert_with(Utc::now);
+        let (Source::Frames { converter, opus_head, size, origin }, Some(writer)) =
+            (&mut packager.source, packager.writer.as_mut())
+        else {
+            return;
+        };
+        let timecode = timestamp.saturating_sub(*origin.get_or_insert(timestamp)).max(0);
+
+        // Audio frames are whole Opus packets; video is Annex B, keyframes being
+        // the ones with an IDR slice
+        let sample = match frame_type {
+            FrameType::Video => converter.convert(frame_type, false, timecode, data.to_vec(), None, *size),
+            FrameType::Audio => converter.convert(frame_type, true, timecode, data.to_vec(), Some(opus_head), None),
+        };
+        if let Some(fragment) = sample.and_then(|sample| writer.push(sample)) {
+            self.add_segment(fragment);
+        }
+    }
+
+    // The publisher has left: cut the last segment and end the playlist
+    pub fn end(&mut self) {
+        if self.packager.is_none() {
+            return;
+        }
+        let fragment = self.packager.as_mut().and_then(|p| p.writer.as_mut()).and_then(|writer| writer.finish());
+        if let Some(fragment) = fragment {
+            self.add_segment(fragment);
+        }
+        self.stop();
+    }
+
+    // Live media playlist, None until the first segment is out. `query` is
+    // appended to every URI it lists.
+    pub fn playlist(&self, query: &str) -> Option<String> {
+        let window: Vec<&LiveSegment> = self.window().collect();
+        let head = window.first()?;
+
+        let mut out = String::new();
+        out.push_str("#EXTM3U\n#EXT-X-VERSION:7\n");
+        let _ = writeln!(out, "#EXT-X-TARGETDURATION:{}", self.target_duration);
+        let _ = writeln!(out, "#EXT-X-MEDIA-SEQUENCE:{}", head.sequence);
+        // Counts the discontinuities before the window
+        let _ = writeln!(out, "#EXT-X-DISCONTINUITY-SEQUENCE:{}", head.discontinuity_count - u64::from(head.discontinuity));
+        out.push_str("#EXT-X-INDEPENDENT-SEGMENTS\n");
+
+        let mut init_id = None;
+        for segment in window {
+            if segment.discontinuity {
+                out.push_str("#EXT-X-DISCONTINUITY\n");
+            }
+            if init_id != Some(segment.init_id) {
+                let _ = writeln!(out, "#EXT-X-MAP:URI=\"live/init_{}.mp4{}\"", segment.init_id, query);
+                init_id = Some(segment.init_id);
+            }
+            let _ = writeln!(out, "#EXTINF:{},", seconds(segment.duration_ms));
+            let _ = writeln!(out, "live/{}.m4s{}", segment.sequence, query);
+        }
+        if self.ended {
+            out.push_str("#EXT-X-ENDLIST\n");
+        }
+        Some(out)
+    }
+
+    // The segments listed in the live playlist, oldest first
+    pub fn window(&self) -> impl Iterator<Item = &LiveSegment> {
+        self.segments.iter().skip(self.segments.len().saturating_sub(PLAYLIST_WINDOW))
+    }
+
+    pub fn init(&self, id: u32) -> Option<&LiveInit> {
+        self.init_segments.get(&id)
+    }
+
+    pub fn init_segment(&self, id: u32) -> Option<Bytes> {
+        self.init_segments.get(&id).map(|init| init.data.clone())
+    }
+
+    pub fn available_since(&self) -> Option<DateTime<Utc>> {
+        self.available_since
+    }
+
+    pub fn is_ended(&self) -> bool {
+        self.ended
+    }
+
+    pub fn segment(&self, sequence: u64) -> Option<Bytes> {
+        self.segments.iter().find(|s| s.sequence == sequence).map(|s| s.data.clone())
+    }
+
+    // Give up on the current stream; what's already been cut stays listed
+    fn stop(&mut self) {
+        self.packager = None;
+        self.ended = !self.segments.is_empty();
+    }
+
+    fn add_segment(&mut self, fragment: Fragment) {
+        let Some(packager) = &self.packager else {
+            return;
+        };
+        let init_id = packager.init_id;
+        if let Entry::Vacant(entry) = self.init_segments.entry(init_id) {
+            let Some(writer) = packager.writer.as_ref() else {
+                return;
+            };
+            let (Some(init), Some(codecs)) = (writer.init_segment(), writer.codecs()) else {
+                return;
+            };
+            let started = packager.started.unwrap_or_else(Utc::now);
+            let available_since = *self.available_since.get_or_insert(started);
+            entry.insert(LiveInit {
+                data: Bytes::copy_from_slice(init),
+                codecs,
+                video_size: writer.video_size(),
+                period_start_ms: (started - available_since).num_milliseconds().max(self.presentation_end_ms),
+            });
+        }
+        if let Some(init) = self.init_segments.get(&init_id) {
+            let end = init.period_start_ms + fragment.start_ms + fragment.duration_ms;
+            self.presentation_end_ms = self.presentation_end_ms.max(end);
+        }
+
+        let discontinuity = std::mem::take(&mut self.discontinuity_pending);
+        if discontinuity {
+            self.discontinuity_count += 1;
+        }
+        let seconds = (fragment.duration_ms.max(0) as u64).div_ceil(1000);
+        self.target_duration = self.target_duration.max(seconds).max(MIN_TARGET_DURATION);
+        self.segments.push_back(LiveSegment {
+            sequence: self.next_sequence,
+            init_id,
+            start_ms: fragment.start_ms,
+            duration_ms: fragment.duration_ms,
+            discontinuity,
+            discontinuity_count: self.discontinuity_count,
+            data: Bytes::from(fragment.data),
+        });
+        self.next_sequence += 1;
+
+        while self.segments.len() > RETAINED_SEGMENTS {
+            self.segments.pop_front();
+        }
+        let segments = &self.segments;
+        self.init_segments
+            .retain(|id, _| *id == init_id || segments.iter().any(|s| s.init_id == *id));
+    }
+}
+
+// VOD media playlist over the fragments of an MP4 copy, served at `uri`
+pub fn vod_playlist(index: &FragmentIndex, uri: &str) -> String {
+    let target = index
+        .fragments
+        .iter()
+        .map(|f| (f.duration_ms.max(0) as u64).div_ceil(1000))
+        .max()
+        .unwrap_or(0)
+        .max(1);
+
+    let mut out = String::new();
+    out.push_str("#EXTM3U\n#EXT-X-VERSION:7\n");
+    let _ = writeln!(out, "#EXT-X-TARGETDURATION:{}", target);
+    out.push_str("#EXT-X-MEDIA-SEQUENCE:0\n#EXT-X-PLAYLIST-TYPE:VOD\n#EXT-X-INDEPENDENT-SEGMENTS\n");
+    let _ = writeln!(out, "#EXT-X-MAP:URI=\"{}\",BYTERANGE=\"{}@0\"", uri, index.init_size);
+    for fragment in &index.fragments {
+        let _ = writeln!(out, "#EXTINF:{},", seconds(fragment.duration_ms));
+        let _ = writeln!(out, "#EXT-X-BYTERANGE:{}@{}", fragment.size, fragment.offset);
+        out.push_str(uri);
+        out.push('\n');
+    }
+    out.push_str("#EXT-X-ENDLIST\n");
+    out
+}
+
+fn seconds(ms: i64) -> String {
+    format!("{:.3}", ms.max(0) as f64 / 1000.0)
+}
@@ -0,0 +1,315 @@
+/*
+ * ingest.rs
+ * Purpose: Per-publisher ingest measurement and enforcement of room limits
+ *
+ * This file contains:
+ * - RateMeter, a sliding-window byte and video frame counter
+ * - IngestLimits, the enforceable part of a room's RoomConfig
+ * - IngestMonitor, which measures a publisher's bitrate, frame rate and
+ *   resolution and decides what happens to each message over the limits
+ * - JPEG dimension parsing for frame streams
+ *
+ * Opaque chunks are container bytes (WebM), so dropping one would corrupt the
+ * stream for viewers and the recording. Under the drop policy, chunks over the
+ * limits are kept and the publisher is hinted instead. A WebM stream that stops
+ * parsing is rejected outright.
+ */
+
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+use crate::{
+    error::AppError,
+    models::{FrameType, IngestLimit, LimitPolicy, RoomConfig},
+    webm::{WebmEvent, WebmScanner},
+};
+
+// Rates are averaged over this window
+const RATE_WINDOW: Duration = Duration::from_secs(2);
+
+// Headroom over the configured rates before a publisher counts as exceeding them
+const RATE_TOLERANCE: f64 = 1.1;
+
+// Minimum time between two hints about the same limit
+const HINT_INTERVAL: Duration = Duration::from_secs(5);
+
+// Bytes and video frames seen within the last RATE_WINDOW
+struct RateMeter {
+    samples: VecDeque<(Instant, usize, u32)>,
+    bytes: usize,
+    frames: u32,
+}
+
+impl RateMeter {
+    fn new() -> Self {
+        Self {
+            samples: VecDeque::new(),
+            bytes: 0,
+            frames: 0,
+        }
+    }
+
+    fn prune(&mut self, now: Instant) {
+        while let Some(&(at, bytes, frames)) = self.samples.front() {
+            if now.duration_since(at) < RATE_WINDOW {
+                break;
+            }
+            self.samples.pop_front();
+            self.bytes -= bytes;
+            self.frames -= frames;
+        }
+    }
+
+    fn record(&mut self, now: Instant, bytes: usize, frames: u32) {
+        self.samples.push_back((now, bytes, frames));
+        self.bytes += bytes;
+        self.frames += frames;
+    }
+
+    fn bitrate(&self) -> f64 {
+        to_bitrate(self.bytes)
+    }
+
+    fn fps(&self) -> f64 {
+        to_fps(self.frames)
+    }
+}
+
+fn to_bitrate(bytes: usize) -> f64 {
+    (bytes * 8) as f64 / RATE_WINDOW.as_secs_f64()
+}
+
+fn to_fps(frames: u32) -> f64 {
+    frames as f64 / RATE_WINDOW.as_secs_f64()
+}
+
+// A limit the publisher went over
+#[derive(Debug, Clone, Copy)]
+pub struct Violation {
+    pub limit: IngestLimit,
+    pub measured: f64,
+    pub allowed: f64,
+}
+
+impl Violation {
+    // Close reason sent when the violation disconnects the publisher
+    pub fn close_reason(&self) -> &'static str {
+        match self.limit {
+            IngestLimit::Bitrate => "bitrate limit exceeded",
+            IngestLimit::FrameRate => "frame rate limit exceeded",
+            IngestLimit::BufferSize => "message size limit exceeded",
+            IngestLimit::Resolution => "resolution limit exceeded",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Verdict {
+    Accept,
+    // Keep the message and tell the publisher it's over a limit
+    Hint(Violation),
+    Drop(Violation),
+    Disconnect(Violation),
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct IngestLimits {
+    // Bits per second
+    max_bitrate: Option<f64>,
+    max_fps: Option<f64>,
+    max_message_bytes: Option<usize>,
+    max_resolution: Option<(u32, u32)>,
+    policy: LimitPolicy,
+}
+
+impl IngestLimits {
+    pub fn from_config(config: &RoomConfig) -> Self {
+        Self {
+            max_bitrate: config.max_bitrate.filter(|b| *b > 0).map(f64::from),
+            max_fps: config.frame_rate.filter(|f| *f > 0).map(f64::from),
+            // max_buffer_size is in MB
+            max_message_bytes: config.max_buffer_size
+                .filter(|mb| *mb > 0)
+                .map(|mb| mb as usize * 1024 * 1024),
+            max_resolution: config.resolution_dimensions(),
+            policy: config.limit_policy,
+        }
+    }
+}
+
+// Measures one publisher's stream against its room's limits
+pub struct IngestMonitor {
+    limits: IngestLimits,
+    // Everything the publisher sent
+    incoming: RateMeter,
+    // What was kept, i.e. not dropped under the drop policy
+    accepted: RateMeter,
+    // Follows opaque chunks to count video blocks, read the video size and catch corruption
+    scanner: WebmScanner,
+    hinted: HashMap<IngestLimit, Instant>,
+}
+
+impl IngestMonitor {
+    pub fn new(limits: IngestLimits) -> Self {
+        Self {
+            limits,
+            incoming: RateMeter::new(),
+            accepted: RateMeter::new(),
+            scanner: WebmScanner::new(),
+            hinted: HashMap::new(),
+        }
+    }
+
+    pub fn policy(&self) -> LimitPolicy {
+        self.limits.policy
+    }
+
+    // Incoming bitrate in bits per second over the last window
+    pub fn bitrate(&self) -> f64 {
+        self.incoming.bitrate()
+    }
+
+    // Incoming video frames per second over the last window
+    pub fn fps(&self) -> f64 {
+        self.incoming.fps()
+    }
+
+    // Errors once a WebM stream is corrupt; chunks that aren't WebM at all pass unparsed
+    pub fn check_chunk(&mut self, data: &[u8]) -> Result<Verdict, AppError> {
+        let frames = self.scanner
+            .feed(data)
+            .into_iter()
+            .filter(|event| match event {
+                WebmEvent::Block { track, .. } => !self.scanner.has_video() || self.scanner.is_video_track(*track),
+                WebmEvent::ClusterStart { .. } => false,
+            })
+            .count() as u32;
+        if let Some(reason) = self.scanner.corruption() {
+            return Err(AppError::StreamingError(format!("Corrupt WebM stream: {}", reason)));
+        }
+        let size = self.scanner.video_size();
+        Ok(self.check(data.len(), frames, size, false))
+    }
+
+    pub fn check_frame(&mut self, frame_type: FrameType, data: &[u8]) -> Verdict {
+        let (frames, size) = match frame_type {
+            FrameType::Video => (1, jpeg_dimensions(data)),
+            FrameType::Audio => (0, None),
+        };
+        self.check(data.len(), frames, size, true)
+    }
+
+    fn check(&mut self, bytes: usize, frames: u32, size: Option<(u32, u32)>, droppable: bool) -> Verdict {
+        let now = Instant::now();
+        self.incoming.prune(now);
+        self.accepted.prune(now);
+        self.incoming.record(now, bytes, frames);
+
+        let Some(violation) = self.violation(bytes, frames, size) else {
+            self.accepted.record(now, bytes, frames);
+            return Verdict::Accept;
+        };
+
+        match self.limits.policy {
+            LimitPolicy::Drop if droppable => Verdict::Drop(violation),
+            LimitPolicy::Disconnect => Verdict::Disconnect(violation),
+            LimitPolicy::Hint | LimitPolicy::Drop => {
+                self.accepted.record(now, bytes, frames);
+                if self.should_hint(violation.limit, now) {
+                    Verdict::Hint(violation)
+                } else {
+                    Verdict::Accept
+                }
+            }
+        }
+    }
+
+    fn violation(&self, bytes: usize, frames: u32, size: Option<(u32, u32)>) -> Option<Violation> {
+        let limits = &self.limits;
+
+        if let Some(max) = limits.max_message_bytes.filter(|max| bytes > *max) {
+            return Some(Violation {
+                limit: IngestLimit::BufferSize,
+                measured: bytes as f64,
+                allowed: max as f64,
+            });
+        }
+
+        // Either orientation fits, so a rotated phone isn't rejected
+        if let (Some((width, height)), Some((max_width, max_height))) = (size, limits.max_resolution) {
+            if width.max(height) > max_width.max(max_height) || width.min(height) > max_width.min(max_height) {
+                return Some(Violation {
+                    limit: IngestLimit::Resolution,
+                    measured: f64::from(width) * f64::from(height),
+                    allowed: f64::from(max_width) * f64::from(max_height),
+                });
+            }
+        }
+
+        // Under the drop policy, measure what would be kept including this message,
+        // so frames are shed down to the limit rather than all dropped while over it
+        let (window_bytes, window_frames) = match limits.policy {
+            LimitPolicy::Drop => (self.accepted.bytes + bytes, self.accepted.frames + frames),
+            LimitPolicy::Hint | LimitPolicy::Disconnect => (self.incoming.bytes, self.incoming.frames),
+        };
+
+        if let Some(max) = limits.max_bitrate {
+            let measured = to_bitrate(window_bytes);
+            if measured > max * RATE_TOLERANCE {
+                return Some(Violation { limit: IngestLimit::Bitrate, measured, allowed: max });
+            }
+        }
+
+        // Audio doesn't count towards the frame rate
+        if let Some(max) = limits.max_fps.filter(|_| frames > 0) {
+            let measured = to_fps(window_frames);
+            if measured > max * RATE_TOLERANCE {
+                return Some(Violation { limit: IngestLimit::FrameRate, measured, allowed: max });
+            }
+        }
+        None
+    }
+
+    fn should_hint(&mut self, limit: IngestLimit, now: Instant) -> bool {
+        match self.hinted.get(&limit) {
+            Some(last) if now.duration_since(*last) < HINT_INTERVAL => false,
+            _ => {
+                self.hinted.insert(limit, now);
+                true
+            }
+        }
+    }
+}
+
+// Width and height from a JPEG's start-of-frame segment; None for anything else
+pub fn jpeg_dimensions(data: &[u8]) -> Option<(u32, u32)> {
+    if !data.starts_with(&[0xFF, 0xD8]) {
+        return None;
+    }
+
+    let mut pos = 2;
+    while pos + 4 <= data.len() {
+        if data[pos] != 0xFF {
+            return None;
+        }
+        let marker = data[pos + 1];
+        match marker {
+            // Fill byte before a marker
+            0xFF => pos += 1,
+            // Markers without a length
+            0x01 | 0xD0..=0xD8 => pos += 2,
+            // SOF0-SOF15, except DHT, JPG and DAC which share the range
+            0xC0..=0xCF if !matches!(marker, 0xC4 | 0xC8 | 0xCC) => {
+                let sof = data.get(pos + 5..pos + 9)?;
+                let height = u16::from_be_bytes([sof[0], sof[1]]);
+                let width = u16::from_be_bytes([sof[2], sof[3]]);
+                return Some((u32::from(width), u32::from(height)));
+            }
+            // Start of scan: image data follows and no frame header was found
+            0xDA => return None,
+            _ => pos += 2 + u16::from_be_bytes([data[pos + 2], data[pos + 3]]) as usize,
+        }
+    }
+    None
+}
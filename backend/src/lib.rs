@@ -1,34 +1,64 @@
 pub mod auth;
+pub mod config;
+pub mod dedup;
 pub mod error;
 pub mod handlers;
 pub mod models;
+pub mod protocol;
+pub mod recording;
+pub mod repository;
 pub mod rooms;
 pub mod storage;
+pub mod stream_hub;
+pub mod webm;
+pub mod ingest;
+pub mod abr;
+pub mod mux;
+pub mod cues;
+pub mod remux;
+pub mod hls;
+pub mod dash;
 pub mod monitoring;
 pub mod logging;
 
 use std::sync::Arc;
 use auth::Auth;
+use config::Config;
+use dedup::Deduplicator;
+use recording::RecordingManager;
+use repository::Repository;
 use rooms::Rooms;
 use storage::Storage;
-use monitoring::{MetricsStore, ConnectionTracker};
+use monitoring::{MetricsStore, ConnectionTracker, ResourceMonitor};
 
 pub struct AppState {
     pub auth: Auth,
+    pub repo: Repository,
     pub rooms: Rooms,
     pub storage: Storage,
+    pub recordings: RecordingManager,
     pub metrics: MetricsStore,
+    pub resource_monitor: ResourceMonitor,
     pub connection_tracker: ConnectionTracker,
 }
 
 impl AppState {
-    pub async fn new(jwt_secret: &[u8]) -> Result<Arc<Self>, error::AppError> {
+    pub async fn new(config: &Config) -> Result<Arc<Self>, error::AppError> {
+        Self::with_repository(config, Repository::memory()).await
+    }
+
+    // Storage comes from the same settings main uses: backend, path, layout and size limit
+    pub async fn with_repository(config: &Config, repo: Repository) -> Result<Arc<Self>, error::AppError> {
+        let storage = Storage::new(config).await?;
         Ok(Arc::new(Self {
-            auth: Auth::new(jwt_secret),
-            rooms: Rooms::new(),
-            storage: Storage::new().await?,
+            auth: Auth::new(config.jwt_secret.as_bytes()),
+            rooms: Rooms::new(repo.clone()),
+            recordings: RecordingManager::new(storage.clone(), repo.clone(), Deduplicator::memory()),
+            repo,
+            storage,
             metrics: MetricsStore::new(),
-            connection_tracker: ConnectionTracker::new(),
+            resource_monitor: ResourceMonitor::new(),
+            connection_tracker: ConnectionTracker::with_limits(config.max_connections, config.max_room_size),
         }))
     }
 } 
\ No newline at end of file
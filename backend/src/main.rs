@@ -25,14 +25,17 @@ use tower_http::{
     timeout::TimeoutLayer,
 };
 use tower_cookies::CookieManagerLayer;
-use tracing::info;
+use tracing::{info, warn};
 
 use crate::{
     error::AppError,
     config::Config,
     auth::Auth,
+    dedup::Deduplicator,
     rooms::Rooms,
     storage::Storage,
+    recording::RecordingManager,
+    repository::Repository,
     monitoring::{MetricsStore, ResourceMonitor, ConnectionTracker},
     logging::setup_logging,
     handlers::auth::require_auth,
@@ -41,19 +44,34 @@ use crate::{
 mod error;
 mod config;
 mod auth;
+mod dedup;
 mod rooms;
 mod storage;
+mod stream_hub;
+mod webm;
+mod ingest;
+mod abr;
+mod mux;
+mod cues;
+mod remux;
+mod hls;
+mod dash;
+mod recording;
+mod repository;
 mod monitoring;
 mod logging;
 mod handlers;
 mod models;
+mod protocol;
 
 #[derive(Clone)]
 pub struct AppState {
     pub config: Config,
     pub auth: Auth,
+    pub repo: Repository,
     pub rooms: Rooms,
     pub storage: Storage,
+    pub recordings: RecordingManager,
     pub metrics: MetricsStore,
     pub resource_monitor: ResourceMonitor,
     pub connection_tracker: ConnectionTracker,
@@ -69,22 +87,76 @@ async fn main() -> Result<(), AppError> {
     // Load config
     let config = Config::load()?;
 
+    // Connect to the database, falling back to process-local state
+    let repo = match &config.database_url {
+        Some(url) => Repository::connect(url).await?,
+        None => {
+            warn!("DATABASE_URL not set, rooms and recordings will not persist across restarts");
+            Repository::memory()
+        }
+    };
+
+    // Frame deduplication, shared across instances through Redis when available
+    let dedup = match &config.redis_url {
+        Some(url) => Deduplicator::connect(url).await?,
+        None => {
+            warn!("REDIS_URL not set, using in-memory frame deduplication");
+            Deduplicator::memory()
+        }
+    };
+
     // Initialize state
+    let storage = Storage::new(&config).await?;
     let state = Arc::new(AppState {
         config: config.clone(),
         auth: Auth::new(config.jwt_secret.as_bytes()),
-        rooms: Rooms::new(),
-        storage: Storage::new().await?,
+        rooms: Rooms::new(repo.clone()),
+        recordings: RecordingManager::new(storage.clone(), repo.clone(), dedup),
+        repo,
+        storage,
         metrics: MetricsStore::new(),
         resource_monitor: ResourceMonitor::new(),
-        connection_tracker: ConnectionTracker::new(),
+        connection_tracker: ConnectionTracker::with_limits(config.max_connections, config.max_room_size),
+    });
+
+    // Keep CPU and memory readings current for adaptive bitrate feedback
+    let resource_monitor = state.resource_monitor.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(15));
+        loop {
+            interval.tick().await;
+            resource_monitor.update_metrics().await;
+        }
     });
 
     // Protected API routes
     let api_routes = Router::new()
         .route("/rooms", post(handlers::room::create_room))
         .route("/rooms", get(handlers::room::list_rooms))
+        .route(
+            "/rooms/:id",
+            get(handlers::room::get_room)
+                .patch(handlers::room::update_room)
+                .delete(handlers::room::delete_room),
+        )
+        .route(
+            "/rooms/:id/config",
+            get(handlers::room::get_room_config).put(handlers::room::update_room_config),
+        )
+        .route("/rooms/:id/close", post(handlers::room::close_room))
+        .route("/rooms/:id/archive", post(handlers::room::archive_room))
+        .route("/rooms/:id/tokens", post(handlers::room::create_room_token))
         .route("/rooms/:id/recordings", get(handlers::room::list_recordings))
+        .route("/rooms/:id/recordings/:rec_id", get(handlers::recording::download_recording))
+        .route("/rooms/:id/hls/live.m3u8", get(handlers::hls::live_playlist))
+        .route("/rooms/:id/hls/live/:file", get(handlers::hls::live_segment))
+        .route("/rooms/:id/hls/recordings/:rec_id/index.m3u8", get(handlers::hls::recording_playlist))
+        .route("/rooms/:id/dash/live.mpd", get(handlers::dash::live_manifest))
+        .route("/rooms/:id/dash/live/:file", get(handlers::hls::live_segment))
+        .route("/rooms/:id/dash/recordings/:rec_id/manifest.mpd", get(handlers::dash::recording_manifest))
+        .route("/rooms/:id/dash/recordings/:rec_id/segments/:file", get(handlers::dash::recording_segment))
+        .route("/rooms/:id/metrics", get(handlers::analytics::get_stream_metrics))
+        .route("/rooms/:id/analytics", get(handlers::analytics::get_room_analytics))
         .route("/rooms/:id/ws", get(handlers::stream::ws_handler))
         .layer(middleware::from_fn_with_state(state.clone(), require_auth));
 
@@ -93,6 +165,7 @@ async fn main() -> Result<(), AppError> {
         .route("/health", get(health_check))
         .route("/metrics", get(metrics_handler))
         .route("/api/auth/credentials", post(handlers::auth::generate_credentials))
+        .route("/api/rooms/join", post(handlers::room::join_room))
         .nest("/api", api_routes)
         .layer(TraceLayer::new_for_http())
         .layer(
@@ -127,7 +200,16 @@ async fn metrics_handler(
     
     // Add basic metrics for all rooms
     output.push_str(&format!("rooms_total {}\n", state.rooms.list_rooms("*").await?.len()));
-    
+    output.push_str(&format!("connections_active {}\n", state.connection_tracker.total_connections()));
+
+    // Viewers currently connected that have fallen behind their room's stream
+    for (room_id, lag) in state.metrics.all_lagging_viewers() {
+        let labels = format!("room=\"{}\",viewer=\"{}\"", room_id, lag.viewer_id);
+        output.push_str(&format!("viewer_lag_events{{{}}} {}\n", labels, lag.lag_events));
+        output.push_str(&format!("viewer_packets_dropped{{{}}} {}\n", labels, lag.packets_dropped));
+        output.push_str(&format!("viewer_bytes_dropped{{{}}} {}\n", labels, lag.bytes_dropped));
+    }
+
     Ok(output)
 }
 
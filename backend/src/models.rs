@@ -2,7 +2,7 @@ use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 use chrono::{DateTime, Utc};
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
 pub struct User {
     pub id: Uuid,
     pub api_key: String,
@@ -11,17 +11,53 @@ pub struct User {
     pub created_at: Option<DateTime<Utc>>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
 pub struct Room {
     pub id: Uuid,
     pub user_id: Uuid,
     pub room_id: String,
+    pub name: String,
+    pub max_participants: i32,
     pub config: Option<serde_json::Value>,
     pub created_at: Option<DateTime<Utc>>,
     pub last_active: Option<DateTime<Utc>>,
+    // RoomState::as_str
+    pub state: String,
+    pub closed_at: Option<DateTime<Utc>>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RoomState {
+    // Accepting connections
+    #[default]
+    Active,
+    // Participants were disconnected; the next connection reopens the room
+    Closed,
+    // Recordings are kept, but the room accepts no connections or changes
+    Archived,
+}
+
+impl RoomState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RoomState::Active => "active",
+            RoomState::Closed => "closed",
+            RoomState::Archived => "archived",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "active" => Some(RoomState::Active),
+            "closed" => Some(RoomState::Closed),
+            "archived" => Some(RoomState::Archived),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Recording {
     pub id: Uuid,
     pub room_id: Uuid,
@@ -29,24 +65,79 @@ pub struct Recording {
     pub end_time: Option<DateTime<Utc>>,
     pub storage_path: String,
     pub size_bytes: i64,
+    // Wall-clock length, known once the recording has ended
+    pub duration_ms: Option<i64>,
     pub frame_count: i64,
+    pub video_frames: i64,
+    pub audio_frames: i64,
+    // e.g. "vp8,opus" for WebM chunks or "jpeg,opus" for frame streams
+    pub codec: Option<String>,
     pub status: RecordingStatus,
+    // Per-track state of frame streams; empty for chunk streams
+    #[serde(default)]
+    pub tracks: Vec<TrackInfo>,
+    // Fragmented MP4 copy of an H.264/Opus recording, once the remux has finished
+    #[serde(default)]
+    pub mp4_path: Option<String>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+// One track of a recording
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TrackInfo {
+    pub track: FrameType,
+    pub codec: String,
+    pub frames: i64,
+    pub bytes: i64,
+    // Timestamps of the first and last frame in milliseconds: the publisher's for
+    // frame streams, the container's timecodes for WebM streams
+    pub first_timestamp: Option<i64>,
+    pub last_timestamp: Option<i64>,
+    // First segment of the file holding the track
+    pub storage_path: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum RecordingStatus {
     Recording,
+    Paused,
     Completed,
     Failed,
     Processing,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+impl RecordingStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RecordingStatus::Recording => "Recording",
+            RecordingStatus::Paused => "Paused",
+            RecordingStatus::Completed => "Completed",
+            RecordingStatus::Failed => "Failed",
+            RecordingStatus::Processing => "Processing",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "Recording" => Some(RecordingStatus::Recording),
+            "Paused" => Some(RecordingStatus::Paused),
+            "Completed" => Some(RecordingStatus::Completed),
+            "Failed" => Some(RecordingStatus::Failed),
+            "Processing" => Some(RecordingStatus::Processing),
+            _ => None,
+        }
+    }
+}
+
+// Request bodies may omit any field, room_id included; it is taken from the path
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+#[serde(default)]
 pub struct RoomConfig {
     pub room_id: Uuid,
     // Video settings
     pub video_codec: Option<String>,
     pub audio_codec: Option<String>,
+    // Channels of the publisher's audio frames, 1 or 2; mono when unset
+    pub audio_channels: Option<i32>,
     pub max_bitrate: Option<i32>,
     pub frame_rate: Option<i32>,
     pub resolution: Option<String>,
@@ -78,6 +169,135 @@ pub struct RoomConfig {
     // Hardware acceleration
     pub enable_hardware_acceleration: bool,   // Use hardware encoding/decoding
     pub preferred_hardware_vendor: Option<String>, // Preferred hardware vendor
+
+    // What happens when a publisher exceeds max_bitrate, frame_rate, max_buffer_size or resolution
+    #[sqlx(try_from = "String")]
+    pub limit_policy: LimitPolicy,
+
+    // How audio and video frames are laid out in the recording
+    #[sqlx(try_from = "String")]
+    pub recording_mode: RecordingMode,
+}
+
+// Matches the column defaults in room_configs
+impl Default for RoomConfig {
+    fn default() -> Self {
+        Self {
+            room_id: Uuid::nil(),
+            video_codec: None,
+            audio_codec: None,
+            audio_channels: None,
+            max_bitrate: None,
+            frame_rate: None,
+            resolution: None,
+            deduplication_enabled: true,
+            deduplication_threshold: None,
+            keyframe_interval: None,
+            adaptive_bitrate: false,
+            min_bitrate: None,
+            quality_degradation_limit: None,
+            enable_frame_batching: false,
+            batch_size: None,
+            batch_timeout_ms: None,
+            max_buffer_size: None,
+            buffer_duration_ms: None,
+            enable_error_resilience: false,
+            error_correction_level: None,
+            retry_attempts: None,
+            enable_hardware_acceleration: false,
+            preferred_hardware_vendor: None,
+            limit_policy: LimitPolicy::default(),
+            recording_mode: RecordingMode::default(),
+        }
+    }
+}
+
+impl RoomConfig {
+    // Width and height from a "1280x720" style resolution
+    pub fn resolution_dimensions(&self) -> Option<(u32, u32)> {
+        let (width, height) = self.resolution.as_deref()?.split_once('x')?;
+        Some((width.trim().parse().ok()?, height.trim().parse().ok()?))
+    }
+}
+
+// How the server reacts to a publisher exceeding its room's configured limits
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LimitPolicy {
+    // Keep everything and ask the publisher to lower its quality
+    #[default]
+    Hint,
+    // Discard frames that go over the limits
+    Drop,
+    // Close the publisher's connection
+    Disconnect,
+}
+
+impl LimitPolicy {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LimitPolicy::Hint => "hint",
+            LimitPolicy::Drop => "drop",
+            LimitPolicy::Disconnect => "disconnect",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "hint" => Some(LimitPolicy::Hint),
+            "drop" => Some(LimitPolicy::Drop),
+            "disconnect" => Some(LimitPolicy::Disconnect),
+            _ => None,
+        }
+    }
+}
+
+impl TryFrom<String> for LimitPolicy {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        LimitPolicy::parse(&value).ok_or_else(|| format!("Unknown limit policy {}", value))
+    }
+}
+
+// How a room's frame streams are stored
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RecordingMode {
+    // Audio and video interleaved in one Matroska file
+    #[default]
+    Interleaved,
+    // One Matroska file per track
+    SeparateTracks,
+    // Audio only; publishers may not send video frames
+    AudioOnly,
+}
+
+impl RecordingMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RecordingMode::Interleaved => "interleaved",
+            RecordingMode::SeparateTracks => "separate_tracks",
+            RecordingMode::AudioOnly => "audio_only",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "interleaved" => Some(RecordingMode::Interleaved),
+            "separate_tracks" => Some(RecordingMode::SeparateTracks),
+            "audio_only" => Some(RecordingMode::AudioOnly),
+            _ => None,
+        }
+    }
+}
+
+impl TryFrom<String> for RecordingMode {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        RecordingMode::parse(&value).ok_or_else(|| format!("Unknown recording mode {}", value))
+    }
 }
 
 // Request/Response structs
@@ -85,6 +305,29 @@ pub struct RoomConfig {
 pub struct CreateRoomRequest {
     pub name: String,
     pub max_participants: Option<u32>,
+    pub slow_consumer: Option<SlowConsumerPolicy>,
+    pub config: Option<RoomConfig>,
+}
+
+// PATCH /rooms/:id; absent fields are left unchanged
+#[derive(Debug, Deserialize)]
+pub struct UpdateRoomRequest {
+    pub name: Option<String>,
+    pub max_participants: Option<u32>,
+    pub slow_consumer: Option<SlowConsumerPolicy>,
+}
+
+// What happens to a viewer whose connection can't keep up with the room's stream
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "policy", rename_all = "snake_case")]
+pub enum SlowConsumerPolicy {
+    // Discard packets until the next keyframe once the viewer falls behind
+    #[default]
+    DropToKeyframe,
+    // Close the connection as soon as the viewer falls behind
+    Disconnect,
+    // Queue up to max_bytes for the viewer, then close the connection
+    Buffer { max_bytes: u64 },
 }
 
 #[derive(Debug, Serialize)]
@@ -94,14 +337,35 @@ pub struct RoomResponse {
     pub max_participants: u32,
     pub recording_enabled: bool,
     pub current_participants: u32,
+    pub publishers: u32,
+    pub viewers: u32,
+    pub slow_consumer: SlowConsumerPolicy,
+    pub state: RoomState,
+    // When the room was created
     pub start_time: DateTime<Utc>,
+    // When the room was last closed or archived, while it stays that way
     pub end_time: Option<DateTime<Utc>>,
+    pub last_active: Option<DateTime<Utc>>,
+}
+
+// Declared when connecting to a room's WebSocket, viewer when unset
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ParticipantRole {
+    // Sends frames that are recorded and fanned out
+    Publisher,
+    // Receives the live stream; may not send frames or controls
+    #[default]
+    Viewer,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct JoinRoomRequest {
     pub room_id: String,
     pub api_key: String,
+    // Room token from the room's creator, needed to join someone else's room
+    #[serde(default)]
+    pub invite: Option<String>,
 }
 
 #[derive(Debug, Serialize)]
@@ -110,6 +374,17 @@ pub struct JoinRoomResponse {
     pub config: Option<RoomConfig>,
 }
 
+#[derive(Debug, Deserialize)]
+pub struct CreateRoomTokenRequest {
+    // What the token may connect as: publisher for devices, viewer for players
+    pub role: ParticipantRole,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RoomTokenResponse {
+    pub token: String,
+}
+
 #[derive(Debug, Serialize)]
 pub struct RecordingListResponse {
     pub recordings: Vec<Recording>,
@@ -117,9 +392,12 @@ pub struct RecordingListResponse {
 
 // WebSocket message types
 #[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type")]
 pub enum WebSocketMessage {
     Frame(Frame),
-    Control(ControlAction),
+    // Several frames sent together; each keeps its own timestamp and track
+    Batch { frames: Vec<Frame> },
+    Control { action: ControlAction },
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -129,13 +407,22 @@ pub struct Frame {
     pub data: Vec<u8>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum FrameType {
     Video,
     Audio,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+impl FrameType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FrameType::Video => "video",
+            FrameType::Audio => "audio",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum ControlAction {
     StartRecording,
     StopRecording,
@@ -143,6 +430,112 @@ pub enum ControlAction {
     ResumeRecording,
 }
 
+// Stored in place of a duplicate frame's bytes
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FrameReference {
+    pub timestamp: i64,
+    pub reference_timestamp: i64,
+    pub frame_type: FrameType,
+}
+
+// A cluster of a recording file that playback can start from
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct KeyframeEntry {
+    // Index into the file's segments
+    pub segment: u32,
+    // Matroska track number of the keyframe
+    pub track: u64,
+    // Timecode of the cluster's first keyframe, in milliseconds
+    pub time_ms: i64,
+    // Byte offset of the Cluster element within the segment
+    pub offset: u64,
+}
+
+// Where the fragments of a recording's MP4 copy are, stored next to it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FragmentIndex {
+    // Length of the ftyp and moov at the start of the file
+    pub init_size: u64,
+    // Codec strings of its tracks, e.g. "avc1.42c01e,opus"
+    #[serde(default)]
+    pub codecs: Option<String>,
+    #[serde(default)]
+    pub video_size: Option<(u32, u32)>,
+    pub fragments: Vec<FragmentEntry>,
+}
+
+// One moof/mdat pair of an MP4 copy
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct FragmentEntry {
+    pub offset: u64,
+    pub size: u64,
+    // Timecode of its first sample and how long it plays, in milliseconds
+    pub start_ms: i64,
+    pub duration_ms: i64,
+}
+
+// Server-to-client WebSocket replies
+#[derive(Debug, Serialize)]
+#[serde(tag = "type")]
+pub enum ServerMessage {
+    Ack {
+        action: ControlAction,
+        recording_id: Option<Uuid>,
+    },
+    Error {
+        code: ErrorCode,
+        message: String,
+    },
+    // The publisher went over one of the room's limits; resolution is measured in pixels per frame
+    LimitExceeded {
+        limit: IngestLimit,
+        measured: f64,
+        allowed: f64,
+        policy: LimitPolicy,
+    },
+    // Adaptive bitrate feedback: encode at this bitrate (bits/s) and, when the room sets a frame rate, fps
+    TargetQuality {
+        bitrate: u32,
+        fps: Option<u32>,
+        reason: FeedbackReason,
+    },
+    // Send a keyframe as soon as possible
+    RequestKeyframe {
+        reason: FeedbackReason,
+    },
+}
+
+// Why the server is asking a publisher to change its encoding
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FeedbackReason {
+    // Viewers are falling behind the live stream
+    ViewerLag,
+    // Server CPU or memory is critical
+    ServerLoad,
+    // Pressure has cleared and quality is being restored
+    Recovered,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IngestLimit {
+    Bitrate,
+    FrameRate,
+    BufferSize,
+    Resolution,
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub enum ErrorCode {
+    InvalidMessage,
+    InvalidState,
+    // The connection's role doesn't allow the message, e.g. a viewer sending frames
+    Forbidden,
+    StorageFailure,
+    Internal,
+}
+
 // Analytics models
 #[derive(Debug, Serialize, Deserialize)]
 pub struct StreamMetrics {
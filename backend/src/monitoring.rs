@@ -6,6 +6,8 @@
  * - MetricsStore for collecting application-wide metrics
  * - ResourceMonitor for tracking system resources (CPU, memory)
  * - ConnectionTracker for managing active WebSocket connections
+ * - Per-viewer lag tracking for slow-consumer handling
+ * - Publisher ingest rates and room limit enforcement counters
  * - Performance metrics collection and reporting
  * - Garbage collection monitoring
  */
@@ -18,9 +20,13 @@ use std::{
 };
 use tokio::sync::{RwLock as TokioRwLock, Mutex};
 use metrics::{counter, gauge, histogram};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use uuid::Uuid;
 use crate::{
     error::AppError,
     logging::log_performance_metrics,
+    models::StreamMetrics,
 };
 use tracing::{info, warn};
 
@@ -36,6 +42,10 @@ pub struct MetricsStore {
     requests: Arc<RwLock<HashMap<String, u64>>>,
     errors: Arc<RwLock<HashMap<String, u64>>>,
     latencies: Arc<RwLock<HashMap<String, Vec<f64>>>>,
+    // Room id -> connected viewers that have fallen behind at least once
+    viewer_lag: Arc<RwLock<HashMap<String, HashMap<Uuid, ViewerLag>>>>,
+    // Room id -> publisher's current (bitrate in bits/s, video fps)
+    ingest_rates: Arc<RwLock<HashMap<String, (f64, f64)>>>,
 }
 
 impl MetricsStore {
@@ -44,6 +54,8 @@ impl MetricsStore {
             requests: Arc::new(RwLock::new(HashMap::new())),
             errors: Arc::new(RwLock::new(HashMap::new())),
             latencies: Arc::new(RwLock::new(HashMap::new())),
+            viewer_lag: Arc::new(RwLock::new(HashMap::new())),
+            ingest_rates: Arc::new(RwLock::new(HashMap::new())),
         }
     }
 
@@ -110,11 +122,104 @@ impl MetricsStore {
         *requests.entry(format!("frames_{}", room_id)).or_insert(0) += frames;
     }
 
+    pub fn record_deduplicated(&self, room_id: String, frames: u64) {
+        let mut requests = self.requests.write().unwrap();
+        *requests.entry(format!("dedup_{}", room_id)).or_insert(0) += frames;
+    }
+
     pub fn record_errors(&self, room_id: String, count: u64) {
         let mut errors = self.errors.write().unwrap();
         *errors.entry(room_id).or_insert(0) += count;
     }
 
+    // A viewer fell behind the room's stream
+    pub fn record_viewer_lag(&self, room_id: &str, viewer_id: Uuid) {
+        {
+            let mut requests = self.requests.write().unwrap();
+            *requests.entry(format!("lag_{}", room_id)).or_insert(0) += 1;
+        }
+        let mut viewer_lag = self.viewer_lag.write().unwrap();
+        let lag = viewer_lag.entry(room_id.to_string())
+            .or_default()
+            .entry(viewer_id)
+            .or_insert_with(|| ViewerLag::new(viewer_id));
+        lag.lag_events += 1;
+        lag.last_lag = Utc::now();
+    }
+
+    // Packets a lagging viewer never received
+    pub fn record_viewer_dropped(&self, room_id: &str, viewer_id: Uuid, packets: u64, bytes: u64) {
+        {
+            let mut requests = self.requests.write().unwrap();
+            *requests.entry(format!("lag_dropped_{}", room_id)).or_insert(0) += packets;
+        }
+        let mut viewer_lag = self.viewer_lag.write().unwrap();
+        let lag = viewer_lag.entry(room_id.to_string())
+            .or_default()
+            .entry(viewer_id)
+            .or_insert_with(|| ViewerLag::new(viewer_id));
+        lag.packets_dropped += packets;
+        lag.bytes_dropped += bytes;
+    }
+
+    pub fn record_slow_consumer_disconnect(&self, room_id: &str) {
+        let mut requests = self.requests.write().unwrap();
+        *requests.entry(format!("slow_disconnects_{}", room_id)).or_insert(0) += 1;
+    }
+
+    pub fn record_ingest_rate(&self, room_id: &str, bitrate: f64, fps: f64) {
+        let mut ingest_rates = self.ingest_rates.write().unwrap();
+        ingest_rates.insert(room_id.to_string(), (bitrate, fps));
+    }
+
+    // Bitrate and video fps of the room's publisher, if one is connected
+    pub fn get_ingest_rate(&self, room_id: &str) -> Option<(f64, f64)> {
+        self.ingest_rates.read().unwrap().get(room_id).copied()
+    }
+
+    // The room's publisher disconnected
+    pub fn clear_ingest_rate(&self, room_id: &str) {
+        self.ingest_rates.write().unwrap().remove(room_id);
+    }
+
+    // The publisher went over a room limit and was hinted or disconnected
+    pub fn record_limit_exceeded(&self, room_id: &str) {
+        let mut requests = self.requests.write().unwrap();
+        *requests.entry(format!("limit_{}", room_id)).or_insert(0) += 1;
+    }
+
+    // A publisher frame was discarded for going over a room limit
+    pub fn record_limit_dropped(&self, room_id: &str) {
+        let mut requests = self.requests.write().unwrap();
+        *requests.entry(format!("limit_dropped_{}", room_id)).or_insert(0) += 1;
+    }
+
+    // Per-viewer lag is only kept while the viewer is connected
+    pub fn remove_viewer(&self, room_id: &str, viewer_id: Uuid) {
+        let mut viewer_lag = self.viewer_lag.write().unwrap();
+        if let Some(viewers) = viewer_lag.get_mut(room_id) {
+            viewers.remove(&viewer_id);
+            if viewers.is_empty() {
+                viewer_lag.remove(room_id);
+            }
+        }
+    }
+
+    pub fn get_lagging_viewers(&self, room_id: &str) -> Vec<ViewerLag> {
+        let viewer_lag = self.viewer_lag.read().unwrap();
+        viewer_lag.get(room_id)
+            .map(|viewers| viewers.values().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    // Room id and lag for every connected viewer that has fallen behind
+    pub fn all_lagging_viewers(&self) -> Vec<(String, ViewerLag)> {
+        let viewer_lag = self.viewer_lag.read().unwrap();
+        viewer_lag.iter()
+            .flat_map(|(room_id, viewers)| viewers.values().map(move |lag| (room_id.clone(), lag.clone())))
+            .collect()
+    }
+
     pub async fn get_room_metrics(&self, room_id: &str) -> Result<RoomMetrics, AppError> {
         let requests = self.requests.read().unwrap();
         let errors = self.errors.read().unwrap();
@@ -123,6 +228,12 @@ impl MetricsStore {
         Ok(RoomMetrics {
             bytes_transferred: *requests.get(&format!("bytes_{}", room_id)).unwrap_or(&0),
             frames_processed: *requests.get(&format!("frames_{}", room_id)).unwrap_or(&0),
+            frames_deduplicated: *requests.get(&format!("dedup_{}", room_id)).unwrap_or(&0),
+            viewer_lag_events: *requests.get(&format!("lag_{}", room_id)).unwrap_or(&0),
+            viewer_packets_dropped: *requests.get(&format!("lag_dropped_{}", room_id)).unwrap_or(&0),
+            slow_consumer_disconnects: *requests.get(&format!("slow_disconnects_{}", room_id)).unwrap_or(&0),
+            limit_violations: *requests.get(&format!("limit_{}", room_id)).unwrap_or(&0),
+            frames_dropped_over_limit: *requests.get(&format!("limit_dropped_{}", room_id)).unwrap_or(&0),
             error_rate: errors.get(room_id).copied().unwrap_or(0) as f64,
             avg_latency: latencies.get(room_id)
                 .map(|v| v.iter().sum::<f64>() / v.len() as f64)
@@ -130,6 +241,27 @@ impl MetricsStore {
         })
     }
 
+    pub async fn get_stream_metrics(&self, room_id: &str) -> Result<StreamMetrics, AppError> {
+        let metrics = self.get_room_metrics(room_id).await?;
+        let room_uuid = Uuid::parse_str(room_id)
+            .map_err(|_| AppError::NotFound(format!("Room {} not found", room_id)))?;
+        let (bitrate, fps) = self.ingest_rates.read().unwrap()
+            .get(room_id)
+            .copied()
+            .unwrap_or_default();
+
+        Ok(StreamMetrics {
+            room_id: room_uuid,
+            timestamp: Utc::now(),
+            bytes_transferred: metrics.bytes_transferred as i64,
+            frames_processed: metrics.frames_processed as i64,
+            frames_deduplicated: metrics.frames_deduplicated as i64,
+            current_bitrate: bitrate as i32,
+            current_fps: fps as f32,
+            peak_memory_mb: (PEAK_MEMORY_USAGE.load(Ordering::Relaxed) / (1024 * 1024)) as i32,
+        })
+    }
+
     pub async fn get_user_metrics(&self) -> Result<UserMetrics, AppError> {
         let requests = self.requests.read().unwrap();
         
@@ -153,10 +285,48 @@ impl MetricsStore {
 pub struct RoomMetrics {
     pub bytes_transferred: u64,
     pub frames_processed: u64,
+    pub frames_deduplicated: u64,
+    pub viewer_lag_events: u64,
+    pub viewer_packets_dropped: u64,
+    pub slow_consumer_disconnects: u64,
+    pub limit_violations: u64,
+    pub frames_dropped_over_limit: u64,
     pub error_rate: f64,
     pub avg_latency: f64,
 }
 
+impl RoomMetrics {
+    // Share of processed frames stored as references instead of bytes
+    pub fn deduplication_ratio(&self) -> f32 {
+        if self.frames_processed == 0 {
+            0.0
+        } else {
+            self.frames_deduplicated as f32 / self.frames_processed as f32
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ViewerLag {
+    pub viewer_id: Uuid,
+    pub lag_events: u64,
+    pub packets_dropped: u64,
+    pub bytes_dropped: u64,
+    pub last_lag: DateTime<Utc>,
+}
+
+impl ViewerLag {
+    fn new(viewer_id: Uuid) -> Self {
+        Self {
+            viewer_id,
+            lag_events: 0,
+            packets_dropped: 0,
+            bytes_dropped: 0,
+            last_lag: Utc::now(),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct UserMetrics {
     pub total_rooms: u64,
@@ -187,16 +357,15 @@ impl ResourceMonitor {
         // Update memory usage
         if let Ok(memory) = sys_info::mem_info() {
             let total = memory.total;
-            let free = memory.free;
+            // Page cache counts as free; `free` alone would read as nearly full on Linux
+            let free = memory.avail.max(memory.free).min(total);
             let used = total - free;
             let usage_percent = (used as f64 / total as f64) * 100.0;
             *self.memory_usage.write().unwrap() = usage_percent as u64;
         }
 
-        // Update CPU usage
-        if let Ok(cpu) = sys_info::loadavg() {
-            *self.cpu_usage.write().unwrap() = cpu.one;
-        }
+        // Update CPU usage, as load per core in percent like the threshold
+        *self.cpu_usage.write().unwrap() = get_cpu_usage();
     }
 
     pub async fn is_memory_critical(&self) -> bool {
@@ -224,42 +393,68 @@ impl ResourceMonitor {
 #[derive(Clone)]
 pub struct ConnectionTracker {
     connections: Arc<RwLock<HashMap<String, u32>>>,
+    // Across all rooms
     max_connections: Arc<RwLock<u32>>,
+    // Publishers and viewers in a single room
+    max_room_connections: Arc<RwLock<u32>>,
 }
 
 impl ConnectionTracker {
     pub fn new() -> Self {
-        Self {
-            connections: Arc::new(RwLock::new(HashMap::new())),
-            max_connections: Arc::new(RwLock::new(100)), // Default max connections
-        }
+        Self::with_limits(100, 100) // Default max connections
     }
 
     pub fn with_max_connections(max: u32) -> Self {
+        Self::with_limits(max, max)
+    }
+
+    pub fn with_limits(max_connections: u32, max_room_connections: u32) -> Self {
         Self {
             connections: Arc::new(RwLock::new(HashMap::new())),
-            max_connections: Arc::new(RwLock::new(max)),
+            max_connections: Arc::new(RwLock::new(max_connections)),
+            max_room_connections: Arc::new(RwLock::new(max_room_connections)),
         }
     }
 
     pub async fn check_limits(&self, room_id: &str) -> bool {
         let connections = self.connections.read().unwrap();
-        let count = connections.get(room_id).copied().unwrap_or(0);
-        let max = *self.max_connections.read().unwrap();
-        
-        count < max
+        self.limit_error(&connections, room_id).is_none()
     }
 
-    pub async fn add_connection(&self, room_id: &str) {
+    fn limit_error(&self, connections: &HashMap<String, u32>, room_id: &str) -> Option<AppError> {
+        let total: u32 = connections.values().sum();
+        let in_room = connections.get(room_id).copied().unwrap_or(0);
+
+        if total >= *self.max_connections.read().unwrap() {
+            return Some(AppError::TooManyConnections("Server connection limit reached".to_string()));
+        }
+        if in_room >= *self.max_room_connections.read().unwrap() {
+            return Some(AppError::TooManyConnections(format!("Room {} connection limit reached", room_id)));
+        }
+        None
+    }
+
+    // Take a connection slot if both the global and the room limit allow it.
+    // The slot is freed when the returned guard is dropped.
+    pub async fn add_connection(&self, room_id: &str) -> Result<ConnectionSlot, AppError> {
         let mut connections = self.connections.write().unwrap();
+        if let Some(err) = self.limit_error(&connections, room_id) {
+            return Err(err);
+        }
         *connections.entry(room_id.to_string()).or_insert(0) += 1;
+
+        Ok(ConnectionSlot {
+            tracker: self.clone(),
+            room_id: room_id.to_string(),
+        })
     }
 
-    pub async fn remove_connection(&self, room_id: &str) {
+    fn remove_connection(&self, room_id: &str) {
         let mut connections = self.connections.write().unwrap();
         if let Some(count) = connections.get_mut(room_id) {
-            if *count > 0 {
-                *count -= 1;
+            *count = count.saturating_sub(1);
+            if *count == 0 {
+                connections.remove(room_id);
             }
         }
     }
@@ -268,6 +463,26 @@ impl ConnectionTracker {
         let connections = self.connections.read().unwrap();
         connections.get(room_id).copied().unwrap_or(0)
     }
+
+    pub fn total_connections(&self) -> u32 {
+        self.connections.read().unwrap().values().sum()
+    }
+
+    pub fn max_room_connections(&self) -> u32 {
+        *self.max_room_connections.read().unwrap()
+    }
+}
+
+// A connection counted against the limits; dropping it releases the slot
+pub struct ConnectionSlot {
+    tracker: ConnectionTracker,
+    room_id: String,
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.tracker.remove_connection(&self.room_id);
+    }
 }
 
 // Garbage collection monitoring
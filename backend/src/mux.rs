@@ -0,0 +1,338 @@
+/*
+ * mux.rs
+ * Purpose: Matroska muxing for recordings made from frames
+ *
+ * This file contains:
+ * - EBML element and size encoding
+ * - MuxTrack, a track's codec and parameters as declared in the file header
+ * - MatroskaMuxer, which turns timestamped frames into a Matroska stream:
+ *   a header at the start of every file, then SimpleBlocks grouped into clusters
+ *
+ * Output is written as frames arrive, so the Segment and Clusters have unknown
+ * sizes, the same way MediaRecorder writes WebM. Timecodes are milliseconds
+ * since the recording's first frame.
+ *
+ * H.264 arrives in Annex B and is stored length-prefixed, with an avcC built
+ * from the stream's SPS and PPS as CodecPrivate. The header waits for them:
+ * audio is held until then, and video before them can't be decoded anyway.
+ */
+
+use std::collections::VecDeque;
+use crate::{
+    ingest::jpeg_dimensions,
+    models::{FrameType, RoomConfig},
+    remux::{annexb_nals, avc_config},
+    webm::{
+        self, element, float_element, id_bytes, uint_element, AUDIO_ID, BIT_DEPTH_ID, CHANNELS_ID, CLUSTER_ID,
+        CODEC_ID_ID, CODEC_PRIVATE_ID, DOC_TYPE_ID, DOC_TYPE_READ_VERSION_ID, DOC_TYPE_VERSION_ID, EBML_HEADER_ID,
+        EBML_MAX_ID_LENGTH_ID, EBML_MAX_SIZE_LENGTH_ID, EBML_READ_VERSION_ID, EBML_VERSION_ID, FLAG_LACING_ID,
+        INFO_ID, MUXING_APP_ID, PIXEL_HEIGHT_ID, PIXEL_WIDTH_ID, SAMPLING_FREQUENCY_ID, SEGMENT_ID, SIMPLE_BLOCK_ID,
+        TIMECODE_ID, TIMECODE_SCALE_ID, TRACKS_ID, TRACK_ENTRY_ID, TRACK_NUMBER_ID, TRACK_TYPE_AUDIO,
+        TRACK_TYPE_VIDEO, TRACK_TYPE_ID, TRACK_UID_ID, VIDEO_ID, WRITING_APP_ID,
+    },
+};
+
+pub const DEFAULT_VIDEO_CODEC: &str = "jpeg";
+pub const DEFAULT_AUDIO_CODEC: &str = "opus";
+
+// Frames carry no audio parameters; voice is the common case when the room
+// config doesn't declare a channel count
+const AUDIO_SAMPLE_RATE: u32 = 48_000;
+const AUDIO_CHANNELS: u8 = 1;
+const PCM_BIT_DEPTH: u64 = 16;
+
+// Audio held while the header waits for H.264 parameter sets; past this the
+// oldest is dropped
+const MAX_HELD_BYTES: usize = 1024 * 1024;
+
+// A new cluster is started at the first video keyframe past this age
+const CLUSTER_DURATION_MS: i64 = 5_000;
+
+// Unknown-size marker for an 8-byte EBML size
+const UNKNOWN_SIZE: [u8; 8] = [0x01, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF];
+
+const APP_NAME: &str = "stream-recorder";
+
+// A track as declared in the file header
+#[derive(Debug, Clone)]
+pub struct MuxTrack {
+    pub track: FrameType,
+    // Short codec name as used in room configs, e.g. "jpeg" or "opus"
+    pub codec: String,
+    // Video width and height, when known before the first frame
+    pub size: Option<(u32, u32)>,
+    // Audio channel count
+    pub channels: u8,
+    // avcC of H.264 video, once a frame carried the SPS and PPS
+    pub avc_config: Option<Vec<u8>>,
+}
+
+impl MuxTrack {
+    // Unknown or unset codecs fall back to JPEG video and Opus audio
+    pub fn new(track: FrameType, codec: Option<&str>, size: Option<(u32, u32)>) -> Self {
+        let codec = codec
+            .map(|codec| codec.to_ascii_lowercase())
+            .filter(|codec| codec_id(track, codec).is_some())
+            .unwrap_or_else(|| match track {
+                FrameType::Video => DEFAULT_VIDEO_CODEC.to_string(),
+                FrameType::Audio => DEFAULT_AUDIO_CODEC.to_string(),
+            });
+        Self { track, codec, size, channels: AUDIO_CHANNELS, avc_config: None }
+    }
+
+    // The track as the room config declares it
+    pub fn for_room(track: FrameType, config: &RoomConfig) -> Self {
+        match track {
+            FrameType::Video => Self::new(track, config.video_codec.as_deref(), config.resolution_dimensions()),
+            FrameType::Audio => Self {
+                channels: config.audio_channels.map_or(AUDIO_CHANNELS, |channels| channels.clamp(1, 2) as u8),
+                ..Self::new(track, config.audio_codec.as_deref(), None)
+            },
+        }
+    }
+
+    fn is_h264(&self) -> bool {
+        self.track == FrameType::Video && self.codec == "h264"
+    }
+}
+
+pub struct MatroskaMuxer {
+    tracks: Vec<MuxTrack>,
+    header_written: bool,
+    // Timecode of the open cluster
+    cluster: Option<i64>,
+    // Audio frames (track index, timecode, data) waiting for the header
+    held: VecDeque<(usize, i64, Vec<u8>)>,
+    held_bytes: usize,
+}
+
+impl MatroskaMuxer {
+    pub fn new(tracks: Vec<MuxTrack>) -> Self {
+        Self {
+            tracks,
+            header_written: false,
+            cluster: None,
+            held: VecDeque::new(),
+            held_bytes: 0,
+        }
+    }
+
+    pub fn tracks(&self) -> &[MuxTrack] {
+        &self.tracks
+    }
+
+    // Start over in a new file: the next frame gets a fresh header and cluster
+    pub fn restart(&mut self) {
+        self.header_written = false;
+        self.cluster = None;
+    }
+
+    // Append one frame to `out`, timed relative to `origin`; false if the frame's
+    // track isn't part of this file
+    pub fn write_frame(&mut self, track: FrameType, timestamp: i64, data: &[u8], origin: i64, out: &mut Vec<u8>) -> bool {
+        let Some(index) = self.tracks.iter().position(|t| t.track == track) else {
+            return false;
+        };
+        let timecode = timestamp.saturating_sub(origin).max(0);
+        let keyframe = is_keyframe(&self.tracks[index], data);
+
+        let length_prefixed;
+        let data = if self.tracks[index].is_h264() {
+            if self.tracks[index].avc_config.is_none() {
+                self.tracks[index].avc_config = parameter_sets(data);
+            }
+            length_prefixed = to_length_prefixed(data);
+            length_prefixed.as_slice()
+        } else {
+            data
+        };
+
+        if !self.header_written {
+            if self.tracks.iter().any(|t| t.is_h264() && t.avc_config.is_none()) {
+                if track == FrameType::Audio {
+                    self.hold(index, timecode, data);
+                }
+                return true;
+            }
+            // The first JPEG frame gives the video size if the room config didn't
+            if let Some(video) = self.tracks.iter_mut().find(|t| t.track == FrameType::Video && t.size.is_none()) {
+                if track == FrameType::Video && video.codec == "jpeg" {
+                    video.size = jpeg_dimensions(data);
+                }
+            }
+            out.extend_from_slice(&self.header());
+            self.header_written = true;
+            self.cluster = None;
+
+            self.held_bytes = 0;
+            for (held_index, held_timecode, held_data) in std::mem::take(&mut self.held) {
+                self.write_block(held_index, held_timecode, true, &held_data, out);
+            }
+        }
+
+        if !data.is_empty() {
+            self.write_block(index, timecode, keyframe, data, out);
+        }
+        true
+    }
+
+    fn hold(&mut self, index: usize, timecode: i64, data: &[u8]) {
+        self.held.push_back((index, timecode, data.to_vec()));
+        self.held_bytes += data.len();
+        while self.held_bytes > MAX_HELD_BYTES {
+            let Some((_, _, dropped)) = self.held.pop_front() else {
+                break;
+            };
+            self.held_bytes -= dropped.len();
+        }
+    }
+
+    fn write_block(&mut self, index: usize, timecode: i64, keyframe: bool, data: &[u8], out: &mut Vec<u8>) {
+        let track = self.tracks[index].track;
+        let has_video = self.tracks.iter().any(|t| t.track == FrameType::Video);
+
+        // Clusters start on video keyframes so players can seek to them
+        let new_cluster = match self.cluster {
+            None => true,
+            Some(cluster) => {
+                let relative = timecode - cluster;
+                relative < i64::from(i16::MIN)
+                    || relative > i64::from(i16::MAX)
+                    || (relative >= CLUSTER_DURATION_MS && (!has_video || (track == FrameType::Video && keyframe)))
+            }
+        };
+        if new_cluster {
+            out.extend_from_slice(&id_bytes(CLUSTER_ID));
+            out.extend_from_slice(&UNKNOWN_SIZE);
+            out.extend_from_slice(&uint_element(TIMECODE_ID, timecode as u64));
+            self.cluster = Some(timecode);
+        }
+        let relative = (timecode - self.cluster.unwrap_or(timecode)) as i16;
+
+        // Track numbers are 1-based and below 127, so they fit a one-byte vint
+        let mut block = Vec::with_capacity(4 + data.len());
+        block.push(0x80 | (index as u8 + 1));
+        block.extend_from_slice(&relative.to_be_bytes());
+        block.push(if keyframe { 0x80 } else { 0x00 });
+        block.extend_from_slice(data);
+        out.extend_from_slice(&element(SIMPLE_BLOCK_ID, &block));
+    }
+
+    fn header(&self) -> Vec<u8> {
+        let webm = self.tracks.iter().all(|t| matches!(t.codec.as_str(), "vp8" | "vp9" | "av1" | "opus" | "vorbis"));
+        let doc_type = if webm { "webm" } else { "matroska" };
+
+        let mut ebml = Vec::new();
+        ebml.extend_from_slice(&uint_element(EBML_VERSION_ID, 1));
+        ebml.extend_from_slice(&uint_element(EBML_READ_VERSION_ID, 1));
+        ebml.extend_from_slice(&uint_element(EBML_MAX_ID_LENGTH_ID, 4));
+        ebml.extend_from_slice(&uint_element(EBML_MAX_SIZE_LENGTH_ID, 8));
+        ebml.extend_from_slice(&element(DOC_TYPE_ID, doc_type.as_bytes()));
+        ebml.extend_from_slice(&uint_element(DOC_TYPE_VERSION_ID, 4));
+        ebml.extend_from_slice(&uint_element(DOC_TYPE_READ_VERSION_ID, 2));
+
+        let mut info = Vec::new();
+        info.extend_from_slice(&uint_element(TIMECODE_SCALE_ID, 1_000_000));
+        info.extend_from_slice(&element(MUXING_APP_ID, APP_NAME.as_bytes()));
+        info.extend_from_slice(&element(WRITING_APP_ID, APP_NAME.as_bytes()));
+
+        let mut tracks = Vec::new();
+        for (index, track) in self.tracks.iter().enumerate() {
+            tracks.extend_from_slice(&element(TRACK_ENTRY_ID, &track_entry(index as u64 + 1, track)));
+        }
+
+        let mut out = element(EBML_HEADER_ID, &ebml);
+        out.extend_from_slice(&id_bytes(SEGMENT_ID));
+        out.extend_from_slice(&UNKNOWN_SIZE);
+        out.extend_from_slice(&element(INFO_ID, &info));
+        out.extend_from_slice(&element(TRACKS_ID, &tracks));
+        out
+    }
+}
+
+fn track_entry(number: u64, track: &MuxTrack) -> Vec<u8> {
+    let mut entry = Vec::new();
+    entry.extend_from_slice(&uint_element(TRACK_NUMBER_ID, number));
+    entry.extend_from_slice(&uint_element(TRACK_UID_ID, number));
+    entry.extend_from_slice(&element(CODEC_ID_ID, codec_id(track.track, &track.codec).unwrap_or_default().as_bytes()));
+    entry.extend_from_slice(&uint_element(FLAG_LACING_ID, 0));
+
+    match track.track {
+        FrameType::Video => {
+            entry.extend_from_slice(&uint_element(TRACK_TYPE_ID, TRACK_TYPE_VIDEO));
+            if let Some(config) = &track.avc_config {
+                entry.extend_from_slice(&element(CODEC_PRIVATE_ID, config));
+            }
+            if let Some((width, height)) = track.size {
+                let mut video = uint_element(PIXEL_WIDTH_ID, u64::from(width));
+                video.extend_from_slice(&uint_element(PIXEL_HEIGHT_ID, u64::from(height)));
+                entry.extend_from_slice(&element(VIDEO_ID, &video));
+            }
+        }
+        FrameType::Audio => {
+            entry.extend_from_slice(&uint_element(TRACK_TYPE_ID, TRACK_TYPE_AUDIO));
+            if track.codec == "opus" {
+                entry.extend_from_slice(&element(CODEC_PRIVATE_ID, &opus_head(track.channels)));
+            }
+            let mut audio = float_element(SAMPLING_FREQUENCY_ID, f64::from(AUDIO_SAMPLE_RATE));
+            audio.extend_from_slice(&uint_element(CHANNELS_ID, u64::from(track.channels)));
+            if track.codec == "pcm" {
+                audio.extend_from_slice(&uint_element(BIT_DEPTH_ID, PCM_BIT_DEPTH));
+            }
+            entry.extend_from_slice(&element(AUDIO_ID, &audio));
+        }
+    }
+    entry
+}
+
+// Matroska CodecID for a room config codec name, if it suits the track
+fn codec_id(track: FrameType, codec: &str) -> Option<&'static str> {
+    let prefix = match track {
+        FrameType::Video => "V_",
+        FrameType::Audio => "A_",
+    };
+    webm::codec_id(codec).filter(|id| id.starts_with(prefix))
+}
+
+// Identification header Opus decoders need before the first packet; mono and
+// stereo use channel mapping family 0, which needs no mapping table
+pub fn opus_head(channels: u8) -> Vec<u8> {
+    let mut head = b"OpusHead".to_vec();
+    head.push(1);
+    head.push(channels);
+    head.extend_from_slice(&0u16.to_le_bytes());
+    head.extend_from_slice(&AUDIO_SAMPLE_RATE.to_le_bytes());
+    head.extend_from_slice(&0i16.to_le_bytes());
+    head.push(0);
+    head
+}
+
+// Audio frames and JPEGs are always keyframes; other video is checked where cheap
+fn is_keyframe(track: &MuxTrack, data: &[u8]) -> bool {
+    match (track.track, track.codec.as_str()) {
+        (FrameType::Audio, _) | (FrameType::Video, "jpeg") => true,
+        // VP8 frame tag: the low bit is 0 for key frames
+        (FrameType::Video, "vp8") => data.first().is_some_and(|b| b & 0x01 == 0),
+        // Annex B stream containing an IDR slice
+        (FrameType::Video, "h264") => data.windows(4).any(|w| w[..3] == [0, 0, 1] && w[3] & 0x1F == 5),
+        _ => false,
+    }
+}
+
+// avcC from an Annex B frame carrying both an SPS and a PPS
+fn parameter_sets(data: &[u8]) -> Option<Vec<u8>> {
+    let nals = annexb_nals(data);
+    let sps = nals.iter().find(|nal| nal[0] & 0x1F == 7)?;
+    let pps = nals.iter().find(|nal| nal[0] & 0x1F == 8)?;
+    avc_config(sps, pps)
+}
+
+// Annex B start codes replaced with 4-byte lengths, as the avcC declares
+fn to_length_prefixed(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len() + 16);
+    for nal in annexb_nals(data) {
+        out.extend_from_slice(&(nal.len() as u32).to_be_bytes());
+        out.extend_from_slice(nal);
+    }
+    out
+}
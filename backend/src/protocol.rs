@@ -0,0 +1,373 @@
+/*
+ * protocol.rs
+ * Purpose: WebSocket subprotocol negotiation and binary framing
+ *
+ * This file contains:
+ * - Subprotocol names offered to clients
+ * - The versioned binary envelope used by constrained publishers
+ * - Conversion between envelopes and WebSocketMessage
+ * - Application close codes
+ *
+ * Binary envelope layout (all integers big-endian):
+ *
+ *   offset  size  field
+ *   0       1     version (currently 1)
+ *   1       1     message type (1 = Frame, 2 = Control, 3 = Batch)
+ *   2       1     track (0 = Video, 1 = Audio)
+ *   3       1     reserved, must be 0
+ *   4       4     sequence number (u32, wraps)
+ *   8       8     timestamp in milliseconds (i64)
+ *   16      4     payload length (u32)
+ *   20      n     payload
+ *
+ * Control envelopes carry a single payload byte with the action code.
+ *
+ * Batch envelopes carry several frames back to back. The header's track is
+ * ignored and its timestamp is the base for the entries, each of which is:
+ *
+ *   offset  size  field
+ *   0       1     track (0 = Video, 1 = Audio)
+ *   1       4     timestamp offset from the header's, in milliseconds (i32)
+ *   5       4     frame length (u32)
+ *   9       n     frame data
+ */
+
+use axum::extract::ws::WebSocket;
+use crate::{
+    error::AppError,
+    models::{ControlAction, Frame, FrameType, WebSocketMessage},
+};
+
+pub const JSON_PROTOCOL: &str = "stream-recorder.json";
+pub const BINARY_PROTOCOL: &str = "stream-recorder.binary.v1";
+
+// Offered in order of preference
+pub const SUPPORTED_PROTOCOLS: [&str; 2] = [BINARY_PROTOCOL, JSON_PROTOCOL];
+
+// Close code sent to a viewer disconnected by its room's slow-consumer policy
+pub const CLOSE_SLOW_CONSUMER: u16 = 4008;
+
+// Close code sent to every participant when the room is closed, archived or deleted
+pub const CLOSE_ROOM_CLOSED: u16 = 4009;
+
+// Close code sent to a publisher that exceeded its room's limits under the disconnect policy
+pub const CLOSE_LIMIT_EXCEEDED: u16 = 4010;
+
+// Close code sent to a publisher whose WebM stream turned out to be corrupt
+pub const CLOSE_INVALID_MEDIA: u16 = 4011;
+
+pub const BINARY_VERSION: u8 = 1;
+pub const HEADER_LEN: usize = 20;
+pub const BATCH_ENTRY_HEADER_LEN: usize = 9;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    // No subprotocol negotiated: binary messages are opaque media chunks
+    Raw,
+    // JSON WebSocketMessage over text, opaque chunks over binary
+    Json,
+    // Binary envelopes over binary, JSON still accepted over text
+    Binary,
+}
+
+impl Protocol {
+    pub fn from_socket(socket: &WebSocket) -> Self {
+        match socket.protocol().and_then(|value| value.to_str().ok()) {
+            Some(BINARY_PROTOCOL) => Protocol::Binary,
+            Some(JSON_PROTOCOL) => Protocol::Json,
+            _ => Protocol::Raw,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum BinaryMessageType {
+    Frame = 1,
+    Control = 2,
+    Batch = 3,
+}
+
+#[derive(Debug)]
+pub struct BinaryEnvelope {
+    pub message_type: BinaryMessageType,
+    pub track: FrameType,
+    pub sequence: u32,
+    pub timestamp: i64,
+    pub payload: Vec<u8>,
+}
+
+impl BinaryEnvelope {
+    pub fn decode(data: &[u8]) -> Result<Self, AppError> {
+        if data.len() < HEADER_LEN {
+            return Err(AppError::BadRequest(format!(
+                "Binary envelope too short: {} bytes, header is {}",
+                data.len(), HEADER_LEN
+            )));
+        }
+
+        if data[0] != BINARY_VERSION {
+            return Err(AppError::BadRequest(format!("Unsupported binary envelope version {}", data[0])));
+        }
+
+        let message_type = match data[1] {
+            1 => BinaryMessageType::Frame,
+            2 => BinaryMessageType::Control,
+            3 => BinaryMessageType::Batch,
+            other => return Err(AppError::BadRequest(format!("Unknown binary message type {}", other))),
+        };
+
+        let track = decode_track(data[2])?;
+
+        if data[3] != 0 {
+            return Err(AppError::BadRequest(format!("Reserved binary envelope byte must be 0, got {}", data[3])));
+        }
+
+        let sequence = u32::from_be_bytes([data[4], data[5], data[6], data[7]]);
+        let mut timestamp = [0u8; 8];
+        timestamp.copy_from_slice(&data[8..16]);
+        let timestamp = i64::from_be_bytes(timestamp);
+        let payload_len = u32::from_be_bytes([data[16], data[17], data[18], data[19]]) as usize;
+
+        let payload = &data[HEADER_LEN..];
+        if payload.len() != payload_len {
+            return Err(AppError::BadRequest(format!(
+                "Binary envelope payload length mismatch: header says {}, got {}",
+                payload_len, payload.len()
+            )));
+        }
+
+        Ok(Self {
+            message_type,
+            track,
+            sequence,
+            timestamp,
+            payload: payload.to_vec(),
+        })
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(HEADER_LEN + self.payload.len());
+        out.push(BINARY_VERSION);
+        out.push(self.message_type as u8);
+        out.push(encode_track(self.track));
+        out.push(0);
+        out.extend_from_slice(&self.sequence.to_be_bytes());
+        out.extend_from_slice(&self.timestamp.to_be_bytes());
+        out.extend_from_slice(&(self.payload.len() as u32).to_be_bytes());
+        out.extend_from_slice(&self.payload);
+        out
+    }
+
+    pub fn into_message(self) -> Result<WebSocketMessage, AppError> {
+        match self.message_type {
+            BinaryMessageType::Frame => Ok(WebSocketMessage::Frame(Frame {
+                timestamp: self.timestamp,
+                frame_type: self.track,
+                data: self.payload,
+            })),
+            BinaryMessageType::Control => {
+                let action = match self.payload.as_slice() {
+                    [1] => ControlAction::StartRecording,
+                    [2] => ControlAction::StopRecording,
+                    [3] => ControlAction::PauseRecording,
+                    [4] => ControlAction::ResumeRecording,
+                    _ => return Err(AppError::BadRequest("Invalid control envelope payload".to_string())),
+                };
+                Ok(WebSocketMessage::Control { action })
+            }
+            BinaryMessageType::Batch => Ok(WebSocketMessage::Batch {
+                frames: decode_batch(self.timestamp, &self.payload)?,
+            }),
+        }
+    }
+}
+
+// Split a batch envelope's payload into its frames
+fn decode_batch(base: i64, payload: &[u8]) -> Result<Vec<Frame>, AppError> {
+    let mut frames = Vec::new();
+    let mut rest = payload;
+    while !rest.is_empty() {
+        if rest.len() < BATCH_ENTRY_HEADER_LEN {
+            return Err(AppError::BadRequest(format!(
+                "Batch entry {} truncated: {} bytes, entry header is {}",
+                frames.len(), rest.len(), BATCH_ENTRY_HEADER_LEN
+            )));
+        }
+
+        let frame_type = decode_track(rest[0])?;
+        let offset = i32::from_be_bytes([rest[1], rest[2], rest[3], rest[4]]);
+        let len = u32::from_be_bytes([rest[5], rest[6], rest[7], rest[8]]) as usize;
+        let data = rest.get(BATCH_ENTRY_HEADER_LEN..BATCH_ENTRY_HEADER_LEN + len).ok_or_else(|| {
+            AppError::BadRequest(format!(
+                "Batch entry {} length mismatch: header says {}, {} bytes left",
+                frames.len(), len, rest.len() - BATCH_ENTRY_HEADER_LEN
+            ))
+        })?;
+
+        frames.push(Frame {
+            timestamp: base.saturating_add(i64::from(offset)),
+            frame_type,
+            data: data.to_vec(),
+        });
+        rest = &rest[BATCH_ENTRY_HEADER_LEN + len..];
+    }
+    Ok(frames)
+}
+
+fn decode_track(track: u8) -> Result<FrameType, AppError> {
+    match track {
+        0 => Ok(FrameType::Video),
+        1 => Ok(FrameType::Audio),
+        other => Err(AppError::BadRequest(format!("Unknown track {}", other))),
+    }
+}
+
+fn encode_track(track: FrameType) -> u8 {
+    match track {
+        FrameType::Video => 0,
+        FrameType::Audio => 1,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn envelope(message_type: BinaryMessageType, track: FrameType, timestamp: i64, payload: Vec<u8>) -> Vec<u8> {
+        BinaryEnvelope { message_type, track, sequence: 7, timestamp, payload }.encode()
+    }
+
+    fn bad_request(result: Result<impl std::fmt::Debug, AppError>) -> String {
+        match result {
+            Err(AppError::BadRequest(message)) => message,
+            other => panic!("expected BadRequest, got {:?}", other),
+        }
+    }
+
+    fn batch_entry(track: u8, offset: i32, data: &[u8]) -> Vec<u8> {
+        let mut entry = vec![track];
+        entry.extend_from_slice(&offset.to_be_bytes());
+        entry.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        entry.extend_from_slice(data);
+        entry
+    }
+
+    #[test]
+    fn decodes_what_it_encodes() {
+        let data = envelope(BinaryMessageType::Frame, FrameType::Audio, -40, vec![1, 2, 3]);
+        assert_eq!(data.len(), HEADER_LEN + 3);
+        assert_eq!(&data[..4], [BINARY_VERSION, 1, 1, 0]);
+
+        let decoded = BinaryEnvelope::decode(&data).unwrap();
+        assert_eq!(decoded.message_type, BinaryMessageType::Frame);
+        assert_eq!(decoded.track, FrameType::Audio);
+        assert_eq!(decoded.sequence, 7);
+        assert_eq!(decoded.timestamp, -40);
+        match decoded.into_message().unwrap() {
+            WebSocketMessage::Frame(frame) => {
+                assert_eq!(frame.frame_type, FrameType::Audio);
+                assert_eq!(frame.timestamp, -40);
+                assert_eq!(frame.data, [1, 2, 3]);
+            }
+            other => panic!("expected a frame, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn rejects_truncated_headers() {
+        let data = envelope(BinaryMessageType::Frame, FrameType::Video, 1000, Vec::new());
+        for len in 0..HEADER_LEN {
+            let message = bad_request(BinaryEnvelope::decode(&data[..len]));
+            assert_eq!(message, format!("Binary envelope too short: {} bytes, header is {}", len, HEADER_LEN));
+        }
+        assert!(BinaryEnvelope::decode(&data).is_ok());
+    }
+
+    #[test]
+    fn rejects_payload_length_mismatches() {
+        let data = envelope(BinaryMessageType::Frame, FrameType::Video, 0, vec![9; 10]);
+        let message = bad_request(BinaryEnvelope::decode(&data[..data.len() - 1]));
+        assert!(message.contains("header says 10, got 9"), "{}", message);
+
+        let mut longer = data.clone();
+        longer.push(0);
+        assert!(bad_request(BinaryEnvelope::decode(&longer)).contains("header says 10, got 11"));
+    }
+
+    #[test]
+    fn rejects_unknown_header_fields() {
+        let data = envelope(BinaryMessageType::Frame, FrameType::Video, 0, Vec::new());
+        let with = |index: usize, value: u8| {
+            let mut data = data.clone();
+            data[index] = value;
+            data
+        };
+        assert!(bad_request(BinaryEnvelope::decode(&with(0, 2))).contains("version 2"));
+        assert!(bad_request(BinaryEnvelope::decode(&with(1, 4))).contains("message type 4"));
+        assert!(bad_request(BinaryEnvelope::decode(&with(2, 2))).contains("Unknown track 2"));
+        assert!(bad_request(BinaryEnvelope::decode(&with(3, 1))).contains("Reserved"));
+    }
+
+    #[test]
+    fn decodes_control_actions() {
+        for (code, action) in [
+            (1, ControlAction::StartRecording),
+            (2, ControlAction::StopRecording),
+            (3, ControlAction::PauseRecording),
+            (4, ControlAction::ResumeRecording),
+        ] {
+            let data = envelope(BinaryMessageType::Control, FrameType::Video, 0, vec![code]);
+            let message = BinaryEnvelope::decode(&data).unwrap().into_message().unwrap();
+            assert!(matches!(message, WebSocketMessage::Control { action: decoded } if decoded == action));
+        }
+
+        for payload in [vec![], vec![5], vec![1, 1]] {
+            let data = envelope(BinaryMessageType::Control, FrameType::Video, 0, payload);
+            bad_request(BinaryEnvelope::decode(&data).unwrap().into_message());
+        }
+    }
+
+    #[test]
+    fn decodes_batches() {
+        let mut payload = batch_entry(0, 0, &[1, 2]);
+        payload.extend_from_slice(&batch_entry(1, -20, &[3]));
+        payload.extend_from_slice(&batch_entry(1, 20, &[]));
+        let data = envelope(BinaryMessageType::Batch, FrameType::Video, 5000, payload);
+
+        let WebSocketMessage::Batch { frames } = BinaryEnvelope::decode(&data).unwrap().into_message().unwrap() else {
+            panic!("expected a batch");
+        };
+        let frames: Vec<(FrameType, i64, Vec<u8>)> = frames
+            .into_iter()
+            .map(|frame| (frame.frame_type, frame.timestamp, frame.data))
+            .collect();
+        assert_eq!(
+            frames,
+            [
+                (FrameType::Video, 5000, vec![1, 2]),
+                (FrameType::Audio, 4980, vec![3]),
+                (FrameType::Audio, 5020, vec![]),
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_truncated_batch_entries() {
+        let entry = batch_entry(0, 0, &[1, 2, 3]);
+        for len in 1..BATCH_ENTRY_HEADER_LEN {
+            let message = bad_request(decode_batch(0, &entry[..len]));
+            assert!(message.starts_with("Batch entry 0 truncated"), "{}", message);
+        }
+        for len in BATCH_ENTRY_HEADER_LEN..entry.len() {
+            let message = bad_request(decode_batch(0, &entry[..len]));
+            assert!(message.starts_with("Batch entry 0 length mismatch: header says 3"), "{}", message);
+        }
+
+        // The second entry's header is cut short
+        let mut payload = entry.clone();
+        payload.extend_from_slice(&entry[..4]);
+        assert!(bad_request(decode_batch(0, &payload)).starts_with("Batch entry 1 truncated: 4 bytes"));
+        assert!(decode_batch(0, &[]).unwrap().is_empty());
+    }
+}
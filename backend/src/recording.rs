@@ -0,0 +1,947 @@
+/*
+ * recording.rs
+ * Purpose: Recording session management
+ *
+ * This file contains:
+ * - RecordingSession mapping one StartRecording/StopRecording span to one recording,
+ *   split into segment files when the configured size limit is reached
+ * - Per-track ingest state and the room's recording mode: frames are muxed into
+ *   Matroska, interleaved in one file, one file per track, or audio only
+ * - Parsing of everything written, which names each file after its container and
+ *   builds its keyframe index; for WebM streams it also supplies the codecs and
+ *   per-track counts
+ * - Cues written into every finished Matroska segment from that index
+ * - A fragmented MP4 copy of finished H.264/Opus recordings, written in the background
+ * - RecordingManager tracking the active session of every room
+ * - Coalescing of batched frames into a single storage write
+ * - Session lifecycle tracking through RecordingStatus
+ */
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+};
+use chrono::{DateTime, Utc};
+use tokio::{sync::Mutex, task::JoinHandle};
+use tracing::{error, info, warn};
+use uuid::Uuid;
+use crate::{
+    cues::{self, SegmentIndex},
+    error::AppError,
+    dedup::{perceptual_hash, similarity, DedupResult, Deduplicator},
+    models::{
+        Frame, FrameReference, FrameType, KeyframeEntry, Recording, RecordingMode, RecordingStatus, RoomConfig,
+        TrackInfo,
+    },
+    mux::{MatroskaMuxer, MuxTrack},
+    remux,
+    repository::Repository,
+    storage::{Storage, UploadSession},
+    webm::{WebmEvent, WebmScanner, EBML_HEADER_ID},
+};
+
+// One file of a recording: all of it, or a single track when tracks are stored separately
+struct RecordingFile {
+    // The track the file holds on its own, None when it holds every recorded track
+    track: Option<FrameType>,
+    dir: String,
+    // File name without segment suffix or extension
+    stem: String,
+    // Picked from the first bytes written and kept for every segment
+    extension: Option<&'static str>,
+    // Keys of every segment written so far, in order
+    segments: Vec<String>,
+    // Opened by the first write of each segment, once the container is known
+    upload: Option<Box<dyn UploadSession>>,
+    // Bytes written to the current segment
+    segment_bytes: u64,
+    // Frames are muxed into Matroska; chunk streams are already a container
+    muxer: MatroskaMuxer,
+    // Whether the file is written from chunks rather than muxed frames
+    chunked: bool,
+    // Follows everything written to the file
+    scanner: WebmScanner,
+    // Scanner offset at which the current segment starts
+    segment_start: u64,
+    // Cluster waiting for its first keyframe
+    pending_cluster: Option<u64>,
+    keyframes: Vec<KeyframeEntry>,
+    // Whether any video block has been written; a video track can be declared and unused
+    video_seen: bool,
+    // Timecode of the current segment's last block
+    last_timecode: Option<i64>,
+}
+
+impl RecordingFile {
+    fn new(dir: &str, stem: String, track: Option<FrameType>, tracks: Vec<MuxTrack>) -> Self {
+        Self {
+            track,
+            dir: dir.to_string(),
+            stem,
+            extension: None,
+            segments: Vec::new(),
+            upload: None,
+            segment_bytes: 0,
+            muxer: MatroskaMuxer::new(tracks),
+            chunked: false,
+            scanner: WebmScanner::new(),
+            segment_start: 0,
+            pending_cluster: None,
+            keyframes: Vec::new(),
+            video_seen: false,
+            last_timecode: None,
+        }
+    }
+
+    // First segment, or where it will be written while nothing has been
+    fn key(&self) -> String {
+        match self.segments.first() {
+            Some(key) => key.clone(),
+            None => format!("{}/{}", self.dir, self.stem),
+        }
+    }
+
+    async fn open_segment(&mut self, storage: &Storage, data: &[u8]) -> Result<(), AppError> {
+        let extension = *self.extension.get_or_insert_with(|| container_extension(&self.scanner, data));
+        let filename = Storage::segment_filename(&self.stem, self.segments.len() as u32, extension);
+        self.upload = Some(storage.create_segment(&self.dir, &filename).await?);
+        self.segments.push(format!("{}/{}", self.dir, filename));
+        self.segment_bytes = 0;
+        Ok(())
+    }
+
+    // Index clusters that start at a keyframe: the first video block once video
+    // has been seen, otherwise the first block of every cluster
+    fn index(&mut self, events: &[WebmEvent]) {
+        for event in events {
+            match *event {
+                WebmEvent::ClusterStart { offset } => self.pending_cluster = Some(offset),
+                WebmEvent::Block { track, keyframe, timecode, .. } => {
+                    self.last_timecode = Some(self.last_timecode.map_or(timecode, |last| last.max(timecode)));
+                    let Some(cluster) = self.pending_cluster else {
+                        continue;
+                    };
+                    let video = self.scanner.is_video_track(track);
+                    self.video_seen |= video;
+                    if self.video_seen && !video {
+                        continue;
+                    }
+                    self.pending_cluster = None;
+                    let Some(offset) = cluster.checked_sub(self.segment_start) else {
+                        continue;
+                    };
+                    if keyframe || !video {
+                        self.keyframes.push(KeyframeEntry {
+                            segment: self.segments.len().saturating_sub(1) as u32,
+                            track,
+                            time_ms: timecode,
+                            offset,
+                        });
+                    }
+                }
+            }
+        }
+    }
+
+    // Finish the current segment and, if it's Matroska, make it seekable
+    async fn close_segment(&mut self, storage: &Storage) -> Result<(), AppError> {
+        let Some(upload) = self.upload.take() else {
+            return Ok(());
+        };
+        upload.finalize().await?;
+
+        let Some(index) = self.segment_index() else {
+            return Ok(());
+        };
+        let segment = self.segments.len() as u32 - 1;
+        let key = &self.segments[segment as usize];
+        // The recording plays either way, just without seeking
+        match cues::write_cues(storage, key, &index).await {
+            Ok(shift) => {
+                for keyframe in self.keyframes.iter_mut().filter(|k| k.segment == segment) {
+                    keyframe.offset = keyframe.offset.saturating_add_signed(shift);
+                }
+            }
+            Err(e) => warn!("Failed to write cues for {}: {}", key, e),
+        }
+        Ok(())
+    }
+
+    // What the scanner learned about the current segment, if it's a whole Matroska file
+    fn segment_index(&self) -> Option<SegmentIndex> {
+        // A chunk stream's later segments continue the first and have no header of their own
+        if self.scanner.failed() || (self.chunked && self.segments.len() > 1) {
+            return None;
+        }
+        let segment = self.segments.len().checked_sub(1)? as u32;
+        Some(SegmentIndex {
+            head_len: self.scanner.init_segment_len()?,
+            segment_data_offset: self.scanner.segment_data_offset()?,
+            timecode_scale: self.scanner.timecode_scale(),
+            duration_ms: self.last_timecode?,
+            keyframes: self.keyframes.iter().filter(|k| k.segment == segment).copied().collect(),
+        })
+    }
+
+    // Codecs of the tracks declared in a chunk stream's header
+    fn stream_codecs(&self) -> Option<String> {
+        let codecs: Vec<String> = self.scanner
+            .tracks()
+            .filter_map(|track| track.codec_name().map(str::to_string).or_else(|| track.codec_id.clone()))
+            .collect();
+        (!codecs.is_empty()).then(|| codecs.join(","))
+    }
+}
+
+// What has been ingested on one track of a frame stream
+#[derive(Debug, Default, Clone, Copy)]
+struct TrackState {
+    // Every frame received, deduplicated ones included
+    frames: u64,
+    // Frame bytes stored
+    bytes: u64,
+    first_timestamp: Option<i64>,
+    last_timestamp: Option<i64>,
+}
+
+impl TrackState {
+    fn record(&mut self, timestamp: i64, stored_bytes: usize) {
+        self.frames += 1;
+        self.bytes += stored_bytes as u64;
+        self.first_timestamp = Some(self.first_timestamp.map_or(timestamp, |first| first.min(timestamp)));
+        self.last_timestamp = Some(self.last_timestamp.map_or(timestamp, |last| last.max(timestamp)));
+    }
+}
+
+// A single recording, open from start until it is finalized
+pub struct RecordingSession {
+    pub id: Uuid,
+    pub room_id: String,
+    // Directory from the storage layout
+    pub dir: String,
+    pub start_time: DateTime<Utc>,
+    pub end_time: Option<DateTime<Utc>>,
+    pub size_bytes: u64,
+    pub frame_count: u64,
+    pub frames_deduplicated: u64,
+    // Detected from the start of a chunk stream
+    pub codec: Option<String>,
+    pub status: RecordingStatus,
+    video: TrackState,
+    audio: TrackState,
+    // Publisher timestamp that frame timecodes count from, shared by every file
+    origin: Option<i64>,
+    dedup_enabled: bool,
+    // Minimum perceptual similarity for a video frame to count as a duplicate
+    dedup_threshold: Option<f32>,
+    // Perceptual hash and stored timestamp of the last kept video frame
+    last_video_hash: Option<(u64, i64)>,
+    references: Vec<FrameReference>,
+    storage: Storage,
+    // The main file first, then any per-track files
+    files: Vec<RecordingFile>,
+}
+
+impl RecordingSession {
+    // Chunks are written as sent to the main file
+    pub async fn append(&mut self, data: &[u8]) -> Result<(), AppError> {
+        // Chunks arriving while paused are not part of the recording
+        if self.status == RecordingStatus::Paused {
+            return Ok(());
+        }
+        if self.status != RecordingStatus::Recording {
+            return Err(AppError::StreamingError(format!("Recording {} is not active", self.id)));
+        }
+
+        if self.needs_roll_over(0, data.len()) {
+            self.roll_over(0).await?;
+        }
+        if self.size_bytes == 0 && is_jpeg(data) {
+            self.codec = Some("jpeg".to_string());
+        }
+        self.files[0].chunked = true;
+        self.write(0, data, 1).await?;
+
+        if self.codec.is_none() {
+            self.codec = self.files[0].stream_codecs();
+        }
+        Ok(())
+    }
+
+    // Mux several frames into their files with as few storage writes as possible:
+    // one per file, unless the frames straddle a segment boundary
+    pub async fn append_frames(&mut self, frames: &[&Frame]) -> Result<(), AppError> {
+        if self.status == RecordingStatus::Paused || frames.is_empty() {
+            return Ok(());
+        }
+        if self.status != RecordingStatus::Recording {
+            return Err(AppError::StreamingError(format!("Recording {} is not active", self.id)));
+        }
+
+        let origin = *self.origin.get_or_insert(frames[0].timestamp);
+        let mut pending: Vec<(Vec<u8>, u64)> = vec![(Vec::new(), 0); self.files.len()];
+
+        for frame in frames {
+            // Tracks the room doesn't record, e.g. video in an audio-only room
+            let Some(index) = self.file_for(frame.frame_type) else {
+                continue;
+            };
+
+            if self.needs_roll_over(index, pending[index].0.len() + frame.data.len()) {
+                let (data, count) = std::mem::take(&mut pending[index]);
+                if count > 0 {
+                    self.write(index, &data, count).await?;
+                }
+                self.roll_over(index).await?;
+            }
+
+            let (data, count) = &mut pending[index];
+            self.files[index].muxer.write_frame(frame.frame_type, frame.timestamp, &frame.data, origin, data);
+            *count += 1;
+            self.track_mut(frame.frame_type).record(frame.timestamp, frame.data.len());
+        }
+
+        for (index, (data, count)) in pending.into_iter().enumerate() {
+            if count > 0 {
+                self.write(index, &data, count).await?;
+            }
+        }
+        Ok(())
+    }
+
+    // Record a duplicate frame as a pointer to the earlier copy instead of its bytes
+    pub fn append_reference(&mut self, frame: &Frame, reference_timestamp: i64) {
+        if self.status != RecordingStatus::Recording || self.file_for(frame.frame_type).is_none() {
+            return;
+        }
+
+        self.references.push(FrameReference {
+            timestamp: frame.timestamp,
+            reference_timestamp,
+            frame_type: frame.frame_type,
+        });
+        self.frame_count += 1;
+        self.frames_deduplicated += 1;
+        self.track_mut(frame.frame_type).record(frame.timestamp, 0);
+    }
+
+    // Index of the file a track's frames go to, None if the track isn't recorded
+    fn file_for(&self, track: FrameType) -> Option<usize> {
+        self.files.iter().position(|file| match file.track {
+            Some(only) => only == track,
+            None => file.muxer.tracks().iter().any(|t| t.track == track),
+        })
+    }
+
+    fn track_mut(&mut self, track: FrameType) -> &mut TrackState {
+        match track {
+            FrameType::Video => &mut self.video,
+            FrameType::Audio => &mut self.audio,
+        }
+    }
+
+    // Whether writing `len` more bytes to a file would push its segment past the size limit
+    fn needs_roll_over(&self, index: usize, len: usize) -> bool {
+        let segment_bytes = self.files[index].segment_bytes;
+        self.storage
+            .max_file_size()
+            .is_some_and(|max| segment_bytes > 0 && segment_bytes + len as u64 > max)
+    }
+
+    // One storage write of `frames` frames or chunks to a file
+    async fn write(&mut self, index: usize, data: &[u8], frames: u64) -> Result<(), AppError> {
+        // The muxer can hold frames back, e.g. until H.264 parameter sets arrive;
+        // the file isn't opened before there's something to write
+        if data.is_empty() {
+            self.frame_count += frames;
+            return Ok(());
+        }
+        let file = &mut self.files[index];
+        let events = file.scanner.feed(data);
+
+        // A stream that started as Matroska and broke would make the rest of the
+        // file unplayable; what was written before stays as it is
+        if let Some(reason) = file.scanner.corruption() {
+            error!("Stopping recording {} on corrupt media: {}", self.id, reason);
+            self.status = RecordingStatus::Failed;
+            return Err(AppError::StreamingError(format!("Corrupt Matroska stream: {}", reason)));
+        }
+
+        if file.upload.is_none() {
+            if let Err(e) = file.open_segment(&self.storage, data).await {
+                error!("Failed to open recording {}: {}", self.id, e);
+                self.status = RecordingStatus::Failed;
+                return Err(e);
+            }
+        }
+        if let Some(upload) = file.upload.as_mut() {
+            if let Err(e) = upload.append(data).await {
+                error!("Failed to append to recording {}: {}", self.id, e);
+                self.status = RecordingStatus::Failed;
+                return Err(e);
+            }
+        }
+        file.index(&events);
+
+        file.segment_bytes += data.len() as u64;
+        self.size_bytes += data.len() as u64;
+        self.frame_count += frames;
+
+        // Frame streams count their tracks as frames arrive; WebM streams count blocks
+        if file.chunked {
+            let blocks: Vec<(FrameType, i64, u64)> = events
+                .iter()
+                .filter_map(|event| match *event {
+                    WebmEvent::Block { track, timecode, size, .. } => {
+                        let track = self.files[index].scanner.track(track)?;
+                        let frame_type = match (track.is_video(), track.is_audio()) {
+                            (true, _) => FrameType::Video,
+                            (_, true) => FrameType::Audio,
+                            _ => return None,
+                        };
+                        Some((frame_type, timecode, size))
+                    }
+                    WebmEvent::ClusterStart { .. } => None,
+                })
+                .collect();
+            for (frame_type, timecode, size) in blocks {
+                self.track_mut(frame_type).record(timecode, size as usize);
+            }
+        }
+        Ok(())
+    }
+
+    // Timestamp of the last kept video frame if this hash is close enough to it
+    fn near_duplicate(&self, hash: u64) -> Option<i64> {
+        let threshold = self.dedup_threshold?;
+        let (last_hash, last_timestamp) = self.last_video_hash?;
+        (similarity(hash, last_hash) >= threshold).then_some(last_timestamp)
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.status == RecordingStatus::Paused
+    }
+
+    pub fn pause(&mut self) -> Result<(), AppError> {
+        match self.status {
+            RecordingStatus::Recording | RecordingStatus::Paused => {
+                self.status = RecordingStatus::Paused;
+                Ok(())
+            }
+            status => Err(AppError::StreamingError(format!("Cannot pause recording {} in state {:?}", self.id, status))),
+        }
+    }
+
+    pub fn resume(&mut self) -> Result<(), AppError> {
+        match self.status {
+            RecordingStatus::Paused | RecordingStatus::Recording => {
+                self.status = RecordingStatus::Recording;
+                Ok(())
+            }
+            status => Err(AppError::StreamingError(format!("Cannot resume recording {} in state {:?}", self.id, status))),
+        }
+    }
+
+    // Close a file's current segment and continue it in a new one
+    async fn roll_over(&mut self, index: usize) -> Result<(), AppError> {
+        if let Err(e) = self.start_segment(index).await {
+            error!("Failed to roll over recording {}: {}", self.id, e);
+            self.status = RecordingStatus::Failed;
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    async fn start_segment(&mut self, index: usize) -> Result<(), AppError> {
+        let file = &mut self.files[index];
+        file.close_segment(&self.storage).await?;
+        file.last_timecode = None;
+
+        // Every segment of a frame recording is a playable file of its own, parsed
+        // from its header; a chunk stream just carries on into the next one
+        if file.chunked {
+            file.segment_start += file.segment_bytes;
+            file.pending_cluster = None;
+        } else {
+            file.muxer.restart();
+            file.scanner = WebmScanner::new();
+            file.segment_start = 0;
+        }
+        file.segment_bytes = 0;
+
+        info!("Recording {} rolling over {} to segment {}", self.id, file.key(), file.segments.len() + 1);
+        Ok(())
+    }
+
+    // Storage key of the main file's first segment
+    pub fn key(&self) -> String {
+        self.files[0].key()
+    }
+
+    fn track_info(&self) -> Vec<TrackInfo> {
+        let mut tracks = Vec::new();
+        for (track, state) in [(FrameType::Video, &self.video), (FrameType::Audio, &self.audio)] {
+            if state.frames == 0 {
+                continue;
+            }
+            // WebM streams are written whole to the main file
+            let (file, codec) = if self.files[0].chunked {
+                let file = &self.files[0];
+                let Some(webm_track) = file.scanner.tracks().find(|t| match track {
+                    FrameType::Video => t.is_video(),
+                    FrameType::Audio => t.is_audio(),
+                }) else {
+                    continue;
+                };
+                let codec = webm_track.codec_name().map(str::to_string).or_else(|| webm_track.codec_id.clone());
+                (file, codec.unwrap_or_default())
+            } else {
+                let Some(file) = self.file_for(track).map(|index| &self.files[index]) else {
+                    continue;
+                };
+                let Some(mux_track) = file.muxer.tracks().iter().find(|t| t.track == track) else {
+                    continue;
+                };
+                (file, mux_track.codec.clone())
+            };
+            tracks.push(TrackInfo {
+                track,
+                codec,
+                frames: state.frames as i64,
+                bytes: state.bytes as i64,
+                first_timestamp: state.first_timestamp,
+                last_timestamp: state.last_timestamp,
+                storage_path: file.key(),
+            });
+        }
+        tracks
+    }
+
+    pub fn to_record(&self) -> Result<Recording, AppError> {
+        let room_id = Uuid::parse_str(&self.room_id)
+            .map_err(|_| AppError::InternalError(format!("Room id {} is not a UUID", self.room_id)))?;
+
+        // Report the codecs of the tracks that received frames
+        let tracks = self.track_info();
+        let codec = match tracks.is_empty() {
+            true => self.codec.clone(),
+            false => Some(tracks.iter().map(|t| t.codec.as_str()).collect::<Vec<_>>().join(",")),
+        };
+
+        Ok(Recording {
+            id: self.id,
+            room_id,
+            start_time: self.start_time,
+            end_time: self.end_time,
+            storage_path: self.key(),
+            size_bytes: self.size_bytes as i64,
+            duration_ms: self.end_time.map(|end| (end - self.start_time).num_milliseconds()),
+            frame_count: self.frame_count as i64,
+            video_frames: self.video.frames as i64,
+            audio_frames: self.audio.frames as i64,
+            codec,
+            status: self.status,
+            tracks,
+            mp4_path: None,
+        })
+    }
+
+    pub async fn finalize(&mut self) -> Result<(), AppError> {
+        if self.end_time.is_some() {
+            return Ok(());
+        }
+
+        self.status = RecordingStatus::Processing;
+        self.end_time = Some(Utc::now());
+
+        let mut result = Ok(());
+        for file in &mut self.files {
+            if let Err(e) = file.close_segment(&self.storage).await {
+                error!("Failed to finalize recording file {}: {}", file.key(), e);
+                if result.is_ok() {
+                    result = Err(e);
+                }
+            }
+        }
+
+        match result {
+            Ok(()) => {
+                self.status = RecordingStatus::Completed;
+                info!(
+                    "Finalized recording {} for room {} ({} bytes, {} chunks)",
+                    self.key(), self.room_id, self.size_bytes, self.frame_count
+                );
+                Ok(())
+            }
+            Err(e) => {
+                self.status = RecordingStatus::Failed;
+                Err(e)
+            }
+        }
+    }
+
+    // Discard everything written, e.g. for a session that lost the race to start
+    async fn abort(&mut self) {
+        for file in &mut self.files {
+            if let Some(upload) = file.upload.take() {
+                if let Err(e) = upload.abort().await {
+                    warn!("Failed to discard recording file {}: {}", file.key(), e);
+                }
+            }
+        }
+    }
+}
+
+// A finalized recording; `remux` is its MP4 copy, still being written in the background
+pub struct StoppedRecording {
+    pub id: Uuid,
+    pub remux: Option<JoinHandle<()>>,
+}
+
+// Tracks the active recording session of each room
+#[derive(Clone)]
+pub struct RecordingManager {
+    storage: Storage,
+    repo: Repository,
+    dedup: Deduplicator,
+    active: Arc<RwLock<HashMap<String, Arc<Mutex<RecordingSession>>>>>,
+}
+
+impl RecordingManager {
+    pub fn new(storage: Storage, repo: Repository, dedup: Deduplicator) -> Self {
+        Self {
+            storage,
+            repo,
+            dedup,
+            active: Arc::new(RwLock::new(HashMap::new())),
+        }
+    }
+
+    pub async fn start(&self, room_id: &str) -> Result<Arc<Mutex<RecordingSession>>, AppError> {
+        if self.active.read().unwrap().contains_key(room_id) {
+            return Err(AppError::StreamingError(format!("Room {} is already recording", room_id)));
+        }
+
+        let room = match self.repo.get_room(room_id).await {
+            Ok(room) => room,
+            Err(e) => {
+                warn!("Failed to load room {}: {}", room_id, e);
+                None
+            }
+        };
+        let owner = room.as_ref().map(|room| room.user_id);
+        let config = self.room_config(room_id, room.as_ref().map(|room| room.id)).await;
+
+        let id = Uuid::new_v4();
+        let start_time = Utc::now();
+        let dir = self.storage.recording_dir(owner.as_ref(), room_id, start_time);
+
+        let video = MuxTrack::for_room(FrameType::Video, &config);
+        let audio = MuxTrack::for_room(FrameType::Audio, &config);
+        let layout = match config.recording_mode {
+            RecordingMode::Interleaved => vec![(None, vec![video, audio])],
+            RecordingMode::SeparateTracks => vec![
+                (Some(FrameType::Video), vec![video]),
+                (Some(FrameType::Audio), vec![audio]),
+            ],
+            RecordingMode::AudioOnly => vec![(None, vec![audio])],
+        };
+
+        // Segments are opened by the first write, once the container is known
+        let files = layout
+            .into_iter()
+            .map(|(track, tracks)| {
+                let stem = Storage::recording_stem(&id, start_time, track.map(|t| t.as_str()));
+                RecordingFile::new(&dir, stem, track, tracks)
+            })
+            .collect();
+
+        let session = Arc::new(Mutex::new(RecordingSession {
+            id,
+            room_id: room_id.to_string(),
+            dir,
+            start_time,
+            end_time: None,
+            size_bytes: 0,
+            frame_count: 0,
+            frames_deduplicated: 0,
+            codec: None,
+            status: RecordingStatus::Recording,
+            video: TrackState::default(),
+            audio: TrackState::default(),
+            origin: None,
+            dedup_enabled: config.deduplication_enabled,
+            dedup_threshold: config.deduplication_threshold.filter(|t| *t > 0.0 && *t < 1.0),
+            last_video_hash: None,
+            references: Vec::new(),
+            storage: self.storage.clone(),
+            files,
+        }));
+
+        let lost_race = {
+            let mut active = self.active.write().unwrap();
+            if active.contains_key(room_id) {
+                true
+            } else {
+                active.insert(room_id.to_string(), session.clone());
+                false
+            }
+        };
+
+        if lost_race {
+            // Another start got there first; throw away anything it opened
+            session.lock().await.abort().await;
+            return Err(AppError::StreamingError(format!("Room {} is already recording", room_id)));
+        }
+
+        self.persist(&*session.lock().await).await;
+        info!("Started recording {} for room {}", id, room_id);
+        Ok(session)
+    }
+
+    // Rooms without a stored config get the schema defaults, e.g. exact-match dedup only
+    async fn room_config(&self, room_id: &str, record_id: Option<Uuid>) -> RoomConfig {
+        let Some(record_id) = record_id.or_else(|| Uuid::parse_str(room_id).ok()) else {
+            return RoomConfig::default();
+        };
+        match self.repo.get_room_config(record_id).await {
+            Ok(config) => config.unwrap_or_default(),
+            Err(e) => {
+                warn!("Failed to load config for room {}: {}", room_id, e);
+                RoomConfig::default()
+            }
+        }
+    }
+
+    // Store the session's current state; failures are logged, not fatal to ingest
+    async fn persist(&self, session: &RecordingSession) {
+        let result = match session.to_record() {
+            Ok(record) => self.repo.upsert_recording(&record).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            error!("Failed to persist recording {}: {}", session.id, e);
+        }
+    }
+
+    // meta.json next to the main file
+    async fn write_metadata(&self, record: &Recording) {
+        match serde_json::to_vec_pretty(record) {
+            Ok(json) => {
+                if let Err(e) = self.storage.write_sidecar(&record.storage_path, "meta.json", &json).await {
+                    error!("Failed to write metadata for {}: {}", record.storage_path, e);
+                }
+            }
+            Err(e) => error!("Failed to serialize metadata for {}: {}", record.storage_path, e),
+        }
+    }
+
+    // Write the MP4 copy of a finished recording next to it and record where it is
+    async fn remux(&self, mut record: Recording, files: Vec<Vec<String>>, tracks: Vec<FrameType>, dir: String) {
+        let stem = Storage::recording_stem(&record.id, record.start_time, None);
+        let filename = Storage::segment_filename(&stem, 0, "mp4");
+        let key = match remux::remux_to_mp4(&self.storage, files, &tracks, &dir, &filename).await {
+            Ok((key, size)) => {
+                info!("Remuxed recording {} to {} ({} bytes)", record.id, key, size);
+                key
+            }
+            Err(e) => {
+                warn!("Failed to remux recording {} to MP4: {}", record.id, e);
+                return;
+            }
+        };
+
+        if let Err(e) = self.repo.set_recording_mp4(record.id, &key).await {
+            error!("Failed to persist MP4 copy of recording {}: {}", record.id, e);
+        }
+        record.mp4_path = Some(key);
+        self.write_metadata(&record).await;
+    }
+
+    pub fn get_active(&self, room_id: &str) -> Option<Arc<Mutex<RecordingSession>>> {
+        self.active.read().unwrap().get(room_id).cloned()
+    }
+
+    pub fn is_recording(&self, room_id: &str) -> bool {
+        self.active.read().unwrap().contains_key(room_id)
+    }
+
+    // Append an untyped chunk to the room's session; dropped while the room
+    // isn't recording, so a stream never opens a recording by itself
+    pub async fn append(&self, room_id: &str, data: &[u8]) -> Result<(), AppError> {
+        let Some(session) = self.get_active(room_id) else {
+            return Ok(());
+        };
+        let mut session = session.lock().await;
+        session.append(data).await
+    }
+
+    // Deduplicate a batch of frames and store the unique ones in a single write;
+    // returns each frame's result in order, or nothing while the room isn't recording
+    pub async fn append_frames(&self, room_id: &str, frames: &[Frame]) -> Result<Vec<DedupResult>, AppError> {
+        let Some(session) = self.get_active(room_id) else {
+            return Ok(Vec::new());
+        };
+        let mut session = session.lock().await;
+
+        if !session.dedup_enabled || session.is_paused() {
+            session.append_frames(&frames.iter().collect::<Vec<_>>()).await?;
+            return Ok(vec![DedupResult::Unique; frames.len()]);
+        }
+
+        let mut results = Vec::with_capacity(frames.len());
+        let mut unique = Vec::with_capacity(frames.len());
+        for frame in frames {
+            let result = self.check_duplicate(&mut session, room_id, frame).await;
+            match result {
+                DedupResult::Unique => unique.push(frame),
+                DedupResult::Duplicate { reference_timestamp } => session.append_reference(frame, reference_timestamp),
+            }
+            results.push(result);
+        }
+
+        session.append_frames(&unique).await?;
+        Ok(results)
+    }
+
+    async fn check_duplicate(&self, session: &mut RecordingSession, room_id: &str, frame: &Frame) -> DedupResult {
+        // Static cameras produce near-identical JPEGs that never match byte for byte
+        let hash = match (frame.frame_type, session.dedup_threshold) {
+            (FrameType::Video, Some(_)) => perceptual_hash(&frame.data),
+            _ => None,
+        };
+
+        let result = match hash.and_then(|hash| session.near_duplicate(hash)) {
+            Some(reference_timestamp) => DedupResult::Duplicate { reference_timestamp },
+            // A failed lookup should never cost us a frame
+            None => self.dedup.check(&session.id, &frame.data, frame.timestamp).await.unwrap_or_else(|e| {
+                warn!("Deduplication unavailable for room {}: {}", room_id, e);
+                DedupResult::Unique
+            }),
+        };
+
+        if let Some(hash) = hash {
+            if session.near_duplicate(hash).is_none() {
+                let stored_at = match result {
+                    DedupResult::Unique => frame.timestamp,
+                    DedupResult::Duplicate { reference_timestamp } => reference_timestamp,
+                };
+                session.last_video_hash = Some((hash, stored_at));
+            }
+        }
+        result
+    }
+
+    pub async fn pause(&self, room_id: &str) -> Result<Uuid, AppError> {
+        let session = self.get_active(room_id)
+            .ok_or_else(|| AppError::StreamingError(format!("No active recording for room {}", room_id)))?;
+        let mut session = session.lock().await;
+        session.pause()?;
+        self.persist(&session).await;
+        Ok(session.id)
+    }
+
+    pub async fn resume(&self, room_id: &str) -> Result<Uuid, AppError> {
+        let session = self.get_active(room_id)
+            .ok_or_else(|| AppError::StreamingError(format!("No active recording for room {}", room_id)))?;
+        let mut session = session.lock().await;
+        session.resume()?;
+        self.persist(&session).await;
+        Ok(session.id)
+    }
+
+    // Close the room's active session, returning its id if there was one
+    pub async fn stop(&self, room_id: &str) -> Result<Option<Uuid>, AppError> {
+        Ok(self.finish(room_id).await?.map(|stopped| stopped.id))
+    }
+
+    // Like stop, but hands back the MP4 remux so callers can wait for it before touching the files
+    pub async fn finish(&self, room_id: &str) -> Result<Option<StoppedRecording>, AppError> {
+        let session = self.active.write().unwrap().remove(room_id);
+        match session {
+            Some(session) => {
+                let mut session = session.lock().await;
+                let result = session.finalize().await;
+                self.dedup.clear_recording(&session.id);
+
+                if !session.references.is_empty() {
+                    match serde_json::to_vec(&session.references) {
+                        Ok(json) => {
+                            if let Err(e) = self.storage.write_sidecar(&session.key(), "refs.json", &json).await {
+                                error!("Failed to write frame references for {}: {}", session.key(), e);
+                            }
+                        }
+                        Err(e) => error!("Failed to serialize frame references for {}: {}", session.key(), e),
+                    }
+                }
+
+                for file in session.files.iter().filter(|file| file.segments.len() > 1) {
+                    match serde_json::to_vec(&file.segments) {
+                        Ok(json) => {
+                            if let Err(e) = self.storage.write_sidecar(&file.key(), "segments.json", &json).await {
+                                error!("Failed to write segment list for {}: {}", file.key(), e);
+                            }
+                        }
+                        Err(e) => error!("Failed to serialize segment list for {}: {}", file.key(), e),
+                    }
+                }
+
+                for file in session.files.iter().filter(|file| !file.keyframes.is_empty()) {
+                    match serde_json::to_vec(&file.keyframes) {
+                        Ok(json) => {
+                            if let Err(e) = self.storage.write_sidecar(&file.key(), "keyframes.json", &json).await {
+                                error!("Failed to write keyframe index for {}: {}", file.key(), e);
+                            }
+                        }
+                        Err(e) => error!("Failed to serialize keyframe index for {}: {}", file.key(), e),
+                    }
+                }
+
+                // Keep the metadata with the file so it survives without the database
+                let record = session.to_record();
+                match &record {
+                    Ok(record) => self.write_metadata(record).await,
+                    Err(e) => error!("Failed to serialize metadata for {}: {}", session.key(), e),
+                }
+
+                self.persist(&session).await;
+                result?;
+
+                // Copying to MP4 reads the whole recording back, so it doesn't hold up the stop
+                let mut remux = None;
+                if let Ok(record) = record {
+                    if let Some(tracks) = remux::mp4_tracks(&record.tracks) {
+                        let files = session.files
+                            .iter()
+                            .filter(|file| !file.segments.is_empty())
+                            .map(|file| file.segments.clone())
+                            .collect();
+                        let manager = self.clone();
+                        let dir = session.dir.clone();
+                        remux = Some(tokio::spawn(async move { manager.remux(record, files, tracks, dir).await }));
+                    }
+                }
+                Ok(Some(StoppedRecording { id: session.id, remux }))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+fn is_jpeg(data: &[u8]) -> bool {
+    data.starts_with(&[0xFF, 0xD8])
+}
+
+// Extension for a file from the start of what is written to it
+fn container_extension(scanner: &WebmScanner, data: &[u8]) -> &'static str {
+    match scanner.doc_type() {
+        Some("webm") => "webm",
+        Some(_) if scanner.tracks().next().is_some() && !scanner.has_video() => "mka",
+        Some(_) => "mkv",
+        // MediaRecorder's first chunk can end before the DocType
+        None if data.len() >= 4 && u32::from_be_bytes([data[0], data[1], data[2], data[3]]) == EBML_HEADER_ID => "webm",
+        None if is_jpeg(data) => "mjpeg",
+        None if data.get(4..8) == Some(b"ftyp".as_slice()) => "mp4",
+        None => "bin",
+    }
+}
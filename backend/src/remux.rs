@@ -0,0 +1,1115 @@
+/*
+ * remux.rs
+ * Purpose: Fragmented MP4 from H.264/Opus recordings and streams
+ *
+ * This file contains:
+ * - mp4_tracks, which tells whether a recording's tracks fit in MP4 as they are
+ * - MatroskaDemuxer, which takes Matroska bytes as they come and hands back
+ *   whole frames, and MatroskaReader, which feeds it a file's stored segments
+ * - SampleConverter: Annex B to length-prefixed H.264, and the avcC and dOps
+ *   configuration boxes
+ * - Fmp4Writer, which cuts samples into moof/mdat fragments at keyframes and
+ *   builds the ftyp and moov init segment without sample tables
+ * - remux_to_mp4, which writes a recording's MP4 copy to storage in one pass,
+ *   along with an index of its fragments
+ *
+ * Frames are copied, never re-encoded, and only the open fragment is held in
+ * memory. Decode order is taken to be presentation order, as in the
+ * baseline-profile H.264 that browsers and cameras publish. The same writer
+ * packages live HLS segments.
+ */
+
+use std::collections::VecDeque;
+use bytes::Bytes;
+use futures::StreamExt;
+use tracing::warn;
+use crate::{
+    error::AppError,
+    models::{FragmentEntry, FragmentIndex, FrameType, TrackInfo},
+    storage::{ByteStream, Storage, UploadSession},
+    webm::{WebmEvent, WebmScanner, WebmTrack},
+};
+
+// The only codecs copied into MP4
+pub const VIDEO_CODEC: &str = "h264";
+pub const AUDIO_CODEC: &str = "opus";
+
+// Recording copies are cut at the first video keyframe past this age
+const FRAGMENT_DURATION_MS: i64 = 2_000;
+
+// How long a fragment may wait for the first video keyframe before it's cut
+// without video, which then stays out of the output
+const VIDEO_WAIT_MS: i64 = 10_000;
+
+// Units per second of each track's timestamps
+const VIDEO_TIMESCALE: u32 = 90_000;
+const AUDIO_TIMESCALE: u32 = 48_000;
+
+// Used for a track's last sample when nothing follows it, i.e. 30 fps and 20 ms packets
+const DEFAULT_VIDEO_DURATION: u32 = 3_000;
+const DEFAULT_AUDIO_DURATION: u32 = 960;
+
+// trun sample flags: sync samples depend on nothing, others are non-sync
+const SYNC_SAMPLE_FLAGS: u32 = 0x0200_0000;
+const NON_SYNC_SAMPLE_FLAGS: u32 = 0x0101_0000;
+
+// Identity transform for mvhd and tkhd
+const MATRIX: [u32; 9] = [0x0001_0000, 0, 0, 0, 0x0001_0000, 0, 0, 0, 0x4000_0000];
+
+// Whether frames in `codec` can be copied into MP4 on a track of this kind
+pub fn is_copyable(track: FrameType, codec: &str) -> bool {
+    match track {
+        FrameType::Video => codec == VIDEO_CODEC,
+        FrameType::Audio => codec == AUDIO_CODEC,
+    }
+}
+
+// Tracks the MP4 copy of a recording carries, or None if any track that got
+// frames would need re-encoding
+pub fn mp4_tracks(tracks: &[TrackInfo]) -> Option<Vec<FrameType>> {
+    let copyable = tracks.iter().all(|info| is_copyable(info.track, &info.codec));
+    (copyable && !tracks.is_empty()).then(|| tracks.iter().map(|info| info.track).collect())
+}
+
+// Write an MP4 copy of a recording's files, each given as its segment keys in
+// order, to `filename` in `dir`; returns the copy's key and size
+pub async fn remux_to_mp4(
+    storage: &Storage,
+    files: Vec<Vec<String>>,
+    tracks: &[FrameType],
+    dir: &str,
+    filename: &str,
+) -> Result<(String, u64), AppError> {
+    let mut readers: Vec<MatroskaReader> = files
+        .into_iter()
+        .map(|segments| MatroskaReader::new(storage.clone(), segments, tracks))
+        .collect();
+    let mut writer = Fmp4Writer::new(tracks, FRAGMENT_DURATION_MS);
+    let mut output = Mp4Output {
+        upload: storage.create_segment(dir, filename).await?,
+        written: 0,
+        index: FragmentIndex { init_size: 0, codecs: None, video_size: None, fragments: Vec::new() },
+    };
+
+    let result = async {
+        // Files holding one track each are merged by timecode
+        let mut next: Vec<Option<Sample>> = Vec::with_capacity(readers.len());
+        for reader in &mut readers {
+            next.push(reader.next().await?);
+        }
+        loop {
+            let earliest = next
+                .iter()
+                .enumerate()
+                .filter_map(|(index, sample)| sample.as_ref().map(|sample| (index, sample.timecode)))
+                .min_by_key(|(_, timecode)| *timecode)
+                .map(|(index, _)| index);
+            let Some(index) = earliest else {
+                break;
+            };
+            if let Some(fragment) = next[index].take().and_then(|sample| writer.push(sample)) {
+                output.write(&writer, fragment).await?;
+            }
+            next[index] = readers[index].next().await?;
+        }
+        if let Some(fragment) = writer.finish() {
+            output.write(&writer, fragment).await?;
+        }
+        if output.written == 0 {
+            return Err(AppError::StreamingError("Recording has no frames that can be copied to MP4".to_string()));
+        }
+        Ok(())
+    }
+    .await;
+
+    let Mp4Output { upload, written, index } = output;
+    if let Err(e) = result {
+        let _ = upload.abort().await;
+        return Err(e);
+    }
+    upload.finalize().await?;
+
+    // HLS playlists for the recording are built from the index
+    let key = format!("{}/{}", dir, filename);
+    match serde_json::to_vec(&index) {
+        Ok(json) => {
+            if let Err(e) = storage.write_sidecar(&key, "fragments.json", &json).await {
+                warn!("Failed to write fragment index for {}: {}", key, e);
+            }
+        }
+        Err(e) => warn!("Failed to serialize fragment index for {}: {}", key, e),
+    }
+    Ok((key, written))
+}
+
+// The MP4 copy being written and where its fragments went
+struct Mp4Output {
+    upload: Box<dyn UploadSession>,
+    written: u64,
+    index: FragmentIndex,
+}
+
+impl Mp4Output {
+    // The init segment goes in ahead of the first fragment
+    async fn write(&mut self, writer: &Fmp4Writer, fragment: Fragment) -> Result<(), AppError> {
+        if self.written == 0 {
+            let init = writer.init_segment().unwrap_or_default();
+            self.upload.append(init).await?;
+            self.written = init.len() as u64;
+            self.index.init_size = self.written;
+            self.index.codecs = writer.codecs();
+            self.index.video_size = writer.video_size();
+        }
+        self.index.fragments.push(FragmentEntry {
+            offset: self.written,
+            size: fragment.data.len() as u64,
+            start_ms: fragment.start_ms,
+            duration_ms: fragment.duration_ms,
+        });
+        self.upload.append(&fragment.data).await?;
+        self.written += fragment.data.len() as u64;
+        Ok(())
+    }
+}
+
+// Codec configuration a track's sample entry is built from
+#[derive(Debug, Clone)]
+pub struct TrackConfig {
+    // avcC record for H.264, OpusHead for Opus
+    pub private: Vec<u8>,
+    pub size: Option<(u32, u32)>,
+}
+
+// One frame, with H.264 already in length-prefixed form
+#[derive(Debug)]
+pub struct Sample {
+    pub track: FrameType,
+    pub keyframe: bool,
+    // Milliseconds
+    pub timecode: i64,
+    pub data: Vec<u8>,
+    // Set on the first sample of a track once its configuration is known
+    pub config: Option<TrackConfig>,
+}
+
+// Turns frames as stored or sent into samples, and works out each track's
+// configuration from the first ones
+#[derive(Debug, Default)]
+pub struct SampleConverter {
+    // Which tracks have had their configuration handed out
+    configured: Vec<FrameType>,
+    // Parameter sets seen in Annex B video, for the avcC
+    sps: Option<Vec<u8>>,
+    pps: Option<Vec<u8>>,
+}
+
+impl SampleConverter {
+    // `private` is the track's avcC or OpusHead. H.264 without an avcC is taken
+    // to be Annex B, as publishers send it and older recordings stored it; a
+    // frame with an IDR slice is a keyframe whatever the container said.
+    pub fn convert(
+        &mut self,
+        track: FrameType,
+        keyframe: bool,
+        timecode: i64,
+        data: Vec<u8>,
+        private: Option<&[u8]>,
+        size: Option<(u32, u32)>,
+    ) -> Option<Sample> {
+        let (data, keyframe, private) = match (track, private) {
+            (FrameType::Video, None) => {
+                let (data, idr) = self.annexb_to_avcc(&data);
+                let private = self.sps.as_deref().zip(self.pps.as_deref()).and_then(|(sps, pps)| avc_config(sps, pps));
+                (data, keyframe || idr, private)
+            }
+            (_, private) => (data, keyframe, private.map(<[u8]>::to_vec)),
+        };
+        if data.is_empty() {
+            return None;
+        }
+
+        let config = match private {
+            Some(private) if !self.configured.contains(&track) => {
+                self.configured.push(track);
+                Some(TrackConfig { private, size })
+            }
+            _ => None,
+        };
+        Some(Sample { track, keyframe, timecode, data, config })
+    }
+
+    // Replace start codes with 4-byte lengths, noting the first SPS and PPS and
+    // whether there's an IDR slice
+    fn annexb_to_avcc(&mut self, data: &[u8]) -> (Vec<u8>, bool) {
+        let mut out = Vec::with_capacity(data.len() + 16);
+        let mut idr = false;
+        for nal in annexb_nals(data) {
+            match nal[0] & 0x1F {
+                5 => idr = true,
+                7 if self.sps.is_none() => self.sps = Some(nal.to_vec()),
+                8 if self.pps.is_none() => self.pps = Some(nal.to_vec()),
+                _ => {}
+            }
+            out.extend_from_slice(&(nal.len() as u32).to_be_bytes());
+            out.extend_from_slice(nal);
+        }
+        (out, idr)
+    }
+}
+
+// A block whose frame bytes haven't all arrived yet
+#[derive(Debug, Clone, Copy)]
+struct PendingBlock {
+    track: FrameType,
+    keyframe: bool,
+    timecode: i64,
+    offset: u64,
+    size: u64,
+}
+
+// Follows a Matroska stream and hands back the frames of its copyable tracks
+pub struct MatroskaDemuxer {
+    tracks: Vec<FrameType>,
+    scanner: WebmScanner,
+    // Bytes pushed from `buf_offset` on, kept while a block still needs them
+    buf: Vec<u8>,
+    buf_offset: u64,
+    pending: VecDeque<PendingBlock>,
+    converter: SampleConverter,
+}
+
+impl MatroskaDemuxer {
+    // Frames are returned for the first track of each kind in `tracks`
+    pub fn new(tracks: &[FrameType]) -> Self {
+        Self {
+            tracks: tracks.to_vec(),
+            scanner: WebmScanner::new(),
+            buf: Vec::new(),
+            buf_offset: 0,
+            pending: VecDeque::new(),
+            converter: SampleConverter::default(),
+        }
+    }
+
+    // The stream's tracks, once its header has been parsed
+    pub fn header_tracks(&self) -> Option<Vec<&WebmTrack>> {
+        self.scanner.init_segment_len().map(|_| self.scanner.tracks().collect())
+    }
+
+    // Whether a block is still waiting for the rest of its bytes
+    pub fn has_pending(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    // Take the next bytes of the stream
+    pub fn push(&mut self, data: &[u8]) -> Result<(), AppError> {
+        let events = self.scanner.feed(data);
+        if let Some(reason) = self.scanner.corruption() {
+            return Err(AppError::StreamingError(format!("Corrupt Matroska stream: {}", reason)));
+        }
+        if self.scanner.failed() {
+            return Err(AppError::StreamingError("Stream is not Matroska".to_string()));
+        }
+        self.buf.extend_from_slice(data);
+
+        for event in events {
+            let WebmEvent::Block { track, keyframe, timecode, offset, size, laced } = event else {
+                continue;
+            };
+            let Some(track) = self.track_kind(track) else {
+                continue;
+            };
+            if laced {
+                return Err(AppError::StreamingError("Laced blocks are not supported".to_string()));
+            }
+            self.pending.push_back(PendingBlock { track, keyframe, timecode, offset, size });
+        }
+        self.trim();
+        Ok(())
+    }
+
+    // Next frame whose bytes have all been pushed
+    pub fn next_sample(&mut self) -> Option<Sample> {
+        loop {
+            let buf_end = self.buf_offset + self.buf.len() as u64;
+            let block = self.pending.front().copied().filter(|b| b.offset + b.size <= buf_end)?;
+            self.pending.pop_front();
+            let start = (block.offset - self.buf_offset) as usize;
+            let data = self.buf[start..start + block.size as usize].to_vec();
+            self.trim();
+
+            let Some(source) = self.source_track(block.track) else {
+                continue;
+            };
+            let private = source.codec_private.clone();
+            let size = source.width.zip(source.height);
+            let sample = self.converter.convert(block.track, block.keyframe, block.timecode, data, private.as_deref(), size);
+            if sample.is_some() {
+                return sample;
+            }
+        }
+    }
+
+    // The Matroska track copied for a kind: the first one in a copyable codec
+    fn source_track(&self, kind: FrameType) -> Option<&WebmTrack> {
+        self.scanner.tracks().find(|t| match kind {
+            FrameType::Video => t.is_video() && t.codec_name() == Some(VIDEO_CODEC),
+            FrameType::Audio => t.is_audio() && t.codec_name() == Some(AUDIO_CODEC),
+        })
+    }
+
+    // Which copied track a Matroska track number carries, if any
+    fn track_kind(&self, number: u64) -> Option<FrameType> {
+        self.tracks
+            .iter()
+            .copied()
+            .find(|kind| self.source_track(*kind).is_some_and(|t| t.number == number))
+    }
+
+    // Drop buffered bytes no pending block needs. Bytes the scanner hasn't
+    // parsed yet are kept too, as a block found there may start in them.
+    fn trim(&mut self) {
+        let buf_end = self.buf_offset + self.buf.len() as u64;
+        let keep_from = self.pending.front().map_or(buf_end, |block| block.offset).min(self.scanner.consumed()).min(buf_end);
+        let drop = (keep_from.saturating_sub(self.buf_offset) as usize).min(self.buf.len());
+        self.buf.drain(..drop);
+        self.buf_offset += drop as u64;
+    }
+}
+
+// Reads a Matroska file's segments in order, as one stream: a rolled-over
+// chunk stream continues its first segment, and every muxed segment starts
+// with its own header
+struct MatroskaReader {
+    storage: Storage,
+    segments: VecDeque<String>,
+    stream: Option<ByteStream>,
+    demuxer: MatroskaDemuxer,
+}
+
+impl MatroskaReader {
+    fn new(storage: Storage, segments: Vec<String>, tracks: &[FrameType]) -> Self {
+        Self {
+            storage,
+            segments: segments.into(),
+            stream: None,
+            demuxer: MatroskaDemuxer::new(tracks),
+        }
+    }
+
+    // Next frame of a track being copied, None at the end of the last segment
+    async fn next(&mut self) -> Result<Option<Sample>, AppError> {
+        loop {
+            if let Some(sample) = self.demuxer.next_sample() {
+                return Ok(Some(sample));
+            }
+            let Some(chunk) = self.read_chunk().await? else {
+                if self.demuxer.has_pending() {
+                    return Err(AppError::StreamingError("Recording ends inside a block".to_string()));
+                }
+                return Ok(None);
+            };
+            self.demuxer.push(&chunk)?;
+        }
+    }
+
+    // Next bytes of the current segment, moving on to the next one at its end
+    async fn read_chunk(&mut self) -> Result<Option<Bytes>, AppError> {
+        loop {
+            if let Some(stream) = self.stream.as_mut() {
+                match stream.next().await {
+                    Some(chunk) => return Ok(Some(chunk?)),
+                    None => self.stream = None,
+                }
+            }
+            let Some(key) = self.segments.pop_front() else {
+                return Ok(None);
+            };
+            self.stream = Some(self.storage.stream_recording(&key, 0, None).await?);
+        }
+    }
+}
+
+// NAL units between Annex B start codes, without trailing zero bytes
+pub fn annexb_nals(data: &[u8]) -> Vec<&[u8]> {
+    let mut nals = Vec::new();
+    let mut start = None;
+    let mut pos = 0;
+    while pos + 3 <= data.len() {
+        if data[pos..pos + 3] == [0, 0, 1] {
+            if let Some(start) = start {
+                nals.push(&data[start..pos]);
+            }
+            pos += 3;
+            start = Some(pos);
+        } else {
+            pos += 1;
+        }
+    }
+    if let Some(start) = start {
+        nals.push(&data[start..]);
+    }
+    nals.into_iter()
+        .map(|nal| &nal[..nal.iter().rposition(|b| *b != 0).map_or(0, |last| last + 1)])
+        .filter(|nal| !nal.is_empty())
+        .collect()
+}
+
+// AVCDecoderConfigurationRecord for one SPS and PPS, with 4-byte NAL lengths
+pub fn avc_config(sps: &[u8], pps: &[u8]) -> Option<Vec<u8>> {
+    if sps.len() < 4 {
+        return None;
+    }
+    let mut config = vec![1, sps[1], sps[2], sps[3], 0xFF, 0xE1];
+    config.extend_from_slice(&(sps.len() as u16).to_be_bytes());
+    config.extend_from_slice(sps);
+    config.push(1);
+    config.extend_from_slice(&(pps.len() as u16).to_be_bytes());
+    config.extend_from_slice(pps);
+    Some(config)
+}
+
+// OpusSpecificBox from the OpusHead Matroska carries, and the channel count
+fn opus_config(head: &[u8]) -> Option<(Vec<u8>, u16)> {
+    if head.len() < 19 || !head.starts_with(b"OpusHead") {
+        return None;
+    }
+    let channels = head[9];
+    let mut dops = vec![0, channels];
+    // OpusHead is little-endian, dOps big-endian
+    dops.extend_from_slice(&u16::from_le_bytes([head[10], head[11]]).to_be_bytes());
+    dops.extend_from_slice(&u32::from_le_bytes([head[12], head[13], head[14], head[15]]).to_be_bytes());
+    dops.extend_from_slice(&i16::from_le_bytes([head[16], head[17]]).to_be_bytes());
+    dops.push(head[18]);
+    // Stream count, coupled count and channel mapping for mapping families above 0
+    if head[18] != 0 {
+        dops.extend_from_slice(&head[19..]);
+    }
+    Some((mp4_box(b"dOps", &dops), u16::from(channels)))
+}
+
+fn mp4_box(kind: &[u8; 4], body: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(8 + body.len());
+    out.extend_from_slice(&(8 + body.len() as u32).to_be_bytes());
+    out.extend_from_slice(kind);
+    out.extend_from_slice(body);
+    out
+}
+
+fn full_box(kind: &[u8; 4], version: u8, flags: u32, body: &[u8]) -> Vec<u8> {
+    let mut full = Vec::with_capacity(4 + body.len());
+    full.extend_from_slice(&((u32::from(version) << 24) | (flags & 0x00FF_FFFF)).to_be_bytes());
+    full.extend_from_slice(body);
+    mp4_box(kind, &full)
+}
+
+fn matrix() -> Vec<u8> {
+    MATRIX.iter().flat_map(|value| value.to_be_bytes()).collect()
+}
+
+struct FragmentSample {
+    time: u64,
+    keyframe: bool,
+    data: Vec<u8>,
+}
+
+// One output track and the samples of the open fragment
+struct TrackWriter {
+    track: FrameType,
+    id: u32,
+    timescale: u32,
+    // avc1 or Opus sample entry, once the configuration is known
+    entry: Option<Vec<u8>>,
+    // RFC 6381 codec string, e.g. "avc1.42c01e", set with the entry
+    codec: String,
+    size: (u32, u32),
+    samples: Vec<FragmentSample>,
+    last_duration: u32,
+}
+
+impl TrackWriter {
+    fn to_units(&self, ms: i64) -> u64 {
+        ms.max(0) as u64 * u64::from(self.timescale) / 1000
+    }
+
+    // Sample durations, the last one running to `next` if known
+    fn durations(&mut self, next: Option<u64>) -> Vec<u32> {
+        let mut durations = Vec::with_capacity(self.samples.len());
+        for (index, sample) in self.samples.iter().enumerate() {
+            let following = self.samples.get(index + 1).map(|s| s.time).or(next);
+            let duration = match following {
+                Some(time) => time.saturating_sub(sample.time).min(u64::from(u32::MAX)) as u32,
+                None => self.last_duration,
+            };
+            self.last_duration = duration;
+            durations.push(duration);
+        }
+        durations
+    }
+
+    fn trak(&self, entry: &[u8]) -> Vec<u8> {
+        let video = self.track == FrameType::Video;
+
+        let mut tkhd = Vec::new();
+        tkhd.extend_from_slice(&[0; 8]);
+        tkhd.extend_from_slice(&self.id.to_be_bytes());
+        tkhd.extend_from_slice(&[0; 4]);
+        // Duration is unknown up front; the fragments carry it
+        tkhd.extend_from_slice(&0u32.to_be_bytes());
+        tkhd.extend_from_slice(&[0; 8]);
+        tkhd.extend_from_slice(&0u16.to_be_bytes());
+        tkhd.extend_from_slice(&0u16.to_be_bytes());
+        tkhd.extend_from_slice(&(if video { 0u16 } else { 0x0100 }).to_be_bytes());
+        tkhd.extend_from_slice(&[0; 2]);
+        tkhd.extend_from_slice(&matrix());
+        tkhd.extend_from_slice(&(self.size.0 << 16).to_be_bytes());
+        tkhd.extend_from_slice(&(self.size.1 << 16).to_be_bytes());
+
+        let mut mdhd = Vec::new();
+        mdhd.extend_from_slice(&[0; 8]);
+        mdhd.extend_from_slice(&self.timescale.to_be_bytes());
+        mdhd.extend_from_slice(&0u32.to_be_bytes());
+        // "und"
+        mdhd.extend_from_slice(&0x55C4u16.to_be_bytes());
+        mdhd.extend_from_slice(&[0; 2]);
+
+        let (handler, name): (&[u8; 4], &[u8]) = if video {
+            (b"vide", b"VideoHandler\0")
+        } else {
+            (b"soun", b"SoundHandler\0")
+        };
+        let mut hdlr = vec![0; 4];
+        hdlr.extend_from_slice(handler);
+        hdlr.extend_from_slice(&[0; 12]);
+        hdlr.extend_from_slice(name);
+
+        let media_header = if video {
+            full_box(b"vmhd", 0, 1, &[0; 8])
+        } else {
+            full_box(b"smhd", 0, 0, &[0; 4])
+        };
+        let mut dref = 1u32.to_be_bytes().to_vec();
+        dref.extend_from_slice(&full_box(b"url ", 0, 1, &[]));
+        let dinf = mp4_box(b"dinf", &full_box(b"dref", 0, 0, &dref));
+
+        // Sample tables stay empty; every sample is in a fragment
+        let mut stsd = 1u32.to_be_bytes().to_vec();
+        stsd.extend_from_slice(entry);
+        let mut stbl = full_box(b"stsd", 0, 0, &stsd);
+        stbl.extend_from_slice(&full_box(b"stts", 0, 0, &[0; 4]));
+        stbl.extend_from_slice(&full_box(b"stsc", 0, 0, &[0; 4]));
+        stbl.extend_from_slice(&full_box(b"stsz", 0, 0, &[0; 8]));
+        stbl.extend_from_slice(&full_box(b"stco", 0, 0, &[0; 4]));
+
+        let mut minf = media_header;
+        minf.extend_from_slice(&dinf);
+        minf.extend_from_slice(&mp4_box(b"stbl", &stbl));
+
+        let mut mdia = full_box(b"mdhd", 0, 0, &mdhd);
+        mdia.extend_from_slice(&full_box(b"hdlr", 0, 0, &hdlr));
+        mdia.extend_from_slice(&mp4_box(b"minf", &minf));
+
+        let mut trak = full_box(b"tkhd", 0, 3, &tkhd);
+        trak.extend_from_slice(&mp4_box(b"mdia", &mdia));
+        mp4_box(b"trak", &trak)
+    }
+
+    fn traf(&self, durations: &[u32], data_offset: u32) -> Vec<u8> {
+        // Sample data offsets count from the start of the moof
+        let mut traf = full_box(b"tfhd", 0, 0x02_0000, &self.id.to_be_bytes());
+        let base_time = self.samples.first().map_or(0, |sample| sample.time);
+        traf.extend_from_slice(&full_box(b"tfdt", 1, 0, &base_time.to_be_bytes()));
+
+        let mut trun = (self.samples.len() as u32).to_be_bytes().to_vec();
+        trun.extend_from_slice(&data_offset.to_be_bytes());
+        for (sample, duration) in self.samples.iter().zip(durations) {
+            let flags = if sample.keyframe || self.track == FrameType::Audio {
+                SYNC_SAMPLE_FLAGS
+            } else {
+                NON_SYNC_SAMPLE_FLAGS
+            };
+            trun.extend_from_slice(&duration.to_be_bytes());
+            trun.extend_from_slice(&(sample.data.len() as u32).to_be_bytes());
+            trun.extend_from_slice(&flags.to_be_bytes());
+        }
+        // Data offset, then duration, size and flags per sample
+        traf.extend_from_slice(&full_box(b"trun", 0, 0x00_0701, &trun));
+        mp4_box(b"traf", &traf)
+    }
+}
+
+// Sample entry for a track's configuration, None if it doesn't parse
+fn sample_entry(track: FrameType, config: &TrackConfig) -> Option<(Vec<u8>, (u32, u32))> {
+    match track {
+        FrameType::Video => {
+            // avcC starts with version 1
+            if config.private.first() != Some(&1) {
+                return None;
+            }
+            let (width, height) = config.size.unwrap_or((0, 0));
+            let mut avc1 = vec![0; 6];
+            avc1.extend_from_slice(&1u16.to_be_bytes());
+            avc1.extend_from_slice(&[0; 16]);
+            avc1.extend_from_slice(&(width as u16).to_be_bytes());
+            avc1.extend_from_slice(&(height as u16).to_be_bytes());
+            // 72 dpi
+            avc1.extend_from_slice(&0x0048_0000u32.to_be_bytes());
+            avc1.extend_from_slice(&0x0048_0000u32.to_be_bytes());
+            avc1.extend_from_slice(&[0; 4]);
+            avc1.extend_from_slice(&1u16.to_be_bytes());
+            avc1.extend_from_slice(&[0; 32]);
+            avc1.extend_from_slice(&0x0018u16.to_be_bytes());
+            avc1.extend_from_slice(&(-1i16).to_be_bytes());
+            avc1.extend_from_slice(&mp4_box(b"avcC", &config.private));
+            Some((mp4_box(b"avc1", &avc1), (width, height)))
+        }
+        FrameType::Audio => {
+            let (dops, channels) = opus_config(&config.private)?;
+            let mut opus = vec![0; 6];
+            opus.extend_from_slice(&1u16.to_be_bytes());
+            opus.extend_from_slice(&[0; 8]);
+            opus.extend_from_slice(&channels.to_be_bytes());
+            opus.extend_from_slice(&16u16.to_be_bytes());
+            opus.extend_from_slice(&[0; 4]);
+            opus.extend_from_slice(&(AUDIO_TIMESCALE << 16).to_be_bytes());
+            opus.extend_from_slice(&dops);
+            Some((mp4_box(b"Opus", &opus), (0, 0)))
+        }
+    }
+}
+
+// avc1.PPCCLL from the profile, constraints and level in the avcC
+fn codec_string(track: FrameType, config: &TrackConfig) -> String {
+    match (track, config.private.get(1..4)) {
+        (FrameType::Video, Some(profile)) => format!("avc1.{:02x}{:02x}{:02x}", profile[0], profile[1], profile[2]),
+        (FrameType::Video, None) => "avc1".to_string(),
+        (FrameType::Audio, _) => "opus".to_string(),
+    }
+}
+
+// A finished moof/mdat pair
+#[derive(Debug)]
+pub struct Fragment {
+    pub data: Vec<u8>,
+    // Timecode of its first sample and how long it plays, in milliseconds
+    pub start_ms: i64,
+    pub duration_ms: i64,
+}
+
+pub struct Fmp4Writer {
+    tracks: Vec<TrackWriter>,
+    fragment_ms: i64,
+    has_video: bool,
+    // Whether the first video keyframe has been seen; video before it can't be decoded
+    video_started: bool,
+    // ftyp and moov, built when the first fragment is cut
+    init: Option<Vec<u8>>,
+    sequence: u32,
+    // Timecode of the open fragment's first sample, in milliseconds
+    fragment_start: Option<i64>,
+}
+
+impl Fmp4Writer {
+    // Fragments run from one video keyframe to the first one at least
+    // `fragment_ms` later, or for `fragment_ms` without video
+    pub fn new(tracks: &[FrameType], fragment_ms: i64) -> Self {
+        let tracks: Vec<TrackWriter> = tracks
+            .iter()
+            .enumerate()
+            .map(|(index, track)| TrackWriter {
+                track: *track,
+                id: index as u32 + 1,
+                timescale: match track {
+                    FrameType::Video => VIDEO_TIMESCALE,
+                    FrameType::Audio => AUDIO_TIMESCALE,
+                },
+                entry: None,
+                codec: String::new(),
+                size: (0, 0),
+                samples: Vec::new(),
+                last_duration: match track {
+                    FrameType::Video => DEFAULT_VIDEO_DURATION,
+                    FrameType::Audio => DEFAULT_AUDIO_DURATION,
+                },
+            })
+            .collect();
+        Self {
+            has_video: tracks.iter().any(|t| t.track == FrameType::Video),
+            tracks,
+            fragment_ms,
+            video_started: false,
+            init: None,
+            sequence: 0,
+            fragment_start: None,
+        }
+    }
+
+    // The init segment, once the first fragment has been cut
+    pub fn init_segment(&self) -> Option<&[u8]> {
+        self.init.as_deref()
+    }
+
+    // Codec strings of the tracks in the init segment, comma-separated as in
+    // DASH and HLS codecs attributes
+    pub fn codecs(&self) -> Option<String> {
+        self.init.as_ref()?;
+        let codecs: Vec<&str> = self.tracks.iter().filter(|t| t.entry.is_some()).map(|t| t.codec.as_str()).collect();
+        Some(codecs.join(","))
+    }
+
+    // Width and height written for the video track, if known
+    pub fn video_size(&self) -> Option<(u32, u32)> {
+        self.tracks
+            .iter()
+            .find(|t| t.track == FrameType::Video && t.entry.is_some())
+            .map(|t| t.size)
+            .filter(|(width, height)| *width > 0 && *height > 0)
+    }
+
+    // Add a sample; returns the open fragment if this sample starts a new one
+    pub fn push(&mut self, sample: Sample) -> Option<Fragment> {
+        let index = self.tracks.iter().position(|t| t.track == sample.track)?;
+        if let Some(config) = &sample.config {
+            let track = &mut self.tracks[index];
+            if track.entry.is_none() && self.init.is_none() {
+                if let Some((entry, size)) = sample_entry(sample.track, config) {
+                    track.entry = Some(entry);
+                    track.codec = codec_string(sample.track, config);
+                    track.size = size;
+                }
+            }
+        }
+        // Nothing of a track is kept until its configuration is known
+        self.tracks[index].entry.as_ref()?;
+        if sample.track == FrameType::Video && !self.video_started {
+            if !sample.keyframe {
+                return None;
+            }
+            self.video_started = true;
+        }
+
+        // Fragments start on video keyframes so each one can be decoded on its own
+        let cut = self.fragment_start.is_some_and(|start| {
+            let age = sample.timecode - start;
+            match (self.has_video, self.video_started) {
+                (true, true) => age >= self.fragment_ms && sample.track == FrameType::Video && sample.keyframe,
+                (true, false) => age >= VIDEO_WAIT_MS,
+                (false, _) => age >= self.fragment_ms,
+            }
+        });
+        let fragment = if cut {
+            let next = self.tracks[index].to_units(sample.timecode);
+            self.flush(Some((index, next)))
+        } else {
+            None
+        };
+
+        let track = &mut self.tracks[index];
+        let time = track.to_units(sample.timecode);
+        track.samples.push(FragmentSample { time, keyframe: sample.keyframe, data: sample.data });
+        self.fragment_start.get_or_insert(sample.timecode);
+        fragment
+    }
+
+    // Cut the open fragment, e.g. at the end of the input
+    pub fn finish(&mut self) -> Option<Fragment> {
+        self.flush(None)
+    }
+
+    // Cut the open fragment; `next` is the start of the sample that follows it
+    // on one track
+    fn flush(&mut self, next: Option<(usize, u64)>) -> Option<Fragment> {
+        if self.init.is_none() {
+            // Tracks whose configuration never turned up are left out
+            if self.tracks.iter().all(|t| t.entry.is_none()) {
+                return None;
+            }
+            self.init = Some(self.header());
+        }
+        let start_ms = self.fragment_start.take()?;
+        self.sequence += 1;
+
+        let durations: Vec<Vec<u32>> = (0..self.tracks.len())
+            .map(|index| {
+                let following = next.filter(|(track, _)| *track == index).map(|(_, time)| time);
+                self.tracks[index].durations(following)
+            })
+            .collect();
+        let fragment: Vec<usize> = (0..self.tracks.len()).filter(|i| !self.tracks[*i].samples.is_empty()).collect();
+        let duration_ms = fragment
+            .iter()
+            .map(|index| {
+                let total: u64 = durations[*index].iter().map(|d| u64::from(*d)).sum();
+                (total * 1000 / u64::from(self.tracks[*index].timescale)) as i64
+            })
+            .max()
+            .unwrap_or(0);
+
+        // trafs don't change size with their data offsets, so size the moof first
+        let moof = |offsets: &[u32]| {
+            let mut moof = full_box(b"mfhd", 0, 0, &self.sequence.to_be_bytes());
+            for (index, offset) in fragment.iter().zip(offsets) {
+                moof.extend_from_slice(&self.tracks[*index].traf(&durations[*index], *offset));
+            }
+            mp4_box(b"moof", &moof)
+        };
+        let moof_len = moof(&vec![0; fragment.len()]).len() as u32;
+        let mut offsets = Vec::with_capacity(fragment.len());
+        let mut offset = moof_len + 8;
+        for index in &fragment {
+            offsets.push(offset);
+            offset += self.tracks[*index].samples.iter().map(|s| s.data.len() as u32).sum::<u32>();
+        }
+        let mut data = moof(&offsets);
+
+        let mut mdat = Vec::with_capacity((offset - moof_len) as usize);
+        for index in &fragment {
+            for sample in self.tracks[*index].samples.drain(..) {
+                mdat.extend_from_slice(&sample.data);
+            }
+        }
+        data.extend_from_slice(&mp4_box(b"mdat", &mdat));
+        Some(Fragment { data, start_ms, duration_ms })
+    }
+
+    fn header(&self) -> Vec<u8> {
+        let mut ftyp = b"iso6".to_vec();
+        ftyp.extend_from_slice(&0u32.to_be_bytes());
+        for brand in [b"iso6", b"isom", b"iso2", b"avc1", b"mp41"] {
+            ftyp.extend_from_slice(brand);
+        }
+
+        let mut mvhd = Vec::new();
+        mvhd.extend_from_slice(&[0; 8]);
+        mvhd.extend_from_slice(&1000u32.to_be_bytes());
+        mvhd.extend_from_slice(&0u32.to_be_bytes());
+        mvhd.extend_from_slice(&0x0001_0000u32.to_be_bytes());
+        mvhd.extend_from_slice(&0x0100u16.to_be_bytes());
+        mvhd.extend_from_slice(&[0; 10]);
+        mvhd.extend_from_slice(&matrix());
+        mvhd.extend_from_slice(&[0; 24]);
+        mvhd.extend_from_slice(&(self.tracks.len() as u32 + 1).to_be_bytes());
+
+        let mut moov = full_box(b"mvhd", 0, 0, &mvhd);
+        let mut mvex = Vec::new();
+        for track in &self.tracks {
+            let Some(entry) = &track.entry else {
+                continue;
+            };
+            moov.extend_from_slice(&track.trak(entry));
+            let mut trex = track.id.to_be_bytes().to_vec();
+            trex.extend_from_slice(&1u32.to_be_bytes());
+            trex.extend_from_slice(&[0; 12]);
+            mvex.extend_from_slice(&full_box(b"trex", 0, 0, &trex));
+        }
+        moov.extend_from_slice(&mp4_box(b"mvex", &mvex));
+
+        let mut out = mp4_box(b"ftyp", &ftyp);
+        out.extend_from_slice(&mp4_box(b"moov", &moov));
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SPS: [u8; 5] = [0x67, 0x42, 0xC0, 0x1E, 0xDA];
+    const PPS: [u8; 3] = [0x68, 0xCE, 0x3C];
+
+    fn video_config() -> TrackConfig {
+        TrackConfig { private: avc_config(&SPS, &PPS).unwrap(), size: Some((640, 360)) }
+    }
+
+    fn audio_config() -> TrackConfig {
+        TrackConfig { private: crate::mux::opus_head(2), size: None }
+    }
+
+    fn sample(track: FrameType, keyframe: bool, timecode: i64, config: Option<TrackConfig>) -> Sample {
+        let data = match track {
+            FrameType::Video => vec![0, 0, 0, 4, 0x65, 1, 2, 3],
+            FrameType::Audio => vec![0xFC, 0xFF, 0xFE],
+        };
+        Sample { track, keyframe, timecode, data, config }
+    }
+
+    // Boxes laid end to end, as (type, body)
+    fn boxes(mut data: &[u8]) -> Vec<([u8; 4], &[u8])> {
+        let mut out = Vec::new();
+        while data.len() >= 8 {
+            let size = u32::from_be_bytes(data[..4].try_into().unwrap()) as usize;
+            out.push((data[4..8].try_into().unwrap(), &data[8..size]));
+            data = &data[size..];
+        }
+        out
+    }
+
+    fn children<'a>(data: &'a [u8], kind: &[u8; 4]) -> Vec<&'a [u8]> {
+        boxes(data).into_iter().filter(|(k, _)| k == kind).map(|(_, body)| body).collect()
+    }
+
+    #[test]
+    fn splits_nals_on_three_and_four_byte_start_codes() {
+        let data = [
+            0, 0, 0, 1, 0x67, 1, 2, 3,
+            0, 0, 1, 0x68, 4,
+            0, 0, 0, 1, 0x65, 5, 0, 6,
+            0, 0,
+        ];
+        let nals = annexb_nals(&data);
+        assert_eq!(nals, vec![&[0x67, 1, 2, 3][..], &[0x68, 4], &[0x65, 5, 0, 6]]);
+
+        assert!(annexb_nals(&[0x65, 1, 2]).is_empty());
+        assert!(annexb_nals(&[0, 0, 1, 0, 0, 0, 1]).is_empty());
+    }
+
+    #[test]
+    fn converts_annexb_to_length_prefixed() {
+        let mut converter = SampleConverter::default();
+        let mut frame = vec![0, 0, 0, 1];
+        frame.extend_from_slice(&SPS);
+        frame.extend_from_slice(&[0, 0, 1]);
+        frame.extend_from_slice(&PPS);
+        frame.extend_from_slice(&[0, 0, 0, 1, 0x65, 0x88]);
+
+        let sample = converter.convert(FrameType::Video, false, 0, frame, None, Some((640, 360))).unwrap();
+        let mut expected = vec![0, 0, 0, 5];
+        expected.extend_from_slice(&SPS);
+        expected.extend_from_slice(&[0, 0, 0, 3]);
+        expected.extend_from_slice(&PPS);
+        expected.extend_from_slice(&[0, 0, 0, 2, 0x65, 0x88]);
+        assert_eq!(sample.data, expected);
+        // The IDR slice makes it a keyframe
+        assert!(sample.keyframe);
+        assert_eq!(sample.config.unwrap().private, avc_config(&SPS, &PPS).unwrap());
+
+        // The configuration is only handed out once
+        let sample = converter.convert(FrameType::Video, false, 33, vec![0, 0, 1, 0x41, 0x9A], None, None).unwrap();
+        assert_eq!(sample.data, [0, 0, 0, 2, 0x41, 0x9A]);
+        assert!(!sample.keyframe);
+        assert!(sample.config.is_none());
+    }
+
+    #[test]
+    fn builds_avcc() {
+        let config = avc_config(&SPS, &PPS).unwrap();
+        let mut expected = vec![1, 0x42, 0xC0, 0x1E, 0xFF, 0xE1, 0, 5];
+        expected.extend_from_slice(&SPS);
+        expected.extend_from_slice(&[1, 0, 3]);
+        expected.extend_from_slice(&PPS);
+        assert_eq!(config, expected);
+
+        assert!(avc_config(&[0x67, 0x42], &PPS).is_none());
+        assert_eq!(codec_string(FrameType::Video, &video_config()), "avc1.42c01e");
+    }
+
+    #[test]
+    fn builds_dops_from_opus_head() {
+        let mut head = b"OpusHead".to_vec();
+        head.extend_from_slice(&[1, 2, 0x38, 0x01, 0x80, 0xBB, 0, 0, 0xFE, 0xFF, 0]);
+        let (dops, channels) = opus_config(&head).unwrap();
+        assert_eq!(channels, 2);
+        assert_eq!(
+            dops,
+            [0, 0, 0, 19, b'd', b'O', b'p', b's', 0, 2, 0x01, 0x38, 0, 0, 0xBB, 0x80, 0xFF, 0xFE, 0]
+        );
+
+        // Mapping family 1 carries its channel mapping table through
+        let mut surround = head.clone();
+        surround[9] = 6;
+        surround[18] = 1;
+        surround.extend_from_slice(&[4, 2, 0, 4, 1, 2, 3, 5]);
+        let (dops, channels) = opus_config(&surround).unwrap();
+        assert_eq!(channels, 6);
+        assert_eq!(&dops[8..], [0, 6, 0x01, 0x38, 0, 0, 0xBB, 0x80, 0xFF, 0xFE, 1, 4, 2, 0, 4, 1, 2, 3, 5]);
+
+        assert!(opus_config(&head[..18]).is_none());
+        assert!(opus_config(b"OpusTags12345678901").is_none());
+    }
+
+    #[test]
+    fn data_offsets_point_into_the_mdat() {
+        let mut writer = Fmp4Writer::new(&[FrameType::Video, FrameType::Audio], FRAGMENT_DURATION_MS);
+        assert!(writer.push(sample(FrameType::Video, true, 0, Some(video_config()))).is_none());
+        assert!(writer.push(sample(FrameType::Audio, true, 0, Some(audio_config()))).is_none());
+        assert!(writer.push(sample(FrameType::Audio, true, 20, None)).is_none());
+        let fragment = writer.finish().unwrap();
+
+        let top = boxes(&fragment.data);
+        assert_eq!(top.iter().map(|(kind, _)| kind).collect::<Vec<_>>(), [b"moof", b"mdat"]);
+        let moof_len = top[0].1.len() as u32 + 8;
+        let mdat = top[1].1;
+
+        let trafs = children(top[0].1, b"traf");
+        assert_eq!(trafs.len(), 2);
+        let trun_offset = |traf: &[u8]| {
+            let trun = children(traf, b"trun")[0];
+            u32::from_be_bytes(trun[8..12].try_into().unwrap())
+        };
+        // Offsets count from the moof's first byte and skip the mdat header
+        assert_eq!(trun_offset(trafs[0]), moof_len + 8);
+        assert_eq!(trun_offset(trafs[1]), moof_len + 8 + 8);
+        let video_start = (trun_offset(trafs[0]) - moof_len - 8) as usize;
+        assert_eq!(&mdat[video_start..video_start + 8], [0, 0, 0, 4, 0x65, 1, 2, 3]);
+        assert_eq!(&mdat[8..], [0xFC, 0xFF, 0xFE, 0xFC, 0xFF, 0xFE]);
+
+        let init = writer.init_segment().unwrap();
+        assert_eq!(writer.codecs().as_deref(), Some("avc1.42c01e,opus"));
+        assert_eq!(writer.video_size(), Some((640, 360)));
+        assert_eq!(boxes(init).iter().map(|(kind, _)| kind).collect::<Vec<_>>(), [b"ftyp", b"moov"]);
+    }
+
+    #[test]
+    fn cuts_fragments_at_keyframes() {
+        let mut writer = Fmp4Writer::new(&[FrameType::Video, FrameType::Audio], 2_000);
+        // Video before the first keyframe can't be decoded and is dropped
+        assert!(writer.push(sample(FrameType::Video, false, 0, Some(video_config()))).is_none());
+        assert!(writer.push(sample(FrameType::Audio, true, 0, Some(audio_config()))).is_none());
+        assert!(writer.push(sample(FrameType::Video, true, 100, None)).is_none());
+        assert!(writer.push(sample(FrameType::Video, false, 1_000, None)).is_none());
+        // Old enough, but not a keyframe
+        assert!(writer.push(sample(FrameType::Video, false, 2_500, None)).is_none());
+        assert!(writer.push(sample(FrameType::Audio, true, 20, None)).is_none());
+
+        let fragment = writer.push(sample(FrameType::Video, true, 3_000, None)).unwrap();
+        assert_eq!(fragment.start_ms, 0);
+        // The video runs up to the keyframe that starts the next fragment
+        assert_eq!(fragment.duration_ms, 2_900);
+        let moof = boxes(&fragment.data)[0].1;
+        let trafs = children(moof, b"traf");
+        let sample_count = |traf: &[u8]| {
+            let trun = children(traf, b"trun")[0];
+            u32::from_be_bytes(trun[4..8].try_into().unwrap())
+        };
+        assert_eq!(trafs.iter().map(|traf| sample_count(traf)).collect::<Vec<_>>(), [3, 2]);
+
+        let fragment = writer.finish().unwrap();
+        assert_eq!(fragment.start_ms, 3_000);
+        assert!(writer.finish().is_none());
+    }
+
+    #[test]
+    fn stops_waiting_for_video() {
+        let mut writer = Fmp4Writer::new(&[FrameType::Video, FrameType::Audio], 2_000);
+        assert!(writer.push(sample(FrameType::Audio, true, 0, Some(audio_config()))).is_none());
+        for timecode in (20..VIDEO_WAIT_MS).step_by(20) {
+            assert!(writer.push(sample(FrameType::Audio, true, timecode, None)).is_none());
+        }
+
+        // No keyframe ever arrived, so audio alone is cut once the wait is up
+        let fragment = writer.push(sample(FrameType::Audio, true, VIDEO_WAIT_MS, None)).unwrap();
+        assert_eq!(fragment.start_ms, 0);
+        assert_eq!(fragment.duration_ms, VIDEO_WAIT_MS);
+        assert_eq!(writer.codecs().as_deref(), Some("opus"));
+        assert_eq!(children(boxes(&fragment.data)[0].1, b"traf").len(), 1);
+    }
+
+    #[test]
+    fn cuts_audio_only_by_duration() {
+        let mut writer = Fmp4Writer::new(&[FrameType::Audio], 2_000);
+        assert!(writer.push(sample(FrameType::Audio, true, 0, Some(audio_config()))).is_none());
+        assert!(writer.push(sample(FrameType::Audio, true, 1_980, None)).is_none());
+        let fragment = writer.push(sample(FrameType::Audio, true, 2_000, None)).unwrap();
+        assert_eq!(fragment.duration_ms, 2_000);
+    }
+}
@@ -0,0 +1,540 @@
+/*
+ * repository.rs
+ * Purpose: Persistence for users, rooms, recordings and room configs
+ *
+ * This file contains:
+ * - Repository, dispatching to PostgreSQL or an in-memory store
+ * - Embedded sqlx migrations run at startup
+ * - Row mapping between the database schema and models.rs
+ */
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+};
+use chrono::{DateTime, Utc};
+use sqlx::{
+    postgres::{PgPool, PgPoolOptions, PgRow},
+    Row,
+};
+use tracing::info;
+use uuid::Uuid;
+use crate::{
+    error::AppError,
+    models::{Recording, RecordingStatus, Room, RoomConfig, TrackInfo, User},
+};
+
+static MIGRATOR: sqlx::migrate::Migrator = sqlx::migrate!("./migrations");
+
+#[derive(Clone)]
+pub enum Repository {
+    Postgres(PgPool),
+    Memory(MemoryStore),
+}
+
+impl Repository {
+    // Connect to PostgreSQL and bring the schema up to date
+    pub async fn connect(database_url: &str) -> Result<Self, AppError> {
+        let pool = PgPoolOptions::new()
+            .max_connections(10)
+            .connect(database_url)
+            .await?;
+
+        MIGRATOR.run(&pool).await?;
+        info!("Database migrations applied");
+
+        Ok(Repository::Postgres(pool))
+    }
+
+    pub fn memory() -> Self {
+        Repository::Memory(MemoryStore::new())
+    }
+
+    // Users
+
+    pub async fn insert_user(&self, user: &User) -> Result<(), AppError> {
+        match self {
+            Repository::Postgres(pool) => {
+                sqlx::query(
+                    "INSERT INTO users (id, api_key, quota_limit, quota_used, created_at)
+                     VALUES ($1, $2, $3, $4, COALESCE($5, CURRENT_TIMESTAMP))",
+                )
+                .bind(user.id)
+                .bind(&user.api_key)
+                .bind(user.quota_limit)
+                .bind(user.quota_used)
+                .bind(user.created_at)
+                .execute(pool)
+                .await?;
+                Ok(())
+            }
+            Repository::Memory(store) => {
+                let mut user = user.clone();
+                user.created_at.get_or_insert_with(Utc::now);
+                store.users.write().unwrap().insert(user.id, user);
+                Ok(())
+            }
+        }
+    }
+
+    pub async fn get_user(&self, id: Uuid) -> Result<Option<User>, AppError> {
+        match self {
+            Repository::Postgres(pool) => Ok(sqlx::query_as::<_, User>(
+                "SELECT id, api_key, quota_limit, quota_used, created_at FROM users WHERE id = $1",
+            )
+            .bind(id)
+            .fetch_optional(pool)
+            .await?),
+            Repository::Memory(store) => Ok(store.users.read().unwrap().get(&id).cloned()),
+        }
+    }
+
+    pub async fn get_user_by_api_key(&self, api_key: &str) -> Result<Option<User>, AppError> {
+        match self {
+            Repository::Postgres(pool) => Ok(sqlx::query_as::<_, User>(
+                "SELECT id, api_key, quota_limit, quota_used, created_at FROM users WHERE api_key = $1",
+            )
+            .bind(api_key)
+            .fetch_optional(pool)
+            .await?),
+            Repository::Memory(store) => Ok(store
+                .users
+                .read()
+                .unwrap()
+                .values()
+                .find(|user| user.api_key == api_key)
+                .cloned()),
+        }
+    }
+
+    // Rooms
+
+    pub async fn insert_room(&self, room: &Room) -> Result<(), AppError> {
+        match self {
+            Repository::Postgres(pool) => {
+                sqlx::query(
+                    "INSERT INTO rooms (id, user_id, room_id, name, max_participants, config, created_at, last_active,
+                                        state, closed_at)
+                     VALUES ($1, $2, $3, $4, $5, $6, COALESCE($7, CURRENT_TIMESTAMP), $8, $9, $10)",
+                )
+                .bind(room.id)
+                .bind(room.user_id)
+                .bind(&room.room_id)
+                .bind(&room.name)
+                .bind(room.max_participants)
+                .bind(&room.config)
+                .bind(room.created_at)
+                .bind(room.last_active)
+                .bind(&room.state)
+                .bind(room.closed_at)
+                .execute(pool)
+                .await?;
+                Ok(())
+            }
+            Repository::Memory(store) => {
+                let mut room = room.clone();
+                room.created_at.get_or_insert_with(Utc::now);
+                store.rooms.write().unwrap().insert(room.room_id.clone(), room);
+                Ok(())
+            }
+        }
+    }
+
+    pub async fn get_room(&self, room_id: &str) -> Result<Option<Room>, AppError> {
+        match self {
+            Repository::Postgres(pool) => Ok(sqlx::query_as::<_, Room>(
+                "SELECT id, user_id, room_id, name, max_participants, config, created_at, last_active, state, closed_at
+                 FROM rooms WHERE room_id = $1",
+            )
+            .bind(room_id)
+            .fetch_optional(pool)
+            .await?),
+            Repository::Memory(store) => Ok(store.rooms.read().unwrap().get(room_id).cloned()),
+        }
+    }
+
+    // List rooms, optionally restricted to one owner
+    pub async fn list_rooms(&self, user_id: Option<Uuid>) -> Result<Vec<Room>, AppError> {
+        match self {
+            Repository::Postgres(pool) => Ok(sqlx::query_as::<_, Room>(
+                "SELECT id, user_id, room_id, name, max_participants, config, created_at, last_active, state, closed_at
+                 FROM rooms WHERE ($1::UUID IS NULL OR user_id = $1) ORDER BY created_at",
+            )
+            .bind(user_id)
+            .fetch_all(pool)
+            .await?),
+            Repository::Memory(store) => {
+                let rooms = store.rooms.read().unwrap();
+                let mut rooms: Vec<Room> = rooms.values()
+                    .filter(|room| user_id.is_none_or(|id| room.user_id == id))
+                    .cloned()
+                    .collect();
+                rooms.sort_by_key(|room| room.created_at);
+                Ok(rooms)
+            }
+        }
+    }
+
+    pub async fn touch_room(&self, room_id: &str) -> Result<(), AppError> {
+        let now = Utc::now();
+        match self {
+            Repository::Postgres(pool) => {
+                sqlx::query("UPDATE rooms SET last_active = $1 WHERE room_id = $2")
+                    .bind(now)
+                    .bind(room_id)
+                    .execute(pool)
+                    .await?;
+                Ok(())
+            }
+            Repository::Memory(store) => {
+                if let Some(room) = store.rooms.write().unwrap().get_mut(room_id) {
+                    room.last_active = Some(now);
+                }
+                Ok(())
+            }
+        }
+    }
+
+    pub async fn update_room_details(
+        &self,
+        room_id: &str,
+        name: &str,
+        max_participants: i32,
+        config: &Option<serde_json::Value>,
+    ) -> Result<(), AppError> {
+        match self {
+            Repository::Postgres(pool) => {
+                sqlx::query("UPDATE rooms SET name = $1, max_participants = $2, config = $3 WHERE room_id = $4")
+                    .bind(name)
+                    .bind(max_participants)
+                    .bind(config)
+                    .bind(room_id)
+                    .execute(pool)
+                    .await?;
+                Ok(())
+            }
+            Repository::Memory(store) => {
+                if let Some(room) = store.rooms.write().unwrap().get_mut(room_id) {
+                    room.name = name.to_string();
+                    room.max_participants = max_participants;
+                    room.config = config.clone();
+                }
+                Ok(())
+            }
+        }
+    }
+
+    pub async fn set_room_state(&self, room_id: &str, state: &str, closed_at: Option<DateTime<Utc>>) -> Result<(), AppError> {
+        match self {
+            Repository::Postgres(pool) => {
+                sqlx::query("UPDATE rooms SET state = $1, closed_at = $2 WHERE room_id = $3")
+                    .bind(state)
+                    .bind(closed_at)
+                    .bind(room_id)
+                    .execute(pool)
+                    .await?;
+                Ok(())
+            }
+            Repository::Memory(store) => {
+                if let Some(room) = store.rooms.write().unwrap().get_mut(room_id) {
+                    room.state = state.to_string();
+                    room.closed_at = closed_at;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    // Remove a room together with its recordings, config and metrics rows
+    pub async fn delete_room(&self, room_id: &str) -> Result<(), AppError> {
+        match self {
+            Repository::Postgres(pool) => {
+                let mut tx = pool.begin().await?;
+                for table in ["stream_metrics", "recordings", "room_configs"] {
+                    sqlx::query(&format!(
+                        "DELETE FROM {} WHERE room_id = (SELECT id FROM rooms WHERE room_id = $1)",
+                        table
+                    ))
+                    .bind(room_id)
+                    .execute(&mut *tx)
+                    .await?;
+                }
+                sqlx::query("DELETE FROM rooms WHERE room_id = $1")
+                    .bind(room_id)
+                    .execute(&mut *tx)
+                    .await?;
+                tx.commit().await?;
+                Ok(())
+            }
+            Repository::Memory(store) => {
+                let Some(room) = store.rooms.write().unwrap().remove(room_id) else {
+                    return Ok(());
+                };
+                store.recordings.write().unwrap().retain(|_, recording| recording.room_id != room.id);
+                store.room_configs.write().unwrap().remove(&room.id);
+                Ok(())
+            }
+        }
+    }
+
+    // Recordings
+
+    pub async fn upsert_recording(&self, recording: &Recording) -> Result<(), AppError> {
+        match self {
+            Repository::Postgres(pool) => {
+                sqlx::query(
+                    "INSERT INTO recordings (id, room_id, start_time, end_time, storage_path, size_bytes, duration_ms,
+                                             frame_count, video_frames, audio_frames, codec, status, tracks)
+                     VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13)
+                     ON CONFLICT (id) DO UPDATE SET
+                        end_time = EXCLUDED.end_time,
+                        storage_path = EXCLUDED.storage_path,
+                        size_bytes = EXCLUDED.size_bytes,
+                        duration_ms = EXCLUDED.duration_ms,
+                        frame_count = EXCLUDED.frame_count,
+                        video_frames = EXCLUDED.video_frames,
+                        audio_frames = EXCLUDED.audio_frames,
+                        codec = EXCLUDED.codec,
+                        status = EXCLUDED.status,
+                        tracks = EXCLUDED.tracks",
+                )
+                .bind(recording.id)
+                .bind(recording.room_id)
+                .bind(recording.start_time)
+                .bind(recording.end_time)
+                .bind(&recording.storage_path)
+                .bind(recording.size_bytes)
+                .bind(recording.duration_ms)
+                .bind(recording.frame_count)
+                .bind(recording.video_frames)
+                .bind(recording.audio_frames)
+                .bind(&recording.codec)
+                .bind(recording.status.as_str())
+                .bind(tracks_json(&recording.tracks)?)
+                .execute(pool)
+                .await?;
+                Ok(())
+            }
+            Repository::Memory(store) => {
+                store.recordings.write().unwrap().insert(recording.id, recording.clone());
+                Ok(())
+            }
+        }
+    }
+
+    pub async fn get_recording(&self, id: Uuid) -> Result<Option<Recording>, AppError> {
+        match self {
+            Repository::Postgres(pool) => {
+                let row = sqlx::query(
+                    "SELECT id, room_id, start_time, end_time, storage_path, size_bytes, duration_ms,
+                            frame_count, video_frames, audio_frames, codec, status, tracks, mp4_path
+                     FROM recordings WHERE id = $1",
+                )
+                .bind(id)
+                .fetch_optional(pool)
+                .await?;
+                row.map(|row| recording_from_row(&row)).transpose()
+            }
+            Repository::Memory(store) => Ok(store.recordings.read().unwrap().get(&id).cloned()),
+        }
+    }
+
+    // Record where a recording's MP4 copy was written
+    pub async fn set_recording_mp4(&self, id: Uuid, mp4_path: &str) -> Result<(), AppError> {
+        match self {
+            Repository::Postgres(pool) => {
+                sqlx::query("UPDATE recordings SET mp4_path = $2 WHERE id = $1")
+                    .bind(id)
+                    .bind(mp4_path)
+                    .execute(pool)
+                    .await?;
+                Ok(())
+            }
+            Repository::Memory(store) => {
+                if let Some(recording) = store.recordings.write().unwrap().get_mut(&id) {
+                    recording.mp4_path = Some(mp4_path.to_string());
+                }
+                Ok(())
+            }
+        }
+    }
+
+    pub async fn list_recordings(&self, room_id: Uuid) -> Result<Vec<Recording>, AppError> {
+        match self {
+            Repository::Postgres(pool) => {
+                let rows = sqlx::query(
+                    "SELECT id, room_id, start_time, end_time, storage_path, size_bytes, duration_ms,
+                            frame_count, video_frames, audio_frames, codec, status, tracks, mp4_path
+                     FROM recordings WHERE room_id = $1 ORDER BY start_time",
+                )
+                .bind(room_id)
+                .fetch_all(pool)
+                .await?;
+                rows.iter().map(recording_from_row).collect()
+            }
+            Repository::Memory(store) => {
+                let recordings = store.recordings.read().unwrap();
+                let mut recordings: Vec<Recording> = recordings.values()
+                    .filter(|recording| recording.room_id == room_id)
+                    .cloned()
+                    .collect();
+                recordings.sort_by_key(|recording| recording.start_time);
+                Ok(recordings)
+            }
+        }
+    }
+
+    // Room configs
+
+    pub async fn upsert_room_config(&self, config: &RoomConfig) -> Result<(), AppError> {
+        match self {
+            Repository::Postgres(pool) => {
+                sqlx::query(
+                    "INSERT INTO room_configs (
+                        room_id, video_codec, audio_codec, max_bitrate, frame_rate, resolution,
+                        deduplication_enabled, deduplication_threshold,
+                        keyframe_interval, adaptive_bitrate, min_bitrate, quality_degradation_limit,
+                        enable_frame_batching, batch_size, batch_timeout_ms,
+                        max_buffer_size, buffer_duration_ms,
+                        enable_error_resilience, error_correction_level, retry_attempts,
+                        enable_hardware_acceleration, preferred_hardware_vendor, limit_policy, recording_mode,
+                        audio_channels
+                     ) VALUES (
+                        $1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11,
+                        $12, $13, $14, $15, $16, $17, $18, $19, $20, $21, $22, $23, $24, $25
+                     )
+                     ON CONFLICT (room_id) DO UPDATE SET
+                        video_codec = EXCLUDED.video_codec,
+                        audio_codec = EXCLUDED.audio_codec,
+                        max_bitrate = EXCLUDED.max_bitrate,
+                        frame_rate = EXCLUDED.frame_rate,
+                        resolution = EXCLUDED.resolution,
+                        deduplication_enabled = EXCLUDED.deduplication_enabled,
+                        deduplication_threshold = EXCLUDED.deduplication_threshold,
+                        keyframe_interval = EXCLUDED.keyframe_interval,
+                        adaptive_bitrate = EXCLUDED.adaptive_bitrate,
+                        min_bitrate = EXCLUDED.min_bitrate,
+                        quality_degradation_limit = EXCLUDED.quality_degradation_limit,
+                        enable_frame_batching = EXCLUDED.enable_frame_batching,
+                        batch_size = EXCLUDED.batch_size,
+                        batch_timeout_ms = EXCLUDED.batch_timeout_ms,
+                        max_buffer_size = EXCLUDED.max_buffer_size,
+                        buffer_duration_ms = EXCLUDED.buffer_duration_ms,
+                        enable_error_resilience = EXCLUDED.enable_error_resilience,
+                        error_correction_level = EXCLUDED.error_correction_level,
+                        retry_attempts = EXCLUDED.retry_attempts,
+                        enable_hardware_acceleration = EXCLUDED.enable_hardware_acceleration,
+                        preferred_hardware_vendor = EXCLUDED.preferred_hardware_vendor,
+                        limit_policy = EXCLUDED.limit_policy,
+                        recording_mode = EXCLUDED.recording_mode,
+                        audio_channels = EXCLUDED.audio_channels",
+                )
+                .bind(config.room_id)
+                .bind(&config.video_codec)
+                .bind(&config.audio_codec)
+                .bind(config.max_bitrate)
+                .bind(config.frame_rate)
+                .bind(&config.resolution)
+                .bind(config.deduplication_enabled)
+                .bind(config.deduplication_threshold)
+                .bind(config.keyframe_interval)
+                .bind(config.adaptive_bitrate)
+                .bind(config.min_bitrate)
+                .bind(config.quality_degradation_limit)
+                .bind(config.enable_frame_batching)
+                .bind(config.batch_size)
+                .bind(config.batch_timeout_ms)
+                .bind(config.max_buffer_size)
+                .bind(config.buffer_duration_ms)
+                .bind(config.enable_error_resilience)
+                .bind(config.error_correction_level)
+                .bind(config.retry_attempts)
+                .bind(config.enable_hardware_acceleration)
+                .bind(&config.preferred_hardware_vendor)
+                .bind(config.limit_policy.as_str())
+                .bind(config.recording_mode.as_str())
+                .bind(config.audio_channels)
+                .execute(pool)
+                .await?;
+                Ok(())
+            }
+            Repository::Memory(store) => {
+                store.room_configs.write().unwrap().insert(config.room_id, config.clone());
+                Ok(())
+            }
+        }
+    }
+
+    pub async fn get_room_config(&self, room_id: Uuid) -> Result<Option<RoomConfig>, AppError> {
+        match self {
+            Repository::Postgres(pool) => Ok(sqlx::query_as::<_, RoomConfig>(
+                "SELECT * FROM room_configs WHERE room_id = $1",
+            )
+            .bind(room_id)
+            .fetch_optional(pool)
+            .await?),
+            Repository::Memory(store) => Ok(store.room_configs.read().unwrap().get(&room_id).cloned()),
+        }
+    }
+}
+
+fn recording_from_row(row: &PgRow) -> Result<Recording, AppError> {
+    let status: String = row.try_get("status")?;
+    Ok(Recording {
+        id: row.try_get("id")?,
+        room_id: row.try_get("room_id")?,
+        start_time: row.try_get("start_time")?,
+        end_time: row.try_get("end_time")?,
+        storage_path: row.try_get("storage_path")?,
+        size_bytes: row.try_get::<Option<i64>, _>("size_bytes")?.unwrap_or(0),
+        duration_ms: row.try_get("duration_ms")?,
+        frame_count: row.try_get::<Option<i64>, _>("frame_count")?.unwrap_or(0),
+        video_frames: row.try_get("video_frames")?,
+        audio_frames: row.try_get("audio_frames")?,
+        codec: row.try_get("codec")?,
+        status: RecordingStatus::parse(&status)
+            .ok_or_else(|| AppError::DatabaseError(format!("Unknown recording status {}", status)))?,
+        tracks: match row.try_get::<Option<String>, _>("tracks")? {
+            Some(json) => serde_json::from_str(&json)
+                .map_err(|e| AppError::DatabaseError(format!("Invalid tracks for recording: {}", e)))?,
+            None => Vec::new(),
+        },
+        mp4_path: row.try_get("mp4_path")?,
+    })
+}
+
+// Tracks are stored as JSON text; chunk streams have none
+fn tracks_json(tracks: &[TrackInfo]) -> Result<Option<String>, AppError> {
+    if tracks.is_empty() {
+        return Ok(None);
+    }
+    serde_json::to_string(tracks)
+        .map(Some)
+        .map_err(|e| AppError::InternalError(format!("Failed to serialize tracks: {}", e)))
+}
+
+// Process-local store used when no database is configured, and in tests
+#[derive(Clone)]
+pub struct MemoryStore {
+    users: Arc<RwLock<HashMap<Uuid, User>>>,
+    rooms: Arc<RwLock<HashMap<String, Room>>>,
+    recordings: Arc<RwLock<HashMap<Uuid, Recording>>>,
+    room_configs: Arc<RwLock<HashMap<Uuid, RoomConfig>>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self {
+            users: Arc::new(RwLock::new(HashMap::new())),
+            rooms: Arc::new(RwLock::new(HashMap::new())),
+            recordings: Arc::new(RwLock::new(HashMap::new())),
+            room_configs: Arc::new(RwLock::new(HashMap::new())),
+        }
+    }
+}
+
+impl Default for MemoryStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -2,107 +2,327 @@ use std::{
     collections::HashMap,
     sync::{Arc, RwLock},
 };
-use tokio::sync::broadcast;
+use chrono::{DateTime, Utc};
+use tracing::warn;
 use uuid::Uuid;
-use crate::error::AppError;
+use crate::{
+    error::AppError,
+    models::{self, ParticipantRole, RoomConfig, RoomState, SlowConsumerPolicy, UpdateRoomRequest},
+    repository::Repository,
+    stream_hub::StreamHub,
+};
+
+// A room records a single stream, so only one publisher may be connected at a time
+pub const MAX_PUBLISHERS: u32 = 1;
 
 #[derive(Debug, Clone)]
 pub struct Room {
     pub id: String,
+    // Primary key of the rooms row, referenced by recordings and room_configs
+    pub record_id: Uuid,
     pub name: String,
+    // Viewer capacity; publishers are limited by MAX_PUBLISHERS
     pub max_participants: u32,
     pub recording_enabled: bool,
-    pub current_participants: u32,
+    pub publishers: u32,
+    pub viewers: u32,
     pub creator_id: String,
+    pub slow_consumer: SlowConsumerPolicy,
+    pub created_at: DateTime<Utc>,
+    pub last_active: Option<DateTime<Utc>>,
+    pub state: RoomState,
+    // Set while the room is closed or archived
+    pub closed_at: Option<DateTime<Utc>>,
+}
+
+impl Room {
+    fn from_record(record: models::Room) -> Self {
+        Self {
+            id: record.room_id,
+            record_id: record.id,
+            name: record.name,
+            max_participants: record.max_participants.max(0) as u32,
+            recording_enabled: true,
+            publishers: 0,
+            viewers: 0,
+            creator_id: record.user_id.to_string(),
+            slow_consumer: record.config
+                .as_ref()
+                .and_then(|config| config.get("slow_consumer"))
+                .and_then(|policy| serde_json::from_value(policy.clone()).ok())
+                .unwrap_or_default(),
+            created_at: record.created_at.unwrap_or_else(Utc::now),
+            last_active: record.last_active,
+            state: RoomState::parse(&record.state).unwrap_or_default(),
+            closed_at: record.closed_at,
+        }
+    }
+
+    // Persisted alongside the room in its config column
+    fn config_json(&self) -> serde_json::Value {
+        serde_json::json!({ "slow_consumer": self.slow_consumer })
+    }
 }
 
 #[derive(Clone)]
 pub struct Rooms {
+    repo: Repository,
     rooms: Arc<RwLock<HashMap<String, Room>>>,
-    streams: Arc<RwLock<HashMap<String, broadcast::Sender<Vec<u8>>>>>,
+    streams: Arc<RwLock<HashMap<String, Arc<StreamHub>>>>,
 }
 
 impl Rooms {
-    pub fn new() -> Self {
+    pub fn new(repo: Repository) -> Self {
         Self {
+            repo,
             rooms: Arc::new(RwLock::new(HashMap::new())),
             streams: Arc::new(RwLock::new(HashMap::new())),
         }
     }
 
-    pub async fn create_room(&self, id: String, name: String, max_participants: u32, creator_id: String) -> Result<Room, AppError> {
-        let mut rooms = self.rooms.write().unwrap();
+    pub async fn create_room(
+        &self,
+        id: String,
+        name: String,
+        max_participants: u32,
+        creator_id: String,
+        slow_consumer: SlowConsumerPolicy,
+        config: Option<RoomConfig>,
+    ) -> Result<Room, AppError> {
+        let user_id = Uuid::parse_str(&creator_id)
+            .map_err(|_| AppError::Unauthorized(format!("Invalid user id {}", creator_id)))?;
+        let record_id = Uuid::parse_str(&id).unwrap_or_else(|_| Uuid::new_v4());
+
         let room = Room {
             id: id.clone(),
+            record_id,
             name,
             max_participants,
             recording_enabled: true,
-            current_participants: 0,
+            publishers: 0,
+            viewers: 0,
             creator_id,
+            slow_consumer,
+            created_at: Utc::now(),
+            last_active: None,
+            state: RoomState::Active,
+            closed_at: None,
         };
-        rooms.insert(id, room.clone());
+
+        self.repo.insert_room(&models::Room {
+            id: record_id,
+            user_id,
+            room_id: id.clone(),
+            name: room.name.clone(),
+            max_participants: max_participants as i32,
+            config: Some(room.config_json()),
+            created_at: Some(room.created_at),
+            last_active: None,
+            state: room.state.as_str().to_string(),
+            closed_at: None,
+        }).await?;
+
+        if let Some(config) = config {
+            self.repo.upsert_room_config(&RoomConfig { room_id: record_id, ..config }).await?;
+        }
+
+        self.rooms.write().unwrap().insert(id, room.clone());
         Ok(room)
     }
 
-    pub fn list_all_rooms(&self) -> Result<Vec<Room>, AppError> {
-        let rooms = self.rooms.read().unwrap();
-        Ok(rooms.values().cloned().collect())
+    // Rooms without a stored config use the schema defaults
+    pub async fn get_config(&self, room_id: &str) -> Result<RoomConfig, AppError> {
+        let room = self.get_room(room_id).await?;
+        let config = self.repo.get_room_config(room.record_id).await?;
+        Ok(config.unwrap_or(RoomConfig { room_id: room.record_id, ..RoomConfig::default() }))
+    }
+
+    // Replace the room's config; applies to connections and recordings started afterwards
+    pub async fn set_config(&self, room_id: &str, config: RoomConfig) -> Result<RoomConfig, AppError> {
+        let room = self.get_room(room_id).await?;
+        if room.state == RoomState::Archived {
+            return Err(AppError::Forbidden(format!("Room {} is archived", room_id)));
+        }
+
+        let config = RoomConfig { room_id: room.record_id, ..config };
+        self.repo.upsert_room_config(&config).await?;
+        Ok(config)
+    }
+
+    // Apply changed settings; live participant counts are kept
+    pub async fn update_room(&self, room_id: &str, update: UpdateRoomRequest) -> Result<Room, AppError> {
+        let mut room = self.get_room(room_id).await?;
+        if room.state == RoomState::Archived {
+            return Err(AppError::Forbidden(format!("Room {} is archived", room_id)));
+        }
+
+        if let Some(name) = update.name {
+            room.name = name;
+        }
+        if let Some(max_participants) = update.max_participants {
+            room.max_participants = max_participants;
+        }
+        if let Some(slow_consumer) = update.slow_consumer {
+            room.slow_consumer = slow_consumer;
+        }
+
+        self.repo
+            .update_room_details(room_id, &room.name, room.max_participants as i32, &Some(room.config_json()))
+            .await?;
+
+        let mut rooms = self.rooms.write().unwrap();
+        let live = rooms.entry(room_id.to_string()).or_insert_with(|| room.clone());
+        live.name = room.name;
+        live.max_participants = room.max_participants;
+        live.slow_consumer = room.slow_consumer;
+        Ok(live.clone())
+    }
+
+    pub async fn set_state(&self, room_id: &str, state: RoomState) -> Result<Room, AppError> {
+        // Make sure the room is loaded before changing it
+        self.get_room(room_id).await?;
+
+        let closed_at = match state {
+            RoomState::Active => None,
+            RoomState::Closed | RoomState::Archived => Some(Utc::now()),
+        };
+        self.repo.set_room_state(room_id, state.as_str(), closed_at).await?;
+
+        let mut rooms = self.rooms.write().unwrap();
+        let room = rooms
+            .get_mut(room_id)
+            .ok_or_else(|| AppError::NotFound(format!("Room {} not found", room_id)))?;
+        room.state = state;
+        room.closed_at = closed_at;
+        Ok(room.clone())
+    }
+
+    // Record activity on the room; failures only cost accuracy of last_active
+    pub async fn touch(&self, room_id: &str) {
+        if let Some(room) = self.rooms.write().unwrap().get_mut(room_id) {
+            room.last_active = Some(Utc::now());
+        }
+        if let Err(e) = self.repo.touch_room(room_id).await {
+            warn!("Failed to update last activity for room {}: {}", room_id, e);
+        }
+    }
+
+    // Disconnect everyone on the room's stream. The next connection gets a fresh hub.
+    pub fn close_stream(&self, room_id: &str) {
+        if let Some(hub) = self.streams.write().unwrap().remove(room_id) {
+            hub.close();
+        }
+    }
+
+    // Forget a deleted room; its stream should already be closed
+    pub async fn delete_room(&self, room_id: &str) -> Result<(), AppError> {
+        self.repo.delete_room(room_id).await?;
+        self.rooms.write().unwrap().remove(room_id);
+        self.close_stream(room_id);
+        Ok(())
+    }
+
+    // Merge persisted rooms with live participant counts
+    fn with_live_state(&self, records: Vec<models::Room>) -> Vec<Room> {
+        let mut rooms = self.rooms.write().unwrap();
+        records.into_iter()
+            .map(|record| {
+                rooms.entry(record.room_id.clone())
+                    .or_insert_with(|| Room::from_record(record))
+                    .clone()
+            })
+            .collect()
     }
 
     pub async fn list_rooms(&self, user_id: &str) -> Result<Vec<Room>, AppError> {
-        let rooms = self.rooms.read().unwrap();
-        if user_id == "*" {
-            Ok(rooms.values().cloned().collect())
+        let filter = if user_id == "*" {
+            None
         } else {
-            Ok(rooms.values()
-                .filter(|room| room.creator_id == user_id)
-                .cloned()
-                .collect())
-        }
+            match Uuid::parse_str(user_id) {
+                Ok(id) => Some(id),
+                Err(_) => return Ok(Vec::new()),
+            }
+        };
+
+        let records = self.repo.list_rooms(filter).await?;
+        Ok(self.with_live_state(records))
     }
 
     pub async fn get_room(&self, room_id: &str) -> Result<Room, AppError> {
-        let rooms = self.rooms.read().unwrap();
-        rooms
-            .get(room_id)
-            .cloned()
-            .ok_or_else(|| AppError::NotFound(format!("Room {} not found", room_id)))
+        if let Some(room) = self.rooms.read().unwrap().get(room_id).cloned() {
+            return Ok(room);
+        }
+
+        let record = self.repo
+            .get_room(room_id)
+            .await?
+            .ok_or_else(|| AppError::NotFound(format!("Room {} not found", room_id)))?;
+        Ok(self.with_live_state(vec![record]).remove(0))
     }
 
-    pub async fn get_stream(&self, room_id: &str) -> Result<broadcast::Sender<Vec<u8>>, AppError> {
-        let streams = self.streams.read().unwrap();
-        if let Some(tx) = streams.get(room_id) {
-            Ok(tx.clone())
-        } else {
-            let mut streams = self.streams.write().unwrap();
-            let (tx, _) = broadcast::channel(100);
-            streams.insert(room_id.to_string(), tx.clone());
-            Ok(tx)
+    pub async fn get_stream(&self, room_id: &str) -> Result<Arc<StreamHub>, AppError> {
+        if let Some(hub) = self.streams.read().unwrap().get(room_id) {
+            return Ok(hub.clone());
         }
+
+        let mut streams = self.streams.write().unwrap();
+        let hub = streams
+            .entry(room_id.to_string())
+            .or_insert_with(|| Arc::new(StreamHub::new()));
+        Ok(hub.clone())
+    }
+
+    // The room's hub if anyone has connected to it, without creating one
+    pub fn active_stream(&self, room_id: &str) -> Option<Arc<StreamHub>> {
+        self.streams.read().unwrap().get(room_id).cloned()
     }
 
-    pub async fn add_participant(&self, room_id: &str) -> Result<(), AppError> {
+    // Take a slot for the role; publishers and viewers are counted separately.
+    // The slot is freed when the returned guard is dropped.
+    pub async fn add_participant(&self, room_id: &str, role: ParticipantRole) -> Result<ParticipantSlot, AppError> {
         let mut rooms = self.rooms.write().unwrap();
         if let Some(room) = rooms.get_mut(room_id) {
-            if room.current_participants >= room.max_participants {
-                return Err(AppError::TooManyConnections(format!("Room {} is full", room_id)));
+            match role {
+                ParticipantRole::Publisher => {
+                    if room.publishers >= MAX_PUBLISHERS {
+                        return Err(AppError::TooManyConnections(format!("Room {} already has a publisher", room_id)));
+                    }
+                    room.publishers += 1;
+                }
+                ParticipantRole::Viewer => {
+                    if room.viewers >= room.max_participants {
+                        return Err(AppError::TooManyConnections(format!("Room {} is full", room_id)));
+                    }
+                    room.viewers += 1;
+                }
             }
-            room.current_participants += 1;
-            Ok(())
+            Ok(ParticipantSlot {
+                rooms: self.rooms.clone(),
+                room_id: room_id.to_string(),
+                role,
+            })
         } else {
             Err(AppError::NotFound(format!("Room {} not found", room_id)))
         }
     }
+}
 
-    pub async fn remove_participant(&self, room_id: &str) -> Result<(), AppError> {
+// A publisher or viewer counted in a room; dropping it releases the slot
+pub struct ParticipantSlot {
+    rooms: Arc<RwLock<HashMap<String, Room>>>,
+    room_id: String,
+    role: ParticipantRole,
+}
+
+impl Drop for ParticipantSlot {
+    fn drop(&mut self) {
         let mut rooms = self.rooms.write().unwrap();
-        if let Some(room) = rooms.get_mut(room_id) {
-            if room.current_participants > 0 {
-                room.current_participants -= 1;
-            }
-            Ok(())
-        } else {
-            Err(AppError::NotFound(format!("Room {} not found", room_id)))
+        if let Some(room) = rooms.get_mut(&self.room_id) {
+            let count = match self.role {
+                ParticipantRole::Publisher => &mut room.publishers,
+                ParticipantRole::Viewer => &mut room.viewers,
+            };
+            *count = count.saturating_sub(1);
         }
     }
-} 
\ No newline at end of file
+}
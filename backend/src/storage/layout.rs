@@ -0,0 +1,211 @@
+/*
+ * storage/layout.rs
+ * Purpose: Directory layout for recordings
+ *
+ * A layout is a '/'-separated template such as "{user_id}/{room_id}/{date}".
+ * Supported placeholders: {user_id}, {room_id}, {date} (YYYY-MM-DD), {year}, {month}, {day}.
+ * {room_id} is required so a room's recordings can always be found again.
+ */
+
+use chrono::{DateTime, Datelike, Utc};
+use uuid::Uuid;
+use crate::error::AppError;
+
+pub const DEFAULT_LAYOUT: &str = "{room_id}";
+
+const PLACEHOLDERS: [&str; 6] = ["user_id", "room_id", "date", "year", "month", "day"];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Literal(String),
+    Placeholder(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct StorageLayout {
+    template: String,
+    // One token list per path segment
+    segments: Vec<Vec<Token>>,
+}
+
+impl StorageLayout {
+    pub fn parse(template: &str) -> Result<Self, AppError> {
+        let template = template.trim_matches('/');
+        let mut segments = Vec::new();
+
+        for part in template.split('/') {
+            if part.is_empty() || part == "." || part == ".." {
+                return Err(AppError::InternalError(format!("Invalid storage layout segment {:?} in {}", part, template)));
+            }
+
+            let mut tokens = Vec::new();
+            let mut rest = part;
+            while let Some(start) = rest.find('{') {
+                if start > 0 {
+                    tokens.push(Token::Literal(rest[..start].to_string()));
+                }
+                let end = rest[start..].find('}')
+                    .ok_or_else(|| AppError::InternalError(format!("Unclosed placeholder in storage layout {}", template)))?;
+                let name = &rest[start + 1..start + end];
+                if !PLACEHOLDERS.contains(&name) {
+                    return Err(AppError::InternalError(format!("Unknown storage layout placeholder {{{}}}", name)));
+                }
+                // Two adjacent placeholders couldn't be told apart when matching
+                if matches!(tokens.last(), Some(Token::Placeholder(_))) {
+                    return Err(AppError::InternalError(format!("Storage layout placeholders must be separated in {}", template)));
+                }
+                tokens.push(Token::Placeholder(name.to_string()));
+                rest = &rest[start + end + 1..];
+            }
+            if !rest.is_empty() {
+                tokens.push(Token::Literal(rest.to_string()));
+            }
+            segments.push(tokens);
+        }
+
+        let has_room = segments.iter().flatten().any(|t| *t == Token::Placeholder("room_id".to_string()));
+        if !has_room {
+            return Err(AppError::InternalError(format!("Storage layout {} must contain {{room_id}}", template)));
+        }
+
+        Ok(Self { template: template.to_string(), segments })
+    }
+
+    pub fn template(&self) -> &str {
+        &self.template
+    }
+
+    // Directory for a recording started at `start_time`
+    pub fn render(&self, user_id: Option<&Uuid>, room_id: &str, start_time: DateTime<Utc>) -> String {
+        let user_id = user_id.map(|id| id.to_string()).unwrap_or_else(|| "unknown".to_string());
+        let value = |name: &str| -> String {
+            match name {
+                "user_id" => user_id.clone(),
+                "room_id" => room_id.to_string(),
+                "date" => start_time.format("%Y-%m-%d").to_string(),
+                "year" => format!("{:04}", start_time.year()),
+                "month" => format!("{:02}", start_time.month()),
+                "day" => format!("{:02}", start_time.day()),
+                _ => String::new(),
+            }
+        };
+
+        self.segments
+            .iter()
+            .map(|tokens| {
+                tokens.iter().map(|token| match token {
+                    Token::Literal(text) => text.clone(),
+                    Token::Placeholder(name) => value(name),
+                }).collect::<String>()
+            })
+            .collect::<Vec<_>>()
+            .join("/")
+    }
+
+    // Longest key prefix shared by every recording of the room, used to narrow listings
+    pub fn room_prefix(&self, room_id: &str) -> String {
+        let mut prefix = String::new();
+        for tokens in &self.segments {
+            for token in tokens {
+                match token {
+                    Token::Literal(text) => prefix.push_str(text),
+                    Token::Placeholder(name) if name == "room_id" => prefix.push_str(room_id),
+                    Token::Placeholder(_) => return prefix,
+                }
+            }
+            prefix.push('/');
+        }
+        prefix
+    }
+
+    // Whether a recording directory produced by this layout belongs to the room
+    pub fn matches_room(&self, dir: &str, room_id: &str) -> bool {
+        let parts: Vec<&str> = dir.split('/').collect();
+        parts.len() == self.segments.len()
+            && self.segments
+                .iter()
+                .zip(parts)
+                .all(|(tokens, part)| match_segment(tokens, part, room_id))
+    }
+}
+
+fn match_segment(tokens: &[Token], text: &str, room_id: &str) -> bool {
+    match tokens.split_first() {
+        None => text.is_empty(),
+        Some((Token::Literal(literal), rest)) => text
+            .strip_prefix(literal.as_str())
+            .is_some_and(|remaining| match_segment(rest, remaining, room_id)),
+        Some((Token::Placeholder(name), rest)) if name == "room_id" => text
+            .strip_prefix(room_id)
+            .is_some_and(|remaining| match_segment(rest, remaining, room_id)),
+        // Any other placeholder matches a non-empty run of characters
+        Some((Token::Placeholder(_), rest)) => (1..=text.len())
+            .filter(|&i| text.is_char_boundary(i))
+            .any(|i| match_segment(rest, &text[i..], room_id)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn rejects_invalid_templates() {
+        for template in [
+            "{room_id}/../x",
+            "{room_id}/./x",
+            "{user_id}//{room_id}",
+            "{room_id}/{hour}",
+            "{room_id}/{user_id}{date}",
+            "{user_id}/{date}",
+            "{room_id}/{date",
+        ] {
+            assert!(StorageLayout::parse(template).is_err(), "{} should be rejected", template);
+        }
+    }
+
+    #[test]
+    fn accepts_separated_placeholders_and_outer_slashes() {
+        let layout = StorageLayout::parse("/{user_id}/{room_id}-{date}/").unwrap();
+        assert_eq!(layout.template(), "{user_id}/{room_id}-{date}");
+    }
+
+    #[test]
+    fn rendered_directories_match_their_room() {
+        let layout = StorageLayout::parse("{user_id}/{room_id}/{date}").unwrap();
+        let user_id = Uuid::new_v4();
+        let start = Utc.with_ymd_and_hms(2024, 3, 7, 12, 0, 0).unwrap();
+
+        let dir = layout.render(Some(&user_id), "abc", start);
+        assert_eq!(dir, format!("{}/abc/2024-03-07", user_id));
+        assert!(layout.matches_room(&dir, "abc"));
+        assert!(!layout.matches_room(&dir, "abd"));
+        assert!(!layout.matches_room(&format!("{}/abc", user_id), "abc"));
+        assert_eq!(layout.render(None, "abc", start), "unknown/abc/2024-03-07");
+    }
+
+    #[test]
+    fn room_ids_must_match_exactly() {
+        let layout = StorageLayout::parse("{room_id}/{date}").unwrap();
+        let dir = layout.render(None, "abcd", Utc::now());
+        assert!(layout.matches_room(&dir, "abcd"));
+        assert!(!layout.matches_room(&dir, "abc"));
+
+        let layout = StorageLayout::parse("{room_id}-{date}").unwrap();
+        assert!(layout.matches_room("abc-2024-03-07", "abc"));
+        assert!(!layout.matches_room("abcd-2024-03-07", "abc"));
+    }
+
+    #[test]
+    fn room_prefix_stops_at_first_other_placeholder() {
+        let layout = StorageLayout::parse("rooms/{room_id}/{year}/{month}").unwrap();
+        assert_eq!(layout.room_prefix("abc"), "rooms/abc/");
+
+        let layout = StorageLayout::parse("{room_id}-{date}/raw").unwrap();
+        assert_eq!(layout.room_prefix("abc"), "abc-");
+
+        let layout = StorageLayout::parse("{user_id}/{room_id}").unwrap();
+        assert_eq!(layout.room_prefix("abc"), "");
+    }
+}
@@ -0,0 +1,294 @@
+/*
+ * storage/local.rs
+ * Purpose: Filesystem storage backend
+ *
+ * Keys map directly onto paths below the base directory.
+ */
+
+use std::path::{Path, PathBuf};
+use async_trait::async_trait;
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use tokio::{
+    fs::{self, File, OpenOptions},
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt, SeekFrom},
+};
+use tracing::{error, info};
+use crate::error::AppError;
+use super::{ByteStream, ObjectInfo, StorageBackend, UploadSession};
+
+// Chunk size for streamed reads
+const STREAM_CHUNK_SIZE: usize = 64 * 1024;
+
+pub struct LocalBackend {
+    base_path: PathBuf,
+    file_mode: Option<u32>,
+}
+
+impl LocalBackend {
+    pub async fn new(base_path: &str, file_mode: Option<u32>) -> Result<Self, AppError> {
+        fs::create_dir_all(base_path).await.map_err(|e| {
+            error!("Failed to create storage directory {}: {}", base_path, e);
+            AppError::StorageError(e.to_string())
+        })?;
+
+        info!("Storing recordings under {}", base_path);
+        Ok(Self {
+            base_path: PathBuf::from(base_path),
+            file_mode,
+        })
+    }
+
+    // Apply the configured mode explicitly so the process umask doesn't narrow it
+    async fn apply_mode(&self, path: &Path) -> Result<(), AppError> {
+        #[cfg(unix)]
+        if let Some(mode) = self.file_mode {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(path, std::fs::Permissions::from_mode(mode)).await.map_err(|e| {
+                error!("Failed to set permissions on {}: {}", path.display(), e);
+                AppError::StorageError(e.to_string())
+            })?;
+        }
+        #[cfg(not(unix))]
+        let _ = (path, self.file_mode);
+        Ok(())
+    }
+
+    fn path_for(&self, key: &str) -> Result<PathBuf, AppError> {
+        // Keys come from room ids, so refuse anything that could escape the base directory
+        if key.split('/').any(|part| part == ".." || part.is_empty()) {
+            return Err(AppError::BadRequest(format!("Invalid storage key: {}", key)));
+        }
+        Ok(self.base_path.join(key))
+    }
+
+    async fn object_info(&self, key: &str, path: &Path) -> Result<ObjectInfo, AppError> {
+        let metadata = fs::metadata(path).await.map_err(|e| match e.kind() {
+            std::io::ErrorKind::NotFound => AppError::NotFound(format!("Object not found: {}", key)),
+            _ => AppError::StorageError(e.to_string()),
+        })?;
+
+        let last_modified: DateTime<Utc> = metadata.modified()
+            .map(DateTime::from)
+            .unwrap_or_else(|_| Utc::now());
+
+        Ok(ObjectInfo {
+            key: key.to_string(),
+            size: metadata.len(),
+            last_modified,
+            etag: Some(format!("{:x}-{:x}", metadata.len(), last_modified.timestamp_millis())),
+        })
+    }
+
+    async fn ensure_parent(path: &Path) -> Result<(), AppError> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).await.map_err(|e| AppError::StorageError(e.to_string()))?;
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl StorageBackend for LocalBackend {
+    async fn create_session(&self, key: &str) -> Result<Box<dyn UploadSession>, AppError> {
+        let path = self.path_for(key)?;
+        Self::ensure_parent(&path).await?;
+
+        let file = OpenOptions::new()
+            .create_new(true)
+            .append(true)
+            .open(&path)
+            .await
+            .map_err(|e| {
+                error!("Failed to create {}: {}", path.display(), e);
+                AppError::StorageError(e.to_string())
+            })?;
+        self.apply_mode(&path).await?;
+
+        Ok(Box::new(LocalUpload {
+            key: key.to_string(),
+            path,
+            file,
+            size: 0,
+            replaces: None,
+        }))
+    }
+
+    // Written next to the object and renamed over it on finalize
+    async fn replace_session(&self, key: &str) -> Result<Box<dyn UploadSession>, AppError> {
+        let target = self.path_for(key)?;
+        let mut path = target.clone().into_os_string();
+        path.push(".tmp");
+        let path = PathBuf::from(path);
+
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&path)
+            .await
+            .map_err(|e| {
+                error!("Failed to create {}: {}", path.display(), e);
+                AppError::StorageError(e.to_string())
+            })?;
+        self.apply_mode(&path).await?;
+
+        Ok(Box::new(LocalUpload {
+            key: key.to_string(),
+            path,
+            file,
+            size: 0,
+            replaces: Some(target),
+        }))
+    }
+
+    async fn put(&self, key: &str, data: &[u8]) -> Result<(), AppError> {
+        let path = self.path_for(key)?;
+        Self::ensure_parent(&path).await?;
+
+        fs::write(&path, data).await.map_err(|e| {
+            error!("Failed to write {}: {}", path.display(), e);
+            AppError::StorageError(e.to_string())
+        })?;
+        self.apply_mode(&path).await
+    }
+
+    async fn read_range(&self, key: &str, start: u64, end: Option<u64>) -> Result<Vec<u8>, AppError> {
+        let path = self.path_for(key)?;
+        let mut file = File::open(&path).await.map_err(|e| match e.kind() {
+            std::io::ErrorKind::NotFound => AppError::NotFound(format!("Object not found: {}", key)),
+            _ => AppError::StorageError(e.to_string()),
+        })?;
+
+        file.seek(SeekFrom::Start(start)).await?;
+
+        let mut data = Vec::new();
+        match end {
+            Some(end) => {
+                file.take(end.saturating_sub(start)).read_to_end(&mut data).await?;
+            }
+            None => {
+                file.read_to_end(&mut data).await?;
+            }
+        }
+        Ok(data)
+    }
+
+    async fn stream_range(&self, key: &str, start: u64, end: Option<u64>) -> Result<ByteStream, AppError> {
+        let path = self.path_for(key)?;
+        let mut file = File::open(&path).await.map_err(|e| match e.kind() {
+            std::io::ErrorKind::NotFound => AppError::NotFound(format!("Object not found: {}", key)),
+            _ => AppError::StorageError(e.to_string()),
+        })?;
+        file.seek(SeekFrom::Start(start)).await?;
+
+        let reader = file.take(end.map_or(u64::MAX, |end| end.saturating_sub(start)));
+        let stream = futures::stream::try_unfold(reader, |mut reader| async move {
+            let mut chunk = vec![0u8; STREAM_CHUNK_SIZE];
+            let read = reader.read(&mut chunk).await?;
+            if read == 0 {
+                return Ok(None);
+            }
+            chunk.truncate(read);
+            Ok(Some((Bytes::from(chunk), reader)))
+        });
+
+        Ok(Box::pin(stream))
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<ObjectInfo>, AppError> {
+        let mut objects = Vec::new();
+        let mut pending = vec![self.base_path.clone()];
+
+        while let Some(dir) = pending.pop() {
+            let mut entries = match fs::read_dir(&dir).await {
+                Ok(entries) => entries,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(AppError::StorageError(e.to_string())),
+            };
+
+            while let Some(entry) = entries.next_entry().await? {
+                let path = entry.path();
+                let key = match path.strip_prefix(&self.base_path) {
+                    Ok(relative) => relative.to_string_lossy().replace('\\', "/"),
+                    Err(_) => continue,
+                };
+
+                if entry.file_type().await?.is_dir() {
+                    // Only descend into directories that can contain matching keys
+                    let dir_key = format!("{}/", key);
+                    if dir_key.starts_with(prefix) || prefix.starts_with(&dir_key) {
+                        pending.push(path);
+                    }
+                } else if key.starts_with(prefix) {
+                    objects.push(self.object_info(&key, &path).await?);
+                }
+            }
+        }
+
+        Ok(objects)
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), AppError> {
+        let path = self.path_for(key)?;
+        fs::remove_file(&path).await.map_err(|e| match e.kind() {
+            std::io::ErrorKind::NotFound => AppError::NotFound(format!("Object not found: {}", key)),
+            _ => {
+                error!("Failed to delete {}: {}", path.display(), e);
+                AppError::StorageError(e.to_string())
+            }
+        })?;
+
+        info!("Deleted {}", key);
+        Ok(())
+    }
+
+    async fn stat(&self, key: &str) -> Result<ObjectInfo, AppError> {
+        let path = self.path_for(key)?;
+        self.object_info(key, &path).await
+    }
+}
+
+pub struct LocalUpload {
+    key: String,
+    path: PathBuf,
+    file: File,
+    size: u64,
+    // Object the file is renamed over on finalize
+    replaces: Option<PathBuf>,
+}
+
+#[async_trait]
+impl UploadSession for LocalUpload {
+    async fn append(&mut self, data: &[u8]) -> Result<(), AppError> {
+        self.file.write_all(data).await.map_err(|e| {
+            error!("Failed to write to {}: {}", self.path.display(), e);
+            AppError::StorageError(e.to_string())
+        })?;
+        self.size += data.len() as u64;
+        Ok(())
+    }
+
+    async fn finalize(mut self: Box<Self>) -> Result<ObjectInfo, AppError> {
+        self.file.flush().await?;
+        self.file.sync_all().await?;
+        if let Some(target) = &self.replaces {
+            fs::rename(&self.path, target).await.map_err(|e| {
+                error!("Failed to replace {}: {}", target.display(), e);
+                AppError::StorageError(e.to_string())
+            })?;
+        }
+
+        Ok(ObjectInfo {
+            key: self.key.clone(),
+            size: self.size,
+            last_modified: Utc::now(),
+            etag: None,
+        })
+    }
+
+    async fn abort(self: Box<Self>) -> Result<(), AppError> {
+        drop(self.file);
+        fs::remove_file(&self.path).await.map_err(|e| AppError::StorageError(e.to_string()))
+    }
+}
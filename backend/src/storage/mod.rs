@@ -0,0 +1,246 @@
+/*
+ * storage/mod.rs
+ * Purpose: Recording storage
+ *
+ * This file contains:
+ * - StorageBackend and UploadSession traits implemented by each backend
+ * - Storage, the recording-oriented facade used by the rest of the server
+ * - Backend and layout selection from Config
+ * - Segment naming for recordings that roll over at the size limit
+ */
+
+pub mod layout;
+pub mod local;
+pub mod s3;
+
+use std::{pin::Pin, sync::Arc};
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::Stream;
+use chrono::{DateTime, Utc};
+use tracing::{error, info};
+use uuid::Uuid;
+use crate::{
+    config::{Config, StorageBackendConfig},
+    error::AppError,
+};
+
+pub use layout::{StorageLayout, DEFAULT_LAYOUT};
+pub use local::LocalBackend;
+pub use s3::S3Backend;
+
+// Object contents delivered in chunks, for responses that shouldn't buffer whole files
+pub type ByteStream = Pin<Box<dyn Stream<Item = Result<Bytes, std::io::Error>> + Send>>;
+
+// Extensions recording segments are stored under; older recordings were all .mp4
+pub const RECORDING_EXTENSIONS: [&str; 6] = ["webm", "mkv", "mka", "mjpeg", "mp4", "bin"];
+
+// Metadata about a stored object
+#[derive(Debug, Clone)]
+pub struct ObjectInfo {
+    pub key: String,
+    pub size: u64,
+    pub last_modified: DateTime<Utc>,
+    pub etag: Option<String>,
+}
+
+// An object being written incrementally, e.g. a recording in progress
+#[async_trait]
+pub trait UploadSession: Send + Sync {
+    async fn append(&mut self, data: &[u8]) -> Result<(), AppError>;
+
+    // Make the object durable and visible under its key
+    async fn finalize(self: Box<Self>) -> Result<ObjectInfo, AppError>;
+
+    // Discard everything written so far
+    async fn abort(self: Box<Self>) -> Result<(), AppError>;
+}
+
+#[async_trait]
+pub trait StorageBackend: Send + Sync {
+    async fn create_session(&self, key: &str) -> Result<Box<dyn UploadSession>, AppError>;
+
+    // Rewrite an existing object; it keeps its old contents, readable meanwhile,
+    // until the session is finalized
+    async fn replace_session(&self, key: &str) -> Result<Box<dyn UploadSession>, AppError>;
+
+    // Write a small object in one go
+    async fn put(&self, key: &str, data: &[u8]) -> Result<(), AppError>;
+
+    // Read bytes `start..end` (end exclusive, None for end of object)
+    async fn read_range(&self, key: &str, start: u64, end: Option<u64>) -> Result<Vec<u8>, AppError>;
+
+    // Same range semantics as read_range, streamed
+    async fn stream_range(&self, key: &str, start: u64, end: Option<u64>) -> Result<ByteStream, AppError>;
+
+    // All objects whose key starts with `prefix`
+    async fn list(&self, prefix: &str) -> Result<Vec<ObjectInfo>, AppError>;
+
+    async fn delete(&self, key: &str) -> Result<(), AppError>;
+
+    async fn stat(&self, key: &str) -> Result<ObjectInfo, AppError>;
+}
+
+#[derive(Clone)]
+pub struct Storage {
+    backend: Arc<dyn StorageBackend>,
+    layout: StorageLayout,
+    max_file_size: Option<u64>,
+}
+
+impl Storage {
+    pub async fn new(config: &Config) -> Result<Self, AppError> {
+        let backend: Arc<dyn StorageBackend> = match &config.storage_backend {
+            StorageBackendConfig::Local => {
+                Arc::new(LocalBackend::new(&config.storage_path, config.storage_file_mode).await?)
+            }
+            StorageBackendConfig::S3(s3) => Arc::new(S3Backend::new(s3.clone()).await?),
+        };
+
+        let layout = StorageLayout::parse(&config.storage_layout)?;
+        info!("Recording layout: {}", layout.template());
+
+        Ok(Self {
+            backend,
+            layout,
+            max_file_size: config.storage_max_file_size,
+        })
+    }
+
+    pub async fn local(base_path: &str) -> Result<Self, AppError> {
+        Ok(Self::with_backend(Arc::new(LocalBackend::new(base_path, None).await?)))
+    }
+
+    pub fn with_backend(backend: Arc<dyn StorageBackend>) -> Self {
+        Self {
+            backend,
+            layout: StorageLayout::parse(DEFAULT_LAYOUT).expect("default layout is valid"),
+            max_file_size: None,
+        }
+    }
+
+    pub fn backend(&self) -> &Arc<dyn StorageBackend> {
+        &self.backend
+    }
+
+    pub fn max_file_size(&self) -> Option<u64> {
+        self.max_file_size
+    }
+
+    // Directory a recording's segments are written to
+    pub fn recording_dir(&self, user_id: Option<&Uuid>, room_id: &str, start_time: DateTime<Utc>) -> String {
+        self.layout.render(user_id, room_id, start_time)
+    }
+
+    // File name without segment suffix or extension. Tracks stored in files of
+    // their own are named after the track.
+    pub fn recording_stem(recording_id: &Uuid, start_time: DateTime<Utc>, track: Option<&str>) -> String {
+        match track {
+            Some(track) => format!("{}_{}_{}", start_time.timestamp(), recording_id, track),
+            None => format!("{}_{}", start_time.timestamp(), recording_id),
+        }
+    }
+
+    // Segment 0 keeps the plain name; rollovers get a numeric suffix. The
+    // extension names the container the segment holds.
+    pub fn segment_filename(stem: &str, index: u32, extension: &str) -> String {
+        match index {
+            0 => format!("{}.{}", stem, extension),
+            n => format!("{}_{:03}.{}", stem, n, extension),
+        }
+    }
+
+    pub async fn create_segment(&self, dir: &str, filename: &str) -> Result<Box<dyn UploadSession>, AppError> {
+        let key = format!("{}/{}", dir, filename);
+
+        let session = self.backend.create_session(&key).await.map_err(|e| {
+            error!("Failed to create recording {}: {}", key, e);
+            e
+        })?;
+
+        info!("Created recording: {}", key);
+        Ok(session)
+    }
+
+    // Rewrite a finalized recording in place, e.g. to add an index
+    pub async fn replace_recording(&self, key: &str) -> Result<Box<dyn UploadSession>, AppError> {
+        self.backend.replace_session(key).await
+    }
+
+    // Write a companion object next to a recording, e.g. `{key}.refs.json`
+    pub async fn write_sidecar(&self, key: &str, suffix: &str, data: &[u8]) -> Result<(), AppError> {
+        self.backend.put(&format!("{}.{}", key, suffix), data).await
+    }
+
+    pub async fn get_recording(&self, key: &str) -> Result<Vec<u8>, AppError> {
+        self.backend.read_range(key, 0, None).await
+    }
+
+    pub async fn stream_recording(&self, key: &str, start: u64, end: Option<u64>) -> Result<ByteStream, AppError> {
+        self.backend.stream_range(key, start, end).await
+    }
+
+    pub async fn read_recording_range(&self, key: &str, start: u64, end: Option<u64>) -> Result<Vec<u8>, AppError> {
+        self.backend.read_range(key, start, end).await
+    }
+
+    pub async fn stat_recording(&self, key: &str) -> Result<ObjectInfo, AppError> {
+        self.backend.stat(key).await
+    }
+
+    // Keys of every recording segment stored for the room
+    pub async fn list_recordings(&self, room_id: &str) -> Result<Vec<String>, AppError> {
+        let mut recordings: Vec<String> = self.room_objects(room_id)
+            .await?
+            .into_iter()
+            .map(|object| object.key)
+            .filter(|key| {
+                key.rsplit_once('.')
+                    .is_some_and(|(_, extension)| RECORDING_EXTENSIONS.contains(&extension))
+            })
+            .collect();
+
+        recordings.sort();
+        Ok(recordings)
+    }
+
+    // Every object stored under the room's directories: recordings, segments and sidecars
+    async fn room_objects(&self, room_id: &str) -> Result<Vec<ObjectInfo>, AppError> {
+        Ok(self.backend
+            .list(&self.layout.room_prefix(room_id))
+            .await?
+            .into_iter()
+            .filter(|object| match object.key.rsplit_once('/') {
+                Some((dir, _)) => self.layout.matches_room(dir, room_id),
+                None => false,
+            })
+            .collect())
+    }
+
+    // Remove everything stored for a room, returning how many objects were deleted
+    pub async fn delete_room(&self, room_id: &str) -> Result<usize, AppError> {
+        let objects = self.room_objects(room_id).await?;
+        for object in &objects {
+            self.backend.delete(&object.key).await?;
+        }
+        Ok(objects.len())
+    }
+
+    pub async fn delete_recording(&self, key: &str) -> Result<(), AppError> {
+        self.backend.delete(key).await
+    }
+
+    pub async fn cleanup_old_recordings(&self, days: i64) -> Result<(), AppError> {
+        let cutoff = Utc::now() - chrono::Duration::days(days);
+
+        for object in self.backend.list("").await? {
+            if object.last_modified < cutoff {
+                if let Err(e) = self.backend.delete(&object.key).await {
+                    error!("Failed to delete old recording {}: {}", object.key, e);
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,308 @@
+/*
+ * stream_hub.rs
+ * Purpose: Per-room live stream distribution
+ *
+ * This file contains:
+ * - StreamPacket, the unit fanned out from a publisher to viewers
+ * - StreamHub, the room's broadcast channel plus the state late joiners need:
+ *   the WebM init segment and everything since the latest keyframe cluster
+ * - The room's close signal, watched by every connection
+ * - The room's live HLS packaging, fed with everything published
+ *
+ * WebM chunks are cut at cluster boundaries before they are broadcast, so a
+ * packet flagged as keyframe is a point where a viewer can start decoding.
+ * Bytes the scanner hasn't parsed yet (a partial element header, or a cluster
+ * start before its first video block) are held back until the next chunk.
+ */
+
+use std::sync::Mutex;
+use bytes::{Bytes, BytesMut};
+use tokio::sync::{broadcast, watch};
+use crate::{
+    hls::LiveHls,
+    models::{FrameType, RoomConfig},
+    webm::{WebmEvent, WebmScanner},
+};
+
+// Packets per room buffered for viewers before the slowest starts lagging
+const STREAM_CHANNEL_CAPACITY: usize = 100;
+
+// Headers are a few hundred bytes; anything bigger isn't an init segment we can replay
+const MAX_INIT_SEGMENT_BYTES: usize = 1024 * 1024;
+
+// A cluster whose first video block hasn't arrived after this much is passed on unflagged
+const MAX_UNDECIDED_BYTES: usize = 256 * 1024;
+
+// Upper bound on the keyframe cache; a longer GOP isn't cached until the next keyframe
+const MAX_GOP_CACHE_BYTES: usize = 8 * 1024 * 1024;
+
+// Media fanned out from a room's publisher to its viewers
+#[derive(Debug, Clone)]
+pub enum StreamPacket {
+    // Opaque container chunk, e.g. part of a MediaRecorder WebM stream
+    Chunk {
+        data: Bytes,
+        // Starts a cluster whose first video block is a keyframe; always set
+        // for streams that aren't WebM
+        keyframe: bool,
+    },
+    Frame {
+        frame_type: FrameType,
+        timestamp: i64,
+        data: Bytes,
+    },
+}
+
+impl StreamPacket {
+    pub fn len(&self) -> usize {
+        match self {
+            StreamPacket::Chunk { data, .. } | StreamPacket::Frame { data, .. } => data.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Whether a viewer that skipped packets can pick the stream up again here.
+    // Frames stand alone as far as the server can tell.
+    pub fn is_resume_point(&self) -> bool {
+        match self {
+            StreamPacket::Chunk { keyframe, .. } => *keyframe,
+            StreamPacket::Frame { .. } => true,
+        }
+    }
+}
+
+// Late-joiner state, updated as packets are published
+struct HubCache {
+    scanner: WebmScanner,
+    // Bytes received but not yet broadcast, starting at stream offset pending_offset.
+    // Held back until the scanner knows whether they start a keyframe cluster.
+    pending: BytesMut,
+    pending_offset: u64,
+    pending_keyframe: bool,
+    // A cluster starts at pending_offset and its first video block hasn't been seen yet
+    undecided: bool,
+    init_segment: Vec<u8>,
+    init_complete: bool,
+    // Chunks from the latest keyframe cluster up to the live edge
+    gop: Vec<Bytes>,
+    gop_bytes: usize,
+    gop_valid: bool,
+    // Latest JPEG video frame, decodable on its own
+    last_image: Option<StreamPacket>,
+}
+
+impl HubCache {
+    fn new() -> Self {
+        Self {
+            scanner: WebmScanner::new(),
+            pending: BytesMut::new(),
+            pending_offset: 0,
+            pending_keyframe: false,
+            undecided: false,
+            init_segment: Vec::new(),
+            init_complete: false,
+            gop: Vec::new(),
+            gop_bytes: 0,
+            gop_valid: false,
+            last_image: None,
+        }
+    }
+
+    // Cut the pending bytes into pieces at cluster starts, flagging the pieces that
+    // begin at a keyframe. Returns (stream offset, piece, keyframe) ready to broadcast.
+    fn split_chunk(&mut self, data: Bytes) -> Vec<(u64, Bytes, bool)> {
+        let mut pieces = Vec::new();
+        self.pending.extend_from_slice(&data);
+
+        for event in self.scanner.feed(&data) {
+            match event {
+                WebmEvent::ClusterStart { offset } => {
+                    // An earlier cluster that never showed a video block isn't a start point
+                    self.undecided = false;
+                    self.emit_until(offset, &mut pieces);
+                    self.undecided = true;
+                }
+                WebmEvent::Block { track, keyframe, .. } if self.undecided => {
+                    if !self.scanner.has_video() || self.scanner.is_video_track(track) {
+                        self.pending_keyframe = keyframe;
+                        self.undecided = false;
+                    }
+                }
+                WebmEvent::Block { .. } => {}
+            }
+        }
+
+        if self.scanner.failed() {
+            // Not WebM: pass everything through untouched. Opaque chunks can't be
+            // inspected, so each one counts as a point where viewers may resume.
+            self.undecided = false;
+            self.pending_keyframe = true;
+            let end = self.pending_offset + self.pending.len() as u64;
+            self.emit_until(end, &mut pieces);
+        } else if !self.undecided || self.pending.len() > MAX_UNDECIDED_BYTES {
+            self.undecided = false;
+            self.emit_until(self.scanner.consumed(), &mut pieces);
+        }
+
+        pieces
+    }
+
+    fn emit_until(&mut self, end: u64, pieces: &mut Vec<(u64, Bytes, bool)>) {
+        let len = end.saturating_sub(self.pending_offset) as usize;
+        if len == 0 {
+            return;
+        }
+        let piece = self.pending.split_to(len.min(self.pending.len())).freeze();
+        pieces.push((self.pending_offset, piece.clone(), self.pending_keyframe));
+        self.pending_offset += piece.len() as u64;
+        self.pending_keyframe = false;
+    }
+
+    fn observe_piece(&mut self, piece: &Bytes, keyframe: bool, piece_offset: u64) {
+        // Not WebM, or not a stream we can follow: nothing to cache
+        if self.scanner.failed() {
+            self.init_segment = Vec::new();
+            self.gop.clear();
+            self.gop_valid = false;
+            return;
+        }
+
+        if !self.init_complete {
+            match self.scanner.init_segment_len() {
+                Some(len) if piece_offset >= len => self.init_complete = true,
+                _ => {
+                    if self.init_segment.len() + piece.len() <= MAX_INIT_SEGMENT_BYTES {
+                        self.init_segment.extend_from_slice(piece);
+                    }
+                    return;
+                }
+            }
+        }
+
+        if keyframe {
+            self.gop.clear();
+            self.gop_bytes = 0;
+            self.gop_valid = true;
+        }
+        if !self.gop_valid {
+            return;
+        }
+
+        self.gop_bytes += piece.len();
+        if self.gop_bytes > MAX_GOP_CACHE_BYTES {
+            self.gop.clear();
+            self.gop_bytes = 0;
+            self.gop_valid = false;
+            return;
+        }
+        self.gop.push(piece.clone());
+    }
+
+    fn catch_up(&self) -> Vec<StreamPacket> {
+        let mut packets = Vec::new();
+
+        // The init segment is only replayable if it was captured in full
+        let init_usable = self.init_complete
+            && self.scanner.init_segment_len() == Some(self.init_segment.len() as u64);
+        if init_usable && self.gop_valid {
+            packets.push(StreamPacket::Chunk {
+                data: Bytes::copy_from_slice(&self.init_segment),
+                keyframe: false,
+            });
+            packets.extend(self.gop.iter().enumerate().map(|(i, data)| StreamPacket::Chunk {
+                data: data.clone(),
+                keyframe: i == 0,
+            }));
+        }
+        if let Some(image) = &self.last_image {
+            packets.push(image.clone());
+        }
+
+        packets
+    }
+}
+
+pub struct StreamHub {
+    tx: broadcast::Sender<StreamPacket>,
+    cache: Mutex<HubCache>,
+    hls: Mutex<LiveHls>,
+    // Set once when the room is closed
+    closed: watch::Sender<bool>,
+}
+
+impl StreamHub {
+    pub fn new() -> Self {
+        Self {
+            tx: broadcast::channel(STREAM_CHANNEL_CAPACITY).0,
+            cache: Mutex::new(HubCache::new()),
+            hls: Mutex::new(LiveHls::default()),
+            closed: watch::channel(false).0,
+        }
+    }
+
+    // Tell every connection on this hub to disconnect
+    pub fn close(&self) {
+        self.closed.send_replace(true);
+    }
+
+    pub fn is_closed(&self) -> bool {
+        *self.closed.borrow()
+    }
+
+    pub fn watch_closed(&self) -> watch::Receiver<bool> {
+        self.closed.subscribe()
+    }
+
+    // A new publisher starts a new stream; forget the previous one's headers
+    pub fn reset(&self, config: &RoomConfig) {
+        *self.cache.lock().unwrap() = HubCache::new();
+        self.hls.lock().unwrap().restart(config);
+    }
+
+    // The publisher has left; the HLS playlist gets its last segment and ends
+    pub fn end_publish(&self) {
+        self.hls.lock().unwrap().end();
+    }
+
+    pub fn hls(&self) -> &Mutex<LiveHls> {
+        &self.hls
+    }
+
+    pub fn publish_chunk(&self, data: Bytes) {
+        self.hls.lock().unwrap().push_chunk(&data);
+        let mut cache = self.cache.lock().unwrap();
+        for (offset, piece, keyframe) in cache.split_chunk(data) {
+            cache.observe_piece(&piece, keyframe, offset);
+            let _ = self.tx.send(StreamPacket::Chunk { data: piece, keyframe });
+        }
+    }
+
+    pub fn publish_frame(&self, frame_type: FrameType, timestamp: i64, data: Bytes) {
+        self.hls.lock().unwrap().push_frame(frame_type, timestamp, &data);
+        let packet = StreamPacket::Frame { frame_type, timestamp, data };
+
+        let mut cache = self.cache.lock().unwrap();
+        if let StreamPacket::Frame { frame_type: FrameType::Video, data, .. } = &packet {
+            if data.starts_with(&[0xFF, 0xD8]) {
+                cache.last_image = Some(packet.clone());
+            }
+        }
+        let _ = self.tx.send(packet);
+    }
+
+    // Packets a new viewer needs before live data, and the live subscription.
+    // Taken under the cache lock so nothing is missed or delivered twice.
+    pub fn subscribe(&self) -> (Vec<StreamPacket>, broadcast::Receiver<StreamPacket>) {
+        let cache = self.cache.lock().unwrap();
+        (cache.catch_up(), self.tx.subscribe())
+    }
+}
+
+impl Default for StreamHub {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,892 @@
+/*
+ * webm.rs
+ * Purpose: Incremental WebM (Matroska/EBML) scanning
+ *
+ * This file contains:
+ * - EBML variable-length integer decoding and element encoding
+ * - WebmScanner, which walks a byte stream fed in arbitrary chunks and reports
+ *   where the init segment ends, where clusters start and every block's track,
+ *   timecode, position, size and keyframe flag, along with the DocType, the
+ *   timecode scale and each track's number, type, codec ID, CodecPrivate and
+ *   dimensions
+ * - Mapping between Matroska codec IDs and the codec names used in room configs
+ *
+ * Only the elements needed to follow the stream are descended into; everything
+ * else is skipped by size without being buffered. MediaRecorder writes Segment
+ * and Cluster with unknown sizes, so those end when a sibling element appears.
+ *
+ * A stream that doesn't start with an EBML header isn't WebM and is simply not
+ * followed. One that does but then breaks the structure (bad sizes, elements
+ * overrunning their parent, blocks outside a Cluster or for undeclared tracks)
+ * is corrupt, and the scanner reports why.
+ */
+
+use std::collections::BTreeMap;
+
+pub const EBML_HEADER_ID: u32 = 0x1A45DFA3;
+pub const EBML_VERSION_ID: u32 = 0x4286;
+pub const EBML_READ_VERSION_ID: u32 = 0x42F7;
+pub const EBML_MAX_ID_LENGTH_ID: u32 = 0x42F2;
+pub const EBML_MAX_SIZE_LENGTH_ID: u32 = 0x42F3;
+pub const DOC_TYPE_ID: u32 = 0x4282;
+pub const DOC_TYPE_VERSION_ID: u32 = 0x4287;
+pub const DOC_TYPE_READ_VERSION_ID: u32 = 0x4285;
+pub const SEGMENT_ID: u32 = 0x18538067;
+pub const SEEK_HEAD_ID: u32 = 0x114D9B74;
+pub const SEEK_ID: u32 = 0x4DBB;
+pub const SEEK_ID_ID: u32 = 0x53AB;
+pub const SEEK_POSITION_ID: u32 = 0x53AC;
+pub const INFO_ID: u32 = 0x1549A966;
+pub const TIMECODE_SCALE_ID: u32 = 0x2AD7B1;
+pub const DURATION_ID: u32 = 0x4489;
+pub const MUXING_APP_ID: u32 = 0x4D80;
+pub const WRITING_APP_ID: u32 = 0x5741;
+pub const TRACKS_ID: u32 = 0x1654AE6B;
+pub const TRACK_ENTRY_ID: u32 = 0xAE;
+pub const TRACK_NUMBER_ID: u32 = 0xD7;
+pub const TRACK_UID_ID: u32 = 0x73C5;
+pub const TRACK_TYPE_ID: u32 = 0x83;
+pub const FLAG_LACING_ID: u32 = 0x9C;
+pub const CODEC_ID_ID: u32 = 0x86;
+pub const CODEC_PRIVATE_ID: u32 = 0x63A2;
+pub const VIDEO_ID: u32 = 0xE0;
+pub const PIXEL_WIDTH_ID: u32 = 0xB0;
+pub const PIXEL_HEIGHT_ID: u32 = 0xBA;
+pub const AUDIO_ID: u32 = 0xE1;
+pub const SAMPLING_FREQUENCY_ID: u32 = 0xB5;
+pub const CHANNELS_ID: u32 = 0x9F;
+pub const BIT_DEPTH_ID: u32 = 0x6264;
+pub const CLUSTER_ID: u32 = 0x1F43B675;
+pub const TIMECODE_ID: u32 = 0xE7;
+pub const SIMPLE_BLOCK_ID: u32 = 0xA3;
+pub const BLOCK_GROUP_ID: u32 = 0xA0;
+pub const BLOCK_ID: u32 = 0xA1;
+pub const CUES_ID: u32 = 0x1C53BB6B;
+pub const CUE_POINT_ID: u32 = 0xBB;
+pub const CUE_TIME_ID: u32 = 0xB3;
+pub const CUE_TRACK_POSITIONS_ID: u32 = 0xB7;
+pub const CUE_TRACK_ID: u32 = 0xF7;
+pub const CUE_CLUSTER_POSITION_ID: u32 = 0xF1;
+pub const CHAPTERS_ID: u32 = 0x1043A770;
+pub const TAGS_ID: u32 = 0x1254C367;
+pub const ATTACHMENTS_ID: u32 = 0x1941A469;
+pub const VOID_ID: u32 = 0xEC;
+
+// Matroska TrackType values
+pub const TRACK_TYPE_VIDEO: u64 = 1;
+pub const TRACK_TYPE_AUDIO: u64 = 2;
+
+// Used when Info has no TimecodeScale: timecodes in milliseconds
+pub const DEFAULT_TIMECODE_SCALE: u64 = 1_000_000;
+
+// Track number (vint) + timecode (2 bytes) + flags (1 byte)
+const BLOCK_HEADER_MAX: usize = 8 + 3;
+
+// Longest string element read; DocType and CodecID are a few bytes
+const MAX_STRING_LEN: u64 = 64;
+
+// Longest CodecPrivate kept, e.g. an avcC or OpusHead
+const MAX_CODEC_PRIVATE_LEN: u64 = 64 * 1024;
+
+// Matroska codec IDs and the codec names room configs use for them
+pub const CODECS: [(&str, &str); 9] = [
+    ("V_MJPEG", "jpeg"),
+    ("V_MPEG4/ISO/AVC", "h264"),
+    ("V_VP8", "vp8"),
+    ("V_VP9", "vp9"),
+    ("V_AV1", "av1"),
+    ("A_OPUS", "opus"),
+    ("A_VORBIS", "vorbis"),
+    ("A_AAC", "aac"),
+    ("A_PCM/INT/LIT", "pcm"),
+];
+
+// Short codec name for a Matroska codec ID; AAC IDs carry a profile suffix
+pub fn codec_name(codec_id: &str) -> Option<&'static str> {
+    CODECS
+        .iter()
+        .find(|(id, _)| codec_id == *id || (*id == "A_AAC" && codec_id.starts_with("A_AAC/")))
+        .map(|(_, name)| *name)
+}
+
+pub fn codec_id(name: &str) -> Option<&'static str> {
+    CODECS.iter().find(|(_, n)| *n == name).map(|(id, _)| *id)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WebmEvent {
+    // A Cluster element header starts at this absolute stream offset
+    ClusterStart { offset: u64 },
+    Block {
+        track: u64,
+        keyframe: bool,
+        // Cluster timecode plus the block's offset from it, in milliseconds
+        timecode: i64,
+        // Absolute stream offset and length of the frame bytes, after the block header
+        offset: u64,
+        size: u64,
+        // Whether the block packs several frames with Matroska lacing
+        laced: bool,
+    },
+}
+
+// A TrackEntry from the stream's Tracks
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct WebmTrack {
+    pub number: u64,
+    pub track_type: u64,
+    pub codec_id: Option<String>,
+    pub codec_private: Option<Vec<u8>>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+}
+
+impl WebmTrack {
+    pub fn is_video(&self) -> bool {
+        self.track_type == TRACK_TYPE_VIDEO
+    }
+
+    pub fn is_audio(&self) -> bool {
+        self.track_type == TRACK_TYPE_AUDIO
+    }
+
+    pub fn codec_name(&self) -> Option<&'static str> {
+        self.codec_id.as_deref().and_then(codec_name)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Master {
+    id: u32,
+    // Absolute end offset, None for unknown-size elements
+    end: Option<u64>,
+}
+
+#[derive(Debug, Default, Clone)]
+struct TrackEntry {
+    number: Option<u64>,
+    track_type: Option<u64>,
+    codec_id: Option<String>,
+    codec_private: Option<Vec<u8>>,
+    width: Option<u64>,
+    height: Option<u64>,
+}
+
+enum Vint {
+    Value(u64, usize),
+    Incomplete,
+    Invalid,
+}
+
+pub struct WebmScanner {
+    buf: Vec<u8>,
+    // Absolute offset of buf[0]
+    buf_offset: u64,
+    // Body bytes of the current element still to be skipped
+    skip: u64,
+    stack: Vec<Master>,
+    track: TrackEntry,
+    tracks: BTreeMap<u64, WebmTrack>,
+    doc_type: Option<String>,
+    timecode_scale: u64,
+    // Absolute offset of the Segment's body, which Cues and SeekHead positions count from
+    segment_data_offset: Option<u64>,
+    cluster_timecode: Option<u64>,
+    first_cluster: Option<u64>,
+    failed: bool,
+    // Why a stream that started as WebM stopped parsing
+    corruption: Option<String>,
+}
+
+impl WebmScanner {
+    pub fn new() -> Self {
+        Self {
+            buf: Vec::new(),
+            buf_offset: 0,
+            skip: 0,
+            stack: Vec::new(),
+            track: TrackEntry::default(),
+            tracks: BTreeMap::new(),
+            doc_type: None,
+            timecode_scale: DEFAULT_TIMECODE_SCALE,
+            segment_data_offset: None,
+            cluster_timecode: None,
+            first_cluster: None,
+            failed: false,
+            corruption: None,
+        }
+    }
+
+    // Whether the stream stopped looking like WebM; no further events are produced
+    pub fn failed(&self) -> bool {
+        self.failed
+    }
+
+    // Set when the stream started as WebM and then broke; says what was wrong
+    pub fn corruption(&self) -> Option<&str> {
+        self.corruption.as_deref()
+    }
+
+    // "webm" or "matroska", once the EBML header has been parsed
+    pub fn doc_type(&self) -> Option<&str> {
+        self.doc_type.as_deref()
+    }
+
+    // Nanoseconds per timecode unit
+    pub fn timecode_scale(&self) -> u64 {
+        self.timecode_scale
+    }
+
+    pub fn segment_data_offset(&self) -> Option<u64> {
+        self.segment_data_offset
+    }
+
+    // Tracks declared so far, in track number order
+    pub fn tracks(&self) -> impl Iterator<Item = &WebmTrack> {
+        self.tracks.values()
+    }
+
+    // Offset of the first Cluster, i.e. the length of the init segment, once seen
+    pub fn init_segment_len(&self) -> Option<u64> {
+        self.first_cluster
+    }
+
+    // Stream offset up to which elements have been parsed; later bytes are still buffered
+    pub fn consumed(&self) -> u64 {
+        self.buf_offset
+    }
+
+    pub fn has_video(&self) -> bool {
+        self.tracks.values().any(WebmTrack::is_video)
+    }
+
+    pub fn is_video_track(&self, track: u64) -> bool {
+        self.tracks.get(&track).is_some_and(WebmTrack::is_video)
+    }
+
+    pub fn track(&self, number: u64) -> Option<&WebmTrack> {
+        self.tracks.get(&number)
+    }
+
+    // Width and height of the first video track, once its TrackEntry has been parsed
+    pub fn video_size(&self) -> Option<(u32, u32)> {
+        self.tracks
+            .values()
+            .find(|track| track.is_video())
+            .and_then(|track| Some((track.width?, track.height?)))
+    }
+
+    pub fn feed(&mut self, data: &[u8]) -> Vec<WebmEvent> {
+        let mut events = Vec::new();
+        if self.failed {
+            return events;
+        }
+
+        self.buf.extend_from_slice(data);
+        let mut pos = 0usize;
+
+        loop {
+            if self.skip > 0 {
+                let n = self.skip.min((self.buf.len() - pos) as u64);
+                pos += n as usize;
+                self.skip -= n;
+                if self.skip > 0 {
+                    break;
+                }
+            }
+
+            let offset = self.buf_offset + pos as u64;
+            self.close_finished(offset);
+
+            let rest = &self.buf[pos..];
+            if rest.is_empty() {
+                break;
+            }
+            if offset == 0 {
+                if rest.len() < 4 {
+                    break;
+                }
+                // Not WebM at all, which is not corruption
+                if read_u32(rest) != EBML_HEADER_ID {
+                    self.failed = true;
+                    break;
+                }
+            }
+
+            let (id, id_len) = match read_vint(rest, 4, true) {
+                Vint::Value(id, len) => (id as u32, len),
+                Vint::Incomplete => break,
+                Vint::Invalid => {
+                    self.corrupt(offset, "invalid element ID");
+                    break;
+                }
+            };
+            let (size, size_len) = match read_vint(&rest[id_len..], 8, false) {
+                Vint::Value(size, len) => (unknown_size(size, len), len),
+                Vint::Incomplete => break,
+                Vint::Invalid => {
+                    self.corrupt(offset, "invalid element size");
+                    break;
+                }
+            };
+            let header_len = id_len + size_len;
+            let body_start = offset + header_len as u64;
+            let end = size.map(|s| body_start + s);
+
+            // Top-level elements end everything open; Segment children end an open
+            // unknown-size Cluster, or anything else left open inside the Segment
+            if matches!(id, EBML_HEADER_ID | SEGMENT_ID) {
+                while !self.stack.is_empty() {
+                    self.pop_master();
+                }
+            } else if is_segment_child(id) {
+                while self.stack.last().is_some_and(|m| m.id != SEGMENT_ID) {
+                    self.pop_master();
+                }
+            }
+
+            if let (Some(end), Some(parent_end)) = (end, self.stack.last().and_then(|m| m.end)) {
+                if end > parent_end {
+                    self.corrupt(offset, "element overruns its parent");
+                    break;
+                }
+            }
+
+            let body = &self.buf[pos + header_len..];
+            match id {
+                EBML_HEADER_ID | SEGMENT_ID | INFO_ID | TRACKS_ID | TRACK_ENTRY_ID | BLOCK_GROUP_ID | CLUSTER_ID => {
+                    match id {
+                        SEGMENT_ID => self.segment_data_offset = Some(body_start),
+                        CLUSTER_ID => {
+                            if self.tracks.is_empty() {
+                                self.corrupt(offset, "cluster before tracks");
+                                break;
+                            }
+                            self.first_cluster.get_or_insert(offset);
+                            self.cluster_timecode = None;
+                            events.push(WebmEvent::ClusterStart { offset });
+                        }
+                        TRACK_ENTRY_ID => self.track = TrackEntry::default(),
+                        BLOCK_GROUP_ID if !self.in_master(CLUSTER_ID) => {
+                            self.corrupt(offset, "block group outside a cluster");
+                            break;
+                        }
+                        _ => {}
+                    }
+                    self.stack.push(Master { id, end });
+                    pos += header_len;
+                }
+                VIDEO_ID if self.in_master(TRACK_ENTRY_ID) => {
+                    self.stack.push(Master { id, end });
+                    pos += header_len;
+                }
+                DOC_TYPE_ID | CODEC_ID_ID if self.in_master(value_parent(id)) => {
+                    let Some(size) = size else {
+                        self.corrupt(offset, "unknown-size string element");
+                        break;
+                    };
+                    if size > MAX_STRING_LEN {
+                        pos += header_len;
+                        self.skip = size;
+                        continue;
+                    }
+                    if body.len() < size as usize {
+                        break;
+                    }
+                    // Strings may be padded with trailing zeros
+                    let value = String::from_utf8_lossy(&body[..size as usize])
+                        .trim_end_matches('\0')
+                        .to_string();
+                    if id == DOC_TYPE_ID {
+                        if !matches!(value.as_str(), "webm" | "matroska") {
+                            self.corrupt(offset, "unsupported DocType");
+                            break;
+                        }
+                        self.doc_type = Some(value);
+                    } else {
+                        self.track.codec_id = Some(value);
+                    }
+                    pos += header_len + size as usize;
+                }
+                CODEC_PRIVATE_ID if self.in_master(TRACK_ENTRY_ID) => {
+                    let Some(size) = size else {
+                        self.corrupt(offset, "unknown-size CodecPrivate");
+                        break;
+                    };
+                    if size > MAX_CODEC_PRIVATE_LEN {
+                        pos += header_len;
+                        self.skip = size;
+                        continue;
+                    }
+                    if body.len() < size as usize {
+                        break;
+                    }
+                    self.track.codec_private = Some(body[..size as usize].to_vec());
+                    pos += header_len + size as usize;
+                }
+                TIMECODE_SCALE_ID | TRACK_NUMBER_ID | TRACK_TYPE_ID | PIXEL_WIDTH_ID | PIXEL_HEIGHT_ID | TIMECODE_ID
+                    if self.in_master(value_parent(id)) =>
+                {
+                    let Some(size) = size.filter(|s| *s <= 8) else {
+                        self.corrupt(offset, "invalid unsigned integer element");
+                        break;
+                    };
+                    if body.len() < size as usize {
+                        break;
+                    }
+                    let value = body[..size as usize].iter().fold(0u64, |acc, b| (acc << 8) | *b as u64);
+                    match id {
+                        TIMECODE_SCALE_ID => self.timecode_scale = value.max(1),
+                        TRACK_NUMBER_ID => self.track.number = Some(value),
+                        TRACK_TYPE_ID => self.track.track_type = Some(value),
+                        PIXEL_WIDTH_ID => self.track.width = Some(value),
+                        PIXEL_HEIGHT_ID => self.track.height = Some(value),
+                        _ => self.cluster_timecode = Some(value),
+                    }
+                    pos += header_len + size as usize;
+                }
+                SIMPLE_BLOCK_ID | BLOCK_ID => {
+                    let parent = if id == SIMPLE_BLOCK_ID { CLUSTER_ID } else { BLOCK_GROUP_ID };
+                    if !self.in_master(parent) {
+                        self.corrupt(offset, "block outside a cluster");
+                        break;
+                    }
+                    let Some(size) = size else {
+                        self.corrupt(offset, "unknown-size block");
+                        break;
+                    };
+                    let needed = (size as usize).min(BLOCK_HEADER_MAX);
+                    if body.len() < needed {
+                        break;
+                    }
+                    let header = &body[..needed];
+                    let (track, track_len) = match read_vint(header, 8, false) {
+                        Vint::Value(track, len) if header.len() >= len + 3 => (track, len),
+                        _ => {
+                            self.corrupt(offset, "truncated block header");
+                            break;
+                        }
+                    };
+                    if !self.tracks.contains_key(&track) {
+                        self.corrupt(offset, "block for an undeclared track");
+                        break;
+                    }
+                    let Some(cluster_timecode) = self.cluster_timecode else {
+                        self.corrupt(offset, "block before the cluster timecode");
+                        break;
+                    };
+                    let relative = i16::from_be_bytes([header[track_len], header[track_len + 1]]);
+                    let timecode = (cluster_timecode as i64 + i64::from(relative))
+                        .saturating_mul(self.timecode_scale as i64)
+                        / 1_000_000;
+                    // Only SimpleBlock carries a keyframe flag; plain Blocks are treated as inter frames
+                    let flags = header[track_len + 2];
+                    let keyframe = id == SIMPLE_BLOCK_ID && flags & 0x80 != 0;
+                    events.push(WebmEvent::Block {
+                        track,
+                        keyframe,
+                        timecode,
+                        offset: body_start + (track_len + 3) as u64,
+                        size: size - (track_len + 3) as u64,
+                        laced: flags & 0x06 != 0,
+                    });
+
+                    pos += header_len;
+                    self.skip = size;
+                }
+                _ => {
+                    let Some(size) = size else {
+                        self.corrupt(offset, "unknown-size element");
+                        break;
+                    };
+                    pos += header_len;
+                    self.skip = size;
+                }
+            }
+        }
+
+        self.buf.drain(..pos);
+        self.buf_offset += pos as u64;
+        events
+    }
+
+    fn corrupt(&mut self, offset: u64, reason: &str) {
+        self.failed = true;
+        self.corruption = Some(format!("{} at offset {}", reason, offset));
+    }
+
+    fn in_master(&self, id: u32) -> bool {
+        self.stack.last().is_some_and(|m| m.id == id)
+    }
+
+    fn close_finished(&mut self, offset: u64) {
+        while self.stack.last().is_some_and(|m| m.end.is_some_and(|end| offset >= end)) {
+            self.pop_master();
+        }
+    }
+
+    fn pop_master(&mut self) {
+        let Some(master) = self.stack.pop() else {
+            return;
+        };
+        if master.id != TRACK_ENTRY_ID {
+            return;
+        }
+        let entry = std::mem::take(&mut self.track);
+        if let (Some(number), Some(track_type)) = (entry.number, entry.track_type) {
+            self.tracks.insert(number, WebmTrack {
+                number,
+                track_type,
+                codec_id: entry.codec_id,
+                codec_private: entry.codec_private,
+                width: entry.width.map(|w| w as u32),
+                height: entry.height.map(|h| h as u32),
+            });
+        }
+    }
+}
+
+impl Default for WebmScanner {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn read_u32(data: &[u8]) -> u32 {
+    u32::from_be_bytes([data[0], data[1], data[2], data[3]])
+}
+
+// EBML vint: the number of leading zero bits in the first byte gives the length
+fn read_vint(data: &[u8], max_len: usize, keep_marker: bool) -> Vint {
+    let Some(&first) = data.first() else {
+        return Vint::Incomplete;
+    };
+    let len = first.leading_zeros() as usize + 1;
+    if len > max_len {
+        return Vint::Invalid;
+    }
+    if data.len() < len {
+        return Vint::Incomplete;
+    }
+
+    let mut value = if keep_marker { first as u64 } else { first as u64 & (0xFF >> len) };
+    for &byte in &data[1..len] {
+        value = (value << 8) | byte as u64;
+    }
+    Vint::Value(value, len)
+}
+
+// All value bits set means "unknown size"
+fn unknown_size(size: u64, len: usize) -> Option<u64> {
+    let all_ones = (1u64 << (7 * len)) - 1;
+    (size != all_ones).then_some(size)
+}
+
+// Master element that a value element is read from
+fn value_parent(id: u32) -> u32 {
+    match id {
+        DOC_TYPE_ID => EBML_HEADER_ID,
+        TIMECODE_SCALE_ID => INFO_ID,
+        PIXEL_WIDTH_ID | PIXEL_HEIGHT_ID => VIDEO_ID,
+        TIMECODE_ID => CLUSTER_ID,
+        _ => TRACK_ENTRY_ID,
+    }
+}
+
+fn is_segment_child(id: u32) -> bool {
+    matches!(
+        id,
+        CLUSTER_ID | SEEK_HEAD_ID | INFO_ID | TRACKS_ID | CUES_ID | CHAPTERS_ID | TAGS_ID | ATTACHMENTS_ID
+    )
+}
+
+// Header of the element at the start of `data`: ID, header length and size
+// (None when unknown); None if the header is incomplete or invalid
+pub fn read_element_header(data: &[u8]) -> Option<(u32, usize, Option<u64>)> {
+    let Vint::Value(id, id_len) = read_vint(data, 4, true) else {
+        return None;
+    };
+    let Vint::Value(size, size_len) = read_vint(&data[id_len..], 8, false) else {
+        return None;
+    };
+    Some((id as u32, id_len + size_len, unknown_size(size, size_len)))
+}
+
+pub fn id_bytes(id: u32) -> Vec<u8> {
+    let bytes = id.to_be_bytes();
+    let skip = bytes.iter().position(|b| *b != 0).unwrap_or(3);
+    bytes[skip..].to_vec()
+}
+
+// Shortest EBML vint that holds `size`; all ones is reserved for unknown sizes
+pub fn size_bytes(size: u64) -> Vec<u8> {
+    let len = (1..=8).find(|len| size < (1u64 << (7 * len)) - 1).unwrap_or(8);
+    let marked = size | (1u64 << (7 * len));
+    marked.to_be_bytes()[8 - len..].to_vec()
+}
+
+pub fn element(id: u32, body: &[u8]) -> Vec<u8> {
+    let mut out = id_bytes(id);
+    out.extend_from_slice(&size_bytes(body.len() as u64));
+    out.extend_from_slice(body);
+    out
+}
+
+pub fn uint_element(id: u32, value: u64) -> Vec<u8> {
+    let bytes = value.to_be_bytes();
+    let skip = bytes.iter().position(|b| *b != 0).unwrap_or(7);
+    element(id, &bytes[skip..])
+}
+
+pub fn float_element(id: u32, value: f64) -> Vec<u8> {
+    element(id, &value.to_be_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OPUS_HEAD: &[u8] = b"OpusHead\x01\x02\x38\x01\x80\xBB\x00\x00\x00\x00\x00";
+
+    // EBML header and the start of a Segment of unknown size
+    fn ebml_and_segment() -> Vec<u8> {
+        let mut out = element(EBML_HEADER_ID, &element(DOC_TYPE_ID, b"webm"));
+        out.extend_from_slice(&id_bytes(SEGMENT_ID));
+        out.extend_from_slice(&[0x01, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF]);
+        out
+    }
+
+    // Info and Tracks: VP8 video on track 1, Opus audio on track 2
+    fn info_and_tracks() -> Vec<u8> {
+        let mut out = element(INFO_ID, &uint_element(TIMECODE_SCALE_ID, DEFAULT_TIMECODE_SCALE));
+
+        let mut video = uint_element(TRACK_NUMBER_ID, 1);
+        video.extend_from_slice(&uint_element(TRACK_TYPE_ID, TRACK_TYPE_VIDEO));
+        video.extend_from_slice(&element(CODEC_ID_ID, b"V_VP8"));
+        let mut size = uint_element(PIXEL_WIDTH_ID, 640);
+        size.extend_from_slice(&uint_element(PIXEL_HEIGHT_ID, 360));
+        video.extend_from_slice(&element(VIDEO_ID, &size));
+
+        let mut audio = uint_element(TRACK_NUMBER_ID, 2);
+        audio.extend_from_slice(&uint_element(TRACK_TYPE_ID, TRACK_TYPE_AUDIO));
+        audio.extend_from_slice(&element(CODEC_ID_ID, b"A_OPUS"));
+        audio.extend_from_slice(&element(CODEC_PRIVATE_ID, OPUS_HEAD));
+
+        let mut tracks = element(TRACK_ENTRY_ID, &video);
+        tracks.extend_from_slice(&element(TRACK_ENTRY_ID, &audio));
+        out.extend_from_slice(&element(TRACKS_ID, &tracks));
+        out
+    }
+
+    // A Cluster of unknown size, as MediaRecorder writes them, with its Timecode
+    fn cluster_start(timecode: u64) -> Vec<u8> {
+        let mut out = id_bytes(CLUSTER_ID);
+        out.push(0xFF);
+        out.extend_from_slice(&uint_element(TIMECODE_ID, timecode));
+        out
+    }
+
+    fn simple_block(track: u8, relative: i16, flags: u8, frame: &[u8]) -> Vec<u8> {
+        let mut body = vec![0x80 | track];
+        body.extend_from_slice(&relative.to_be_bytes());
+        body.push(flags);
+        body.extend_from_slice(frame);
+        element(SIMPLE_BLOCK_ID, &body)
+    }
+
+    // A stream of two unknown-size clusters and where its events should land
+    fn stream() -> (Vec<u8>, Vec<WebmEvent>) {
+        let mut data = ebml_and_segment();
+        data.extend_from_slice(&info_and_tracks());
+        let mut events = Vec::new();
+
+        for (cluster, timecode) in [(0u64, 0u64), (1, 1000)] {
+            events.push(WebmEvent::ClusterStart { offset: data.len() as u64 });
+            data.extend_from_slice(&cluster_start(timecode));
+            let blocks = [
+                (1u8, 0i16, 0x80u8, vec![0x10 + cluster as u8; 40]),
+                (2, 10, 0x80, vec![0xFC; 12]),
+                (1, 33, 0, vec![0x31; 20]),
+            ];
+            for (track, relative, flags, frame) in blocks {
+                let block = simple_block(track, relative, flags, &frame);
+                events.push(WebmEvent::Block {
+                    track: u64::from(track),
+                    keyframe: flags & 0x80 != 0,
+                    timecode: timecode as i64 + i64::from(relative),
+                    offset: (data.len() + block.len() - frame.len()) as u64,
+                    size: frame.len() as u64,
+                    laced: false,
+                });
+                data.extend_from_slice(&block);
+            }
+        }
+        (data, events)
+    }
+
+    #[test]
+    fn scans_a_whole_stream() {
+        let (data, expected) = stream();
+        let mut scanner = WebmScanner::new();
+        assert_eq!(scanner.feed(&data), expected);
+
+        assert!(!scanner.failed());
+        assert_eq!(scanner.doc_type(), Some("webm"));
+        assert_eq!(scanner.segment_data_offset(), Some(ebml_and_segment().len() as u64));
+        let WebmEvent::ClusterStart { offset } = expected[0] else { unreachable!() };
+        assert_eq!(scanner.init_segment_len(), Some(offset));
+        assert_eq!(scanner.consumed(), data.len() as u64);
+
+        assert_eq!(scanner.video_size(), Some((640, 360)));
+        assert!(scanner.is_video_track(1));
+        assert!(!scanner.is_video_track(2));
+        assert_eq!(scanner.track(1).unwrap().codec_name(), Some("vp8"));
+        assert_eq!(scanner.track(2).unwrap().codec_private.as_deref(), Some(OPUS_HEAD));
+    }
+
+    #[test]
+    fn scans_elements_split_across_feeds() {
+        let (data, expected) = stream();
+        for split in 1..data.len() {
+            let mut scanner = WebmScanner::new();
+            let mut events = scanner.feed(&data[..split]);
+            events.extend(scanner.feed(&data[split..]));
+            assert_eq!(events, expected, "split at {}", split);
+            assert!(!scanner.failed());
+        }
+
+        let mut scanner = WebmScanner::new();
+        let events: Vec<WebmEvent> = data.iter().flat_map(|byte| scanner.feed(&[*byte])).collect();
+        assert_eq!(events, expected);
+    }
+
+    #[test]
+    fn holds_back_partial_element_headers() {
+        let (data, _) = stream();
+        let head_len = (ebml_and_segment().len() + info_and_tracks().len()) as u64;
+        let mut scanner = WebmScanner::new();
+
+        // Half of the Cluster ID isn't parsed, so it isn't consumed either
+        let events = scanner.feed(&data[..head_len as usize + 2]);
+        assert!(events.is_empty());
+        assert_eq!(scanner.consumed(), head_len);
+        assert_eq!(scanner.init_segment_len(), None);
+
+        let events = scanner.feed(&data[head_len as usize + 2..head_len as usize + 5]);
+        assert_eq!(events, [WebmEvent::ClusterStart { offset: head_len }]);
+        assert_eq!(scanner.init_segment_len(), Some(head_len));
+    }
+
+    #[test]
+    fn ends_unknown_size_clusters_at_segment_children() {
+        let mut data = ebml_and_segment();
+        data.extend_from_slice(&info_and_tracks());
+        data.extend_from_slice(&cluster_start(0));
+        data.extend_from_slice(&simple_block(1, 0, 0x80, &[1; 8]));
+        // Cues close the open Cluster rather than being read as part of it
+        data.extend_from_slice(&element(CUES_ID, &element(CUE_POINT_ID, &uint_element(CUE_TIME_ID, 0))));
+        data.extend_from_slice(&cluster_start(500));
+        data.extend_from_slice(&simple_block(2, -5, 0x80, &[2; 8]));
+
+        let mut scanner = WebmScanner::new();
+        let events = scanner.feed(&data);
+        assert!(!scanner.failed(), "{:?}", scanner.corruption());
+        let timecodes: Vec<i64> = events
+            .iter()
+            .filter_map(|event| match event {
+                WebmEvent::Block { timecode, .. } => Some(*timecode),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(timecodes, [0, 495]);
+    }
+
+    #[test]
+    fn flags_laced_blocks() {
+        let mut data = ebml_and_segment();
+        data.extend_from_slice(&info_and_tracks());
+        data.extend_from_slice(&cluster_start(0));
+        // Xiph, fixed-size and EBML lacing; the lace headers are part of the frame bytes
+        for flags in [0x82, 0x84, 0x86] {
+            data.extend_from_slice(&simple_block(2, 0, flags, &[1, 3, 0xFC, 0xFC, 0xFC, 0xFC]));
+        }
+        // A plain Block in a BlockGroup has no keyframe flag
+        let mut block = vec![0x81, 0, 40, 0];
+        block.extend_from_slice(&[7; 5]);
+        data.extend_from_slice(&element(BLOCK_GROUP_ID, &element(BLOCK_ID, &block)));
+
+        let mut scanner = WebmScanner::new();
+        let blocks: Vec<(bool, bool, u64)> = scanner
+            .feed(&data)
+            .into_iter()
+            .filter_map(|event| match event {
+                WebmEvent::Block { keyframe, laced, size, .. } => Some((keyframe, laced, size)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(blocks, [(true, true, 6), (true, true, 6), (true, true, 6), (false, false, 5)]);
+    }
+
+    #[test]
+    fn reports_corruption() {
+        let mut data = ebml_and_segment();
+        data.extend_from_slice(&info_and_tracks());
+        let cluster = data.len();
+        data.extend_from_slice(&cluster_start(0));
+        let block = data.len();
+        data.extend_from_slice(&simple_block(3, 0, 0x80, &[0; 4]));
+
+        let mut scanner = WebmScanner::new();
+        let events = scanner.feed(&data);
+        assert_eq!(events, [WebmEvent::ClusterStart { offset: cluster as u64 }]);
+        assert!(scanner.failed());
+        assert_eq!(scanner.corruption(), Some(format!("block for an undeclared track at offset {}", block).as_str()));
+        // Nothing more is parsed once it has failed
+        assert!(scanner.feed(&cluster_start(100)).is_empty());
+
+        let mut data = ebml_and_segment();
+        data.extend_from_slice(&cluster_start(0));
+        let mut scanner = WebmScanner::new();
+        scanner.feed(&data);
+        assert!(scanner.corruption().unwrap().starts_with("cluster before tracks"));
+
+        let mut data = ebml_and_segment();
+        // A TrackEntry claiming more bytes than its Tracks holds
+        let mut entry = id_bytes(TRACK_ENTRY_ID);
+        entry.extend_from_slice(&size_bytes(50));
+        entry.extend_from_slice(&uint_element(TRACK_NUMBER_ID, 1));
+        data.extend_from_slice(&element(TRACKS_ID, &entry));
+        let mut scanner = WebmScanner::new();
+        scanner.feed(&data);
+        assert!(scanner.corruption().unwrap().starts_with("element overruns its parent"));
+    }
+
+    #[test]
+    fn leaves_other_formats_alone() {
+        let mut scanner = WebmScanner::new();
+        assert!(scanner.feed(b"\x00\x00\x00\x18ftypiso6").is_empty());
+        assert!(scanner.failed());
+        assert_eq!(scanner.corruption(), None);
+
+        // Too short to tell yet
+        let mut scanner = WebmScanner::new();
+        assert!(scanner.feed(&[0x1A, 0x45]).is_empty());
+        assert!(!scanner.failed());
+    }
+
+    #[test]
+    fn encodes_sizes_and_ids() {
+        assert_eq!(size_bytes(0), [0x80]);
+        assert_eq!(size_bytes(126), [0xFE]);
+        // 127 in one byte would read as unknown
+        assert_eq!(size_bytes(127), [0x40, 0x7F]);
+        assert_eq!(id_bytes(CLUSTER_ID), [0x1F, 0x43, 0xB6, 0x75]);
+        assert_eq!(id_bytes(SIMPLE_BLOCK_ID), [0xA3]);
+        assert_eq!(uint_element(TIMECODE_ID, 0), [0xE7, 0x81, 0]);
+
+        let cluster = element(CLUSTER_ID, &[0; 300]);
+        assert_eq!(read_element_header(&cluster), Some((CLUSTER_ID, 6, Some(300))));
+        assert_eq!(read_element_header(&cluster_start(0)), Some((CLUSTER_ID, 5, None)));
+        assert_eq!(read_element_header(&cluster[..5]), None);
+    }
+}
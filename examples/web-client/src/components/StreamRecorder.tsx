@@ -73,7 +73,7 @@ export const StreamRecorder: React.FC<StreamRecorderProps> = ({
   const initializeWebSocket = (accessToken: string) => {
     if (!roomId) return;
 
-    const ws = new WebSocket(`ws://localhost:3000/room/${roomId}/ws`);
+    const ws = new WebSocket(`ws://localhost:3000/room/${roomId}/ws?role=publisher`);
     wsRef.current = ws;
 
     ws.onopen = () => {